@@ -0,0 +1,259 @@
+use anyhow::{bail, Context, Result};
+use futures_util::{FutureExt, StreamExt};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use zbus::fdo::{ManagedObjects, ObjectManagerProxy};
+use zbus::{Connection, MatchRule};
+use crate::config::BluetoothConfig;
+use crate::daemon::Daemon;
+use crate::holds;
+
+/// Hold kind for connected devices; sources are `bluetooth:<address>`.
+pub const HOLD_KIND: &str = "bluetooth";
+
+const BLUEZ: &str = "org.bluez";
+const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+
+/// Lets a burst of property changes (a headset connecting profiles) settle before re-listing.
+const SETTLE: Duration = Duration::from_millis(250);
+
+/// Normalizes an address to BlueZ's `AA:BB:CC:DD:EE:FF` form.
+pub fn normalize_address(address: &str) -> String {
+    address.trim().replace('-', ":").to_ascii_uppercase()
+}
+
+/// Connected devices among `addresses`, as address and display name.
+pub fn connected_devices(objects: &ManagedObjects, addresses: &[String]) -> BTreeMap<String, String> {
+    objects
+        .values()
+        .filter_map(|interfaces| {
+            interfaces
+                .iter()
+                .find(|(name, _)| name.as_str() == DEVICE_INTERFACE)
+                .map(|(_, properties)| properties)
+        })
+        .filter_map(|properties| {
+            let text = |name: &str| properties.get(name).and_then(|value| <&str>::try_from(&**value).ok());
+            let connected = properties
+                .get("Connected")
+                .and_then(|value| bool::try_from(&**value).ok())
+                .unwrap_or(false);
+
+            let address = normalize_address(text("Address")?);
+            if !connected || !addresses.contains(&address) {
+                return None;
+            }
+            let name = text("Alias").or_else(|| text("Name")).unwrap_or(&address).to_string();
+            Some((address, name))
+        })
+        .collect()
+}
+
+/// Holds the inhibitor while any of `addresses` is connected, following BlueZ on
+/// `connection`. Tests can pass a connection to a bus with a mock BlueZ.
+pub async fn watch_connection(daemon: Daemon, connection: Connection, addresses: Vec<String>, what: String) -> Result<()> {
+    let addresses: Vec<String> = addresses.iter().map(|address| normalize_address(address)).collect();
+
+    let rules = [
+        MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .sender(BLUEZ)?
+            .interface("org.freedesktop.DBus.Properties")?
+            .member("PropertiesChanged")?
+            .arg(0, DEVICE_INTERFACE)?
+            .build(),
+        MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .sender(BLUEZ)?
+            .interface("org.freedesktop.DBus.ObjectManager")?
+            .build(),
+        // bluetoothd restarting drops every connection
+        MatchRule::builder()
+            .msg_type(zbus::message::Type::Signal)
+            .sender("org.freedesktop.DBus")?
+            .interface("org.freedesktop.DBus")?
+            .member("NameOwnerChanged")?
+            .arg(0, BLUEZ)?
+            .build(),
+    ];
+
+    // A peer-to-peer connection (as in tests) has no bus to route signals and
+    // delivers everything the peer sends
+    if connection.unique_name().is_some() {
+        let proxy = zbus::fdo::DBusProxy::new(&connection).await?;
+        for rule in rules {
+            proxy.add_match_rule(rule).await?;
+        }
+    }
+
+    let mut stream = zbus::MessageStream::from(&connection);
+    let manager = ObjectManagerProxy::builder(&connection)
+        .destination(BLUEZ)?
+        .path("/")?
+        .build()
+        .await?;
+
+    tracing::info!("Watching {} Bluetooth device(s)", addresses.len());
+
+    let mut available = true;
+    let mut active: BTreeMap<String, String> = BTreeMap::new();
+    loop {
+        let connected = match manager.get_managed_objects().await {
+            Ok(objects) => {
+                available = true;
+                connected_devices(&objects, &addresses)
+            }
+            Err(e) => {
+                if available {
+                    tracing::warn!("BlueZ is not available: {}", e);
+                }
+                available = false;
+                BTreeMap::new()
+            }
+        };
+
+        for address in active.keys().filter(|address| !connected.contains_key(*address)) {
+            tracing::info!("Bluetooth device {} disconnected", address);
+            daemon.release_hold(&format!("{}:{}", HOLD_KIND, address)).await?;
+        }
+        for (address, name) in &connected {
+            if !active.contains_key(address) {
+                tracing::info!("Bluetooth device {} ({}) connected", name, address);
+            }
+            let source = format!("{}:{}", HOLD_KIND, address);
+            daemon.set_hold_what(&source, &what, &format!("{} connected", name)).await?;
+        }
+        active = connected;
+
+        // Method replies share the stream; only signals warrant a re-list
+        loop {
+            match stream.next().await {
+                Some(Ok(msg)) if msg.message_type() == zbus::message::Type::Signal => break,
+                Some(_) => continue,
+                None => bail!("System bus connection closed"),
+            }
+        }
+
+        tokio::time::sleep(SETTLE).await;
+        while let Some(Some(_)) = stream.next().now_or_never() {}
+    }
+}
+
+/// Holds the configured inhibitor while a configured Bluetooth device is connected,
+/// e.g. a headset during an audio-only call.
+pub async fn watch(daemon: Daemon, config: BluetoothConfig) -> Result<()> {
+    holds::validate_what(&config.what)?;
+
+    let connection = Connection::system()
+        .await
+        .context("Failed to connect to system D-Bus")?;
+
+    watch_connection(daemon, connection, config.devices, config.what).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use zbus::connection::Builder;
+    use zbus::object_server::SignalContext;
+
+    const DEVICE_PATH: &str = "/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF";
+    const SOURCE: &str = "bluetooth:AA:BB:CC:DD:EE:FF";
+
+    struct Device {
+        connected: bool,
+    }
+
+    #[zbus::interface(name = "org.bluez.Device1")]
+    impl Device {
+        #[zbus(property)]
+        fn address(&self) -> &str {
+            "AA:BB:CC:DD:EE:FF"
+        }
+
+        #[zbus(property)]
+        fn alias(&self) -> &str {
+            "Headset"
+        }
+
+        #[zbus(property)]
+        fn connected(&self) -> bool {
+            self.connected
+        }
+    }
+
+    /// A minimal BlueZ (one device below an object manager) on one end of a
+    /// socket pair, and a client connection on the other.
+    async fn fake_bluez() -> (Connection, Connection) {
+        let (server, client) = std::os::unix::net::UnixStream::pair().unwrap();
+        let server = Builder::unix_stream(server)
+            .server(zbus::Guid::generate())
+            .unwrap()
+            .p2p()
+            .serve_at("/", zbus::fdo::ObjectManager)
+            .unwrap()
+            .serve_at(DEVICE_PATH, Device { connected: false })
+            .unwrap()
+            .build();
+        let client = Builder::unix_stream(client).p2p().build();
+
+        let (server, client) = tokio::join!(server, client);
+        (server.unwrap(), client.unwrap())
+    }
+
+    async fn set_connected(server: &Connection, connected: bool) {
+        let device = server.object_server().interface::<_, Device>(DEVICE_PATH).await.unwrap();
+        device.get_mut().await.connected = connected;
+        let context = SignalContext::new(server, DEVICE_PATH).unwrap();
+        device.get().await.connected_changed(&context).await.unwrap();
+    }
+
+    /// Waits until the daemon holds (or no longer holds) `source`.
+    async fn wait_for_hold(daemon: &Daemon, source: &str, held: bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while daemon.holds().await.iter().any(|(s, _)| s == source) != held {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("hold {} never became {}", source, held));
+    }
+
+    #[tokio::test]
+    async fn follows_device_connections() {
+        let (server, client) = fake_bluez().await;
+        let daemon = Daemon::detached(Config::default());
+        let watcher = tokio::spawn(watch_connection(
+            daemon.clone(),
+            client,
+            vec!["aa-bb-cc-dd-ee-ff".to_string()],
+            "idle".to_string(),
+        ));
+
+        // Give the watcher time to list the (disconnected) device
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(daemon.holds().await.is_empty());
+
+        set_connected(&server, true).await;
+        wait_for_hold(&daemon, SOURCE, true).await;
+        let holds = daemon.holds().await;
+        let (_, hold) = holds.iter().find(|(source, _)| *source == SOURCE).unwrap();
+        assert_eq!(hold.reason, "Headset connected");
+        assert_eq!(hold.what, "idle");
+
+        set_connected(&server, false).await;
+        wait_for_hold(&daemon, SOURCE, false).await;
+
+        // Losing BlueZ ends the watcher so it can be restarted
+        drop(server);
+        let result = tokio::time::timeout(Duration::from_secs(5), watcher).await.unwrap().unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn normalizes_addresses() {
+        assert_eq!(normalize_address(" aa-bb-cc-dd-ee-ff\n"), "AA:BB:CC:DD:EE:FF");
+        assert_eq!(normalize_address("AA:BB:CC:DD:EE:FF"), "AA:BB:CC:DD:EE:FF");
+    }
+}
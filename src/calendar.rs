@@ -0,0 +1,833 @@
+use anyhow::{bail, Context, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use crate::config::CalendarConfig;
+use crate::daemon::Daemon;
+use crate::schedule;
+use crate::zoneinfo::TimeZone;
+
+/// Hold kind for calendar events; sources are `calendar:<event UID>`.
+pub const HOLD_KIND: &str = "calendar";
+
+/// How far past the lead time occurrences are expanded on each pass.
+const LOOKAHEAD_SECS: i64 = 24 * 60 * 60;
+
+/// Bounds RRULE expansion for rules without COUNT/UNTIL or with tiny intervals.
+const MAX_RECURRENCE_PERIODS: u32 = 100_000;
+
+const ICAL_WEEKDAYS: [&str; 7] = ["MO", "TU", "WE", "TH", "FR", "SA", "SU"];
+
+/// The zone a calendar date-time is written in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Zone {
+    /// Ends in `Z`
+    Utc,
+    /// No zone given; read in the system time zone
+    Floating,
+    /// Named by a `TZID=` parameter
+    Named(Arc<TimeZone>),
+}
+
+/// A calendar date-time as written in the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CalTime {
+    /// Days since 1970-01-01
+    pub day: i64,
+    /// Seconds since midnight
+    pub second: u32,
+    pub zone: Zone,
+}
+
+impl CalTime {
+    pub fn timestamp(&self) -> Result<i64> {
+        let wall_clock = self.day * 86_400 + i64::from(self.second);
+        match &self.zone {
+            Zone::Utc => return Ok(wall_clock),
+            Zone::Named(zone) => return Ok(zone.to_utc(wall_clock)),
+            Zone::Floating => {}
+        }
+
+        let (year, month, day) = civil_from_days(self.day);
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        tm.tm_year = (year - 1900) as i32;
+        tm.tm_mon = month as i32 - 1;
+        tm.tm_mday = day as i32;
+        tm.tm_hour = (self.second / 3600) as i32;
+        tm.tm_min = (self.second / 60 % 60) as i32;
+        tm.tm_sec = (self.second % 60) as i32;
+        tm.tm_isdst = -1;
+
+        match unsafe { libc::mktime(&mut tm) } {
+            -1 => bail!("Failed to convert {}-{:02}-{:02} to local time", year, month, day),
+            timestamp => Ok(timestamp),
+        }
+    }
+
+    fn on_day(&self, day: i64) -> Self {
+        Self { day, ..self.clone() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// The subset of RFC 5545 RRULE needed for meeting series.
+#[derive(Debug, Clone)]
+pub struct RecurrenceRule {
+    pub frequency: Frequency,
+    pub interval: u32,
+    pub count: Option<u32>,
+    pub until: Option<CalTime>,
+    /// (ordinal, weekday 0 = Monday); the ordinal is only meaningful for MONTHLY
+    pub by_day: Vec<(Option<i32>, usize)>,
+    pub by_month_day: Vec<i32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub uid: String,
+    pub summary: String,
+    pub start: CalTime,
+    pub duration_secs: i64,
+    pub rule: Option<RecurrenceRule>,
+    pub exdates: Vec<i64>,
+    /// Set on an override of a single occurrence of a recurring event
+    pub recurrence_id: Option<i64>,
+}
+
+/// One concrete instance of an event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Occurrence {
+    pub uid: String,
+    pub summary: String,
+    pub start: i64,
+    pub end: i64,
+}
+
+// Howard Hinnant's civil calendar algorithms
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (i64::from(month) + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// 0 = Monday
+fn weekday(days: i64) -> usize {
+    (days + 3).rem_euclid(7) as usize
+}
+
+pub(crate) fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Joins folded lines (continuations start with a space or tab).
+fn unfold(content: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in content.lines() {
+        match line.strip_prefix([' ', '\t']) {
+            Some(rest) if !lines.is_empty() => lines.last_mut().unwrap().push_str(rest),
+            _ => lines.push(line.to_string()),
+        }
+    }
+    lines
+}
+
+/// Splits `NAME;PARAM=x:value` into the upper-cased name, its parameters and value.
+fn split_property(line: &str) -> Option<(String, HashMap<String, String>, &str)> {
+    let mut in_quotes = false;
+    let colon = line.char_indices().find_map(|(i, c)| match c {
+        '"' => {
+            in_quotes = !in_quotes;
+            None
+        }
+        ':' if !in_quotes => Some(i),
+        _ => None,
+    })?;
+
+    let (head, value) = (&line[..colon], &line[colon + 1..]);
+    let mut parts = head.split(';');
+    let name = parts.next()?.to_ascii_uppercase();
+    let params = parts
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| (key.to_ascii_uppercase(), value.trim_matches('"').to_string()))
+        .collect();
+
+    Some((name, params, value))
+}
+
+fn unescape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => out.push(' '),
+            Some(other) => out.push(other),
+            None => {}
+        }
+    }
+    out
+}
+
+/// Parses `YYYYMMDD` or `YYYYMMDDTHHMMSS[Z]`; the bool is false for DATE values.
+fn parse_time(value: &str) -> Option<(CalTime, bool)> {
+    let value = value.trim();
+    let digits = |range: std::ops::Range<usize>| value.get(range)?.parse::<u32>().ok();
+
+    let day = days_from_civil(i64::from(digits(0..4)?), digits(4..6)?, digits(6..8)?);
+
+    if value.len() == 8 {
+        return Some((CalTime { day, second: 0, zone: Zone::Floating }, false));
+    }
+    if value.as_bytes().get(8) != Some(&b'T') {
+        return None;
+    }
+
+    let second = digits(9..11)? * 3600 + digits(11..13)? * 60 + digits(13..15)?;
+    let zone = if value.ends_with('Z') { Zone::Utc } else { Zone::Floating };
+    Some((CalTime { day, second, zone }, true))
+}
+
+/// Time zones named by `TZID` parameters, loaded once per document.
+#[derive(Default)]
+struct Zones {
+    loaded: HashMap<String, Option<Arc<TimeZone>>>,
+}
+
+impl Zones {
+    fn get(&mut self, tzid: &str) -> Result<Zone> {
+        let zone = self.loaded.entry(tzid.to_string()).or_insert_with(|| match TimeZone::load(tzid) {
+            Ok(zone) => Some(Arc::new(zone)),
+            Err(e) => {
+                tracing::warn!("{:#}; skipping calendar events in it", e);
+                None
+            }
+        });
+        zone.clone().map(Zone::Named).with_context(|| format!("Unknown time zone '{}'", tzid))
+    }
+
+    /// Parses a date or date-time value, reading it in the zone its `TZID` names.
+    fn parse_time(&mut self, value: &str, params: &HashMap<String, String>) -> Result<Option<(CalTime, bool)>> {
+        let Some((mut time, timed)) = parse_time(value) else {
+            return Ok(None);
+        };
+        if let (Some(tzid), Zone::Floating) = (params.get("TZID"), &time.zone) {
+            if timed {
+                time.zone = self.get(tzid)?;
+            }
+        }
+        Ok(Some((time, timed)))
+    }
+}
+
+/// Parses an RFC 5545 duration such as `PT1H30M` or `-P1W` into seconds.
+fn parse_duration(value: &str) -> Option<i64> {
+    let (sign, rest) = match value.trim().strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, value.trim().trim_start_matches('+')),
+    };
+    let rest = rest.strip_prefix('P')?;
+
+    let mut total = 0;
+    let mut number = String::new();
+    for c in rest.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' => {}
+            unit => {
+                let n: i64 = number.parse().ok()?;
+                number.clear();
+                total += n * match unit {
+                    'W' => 7 * 86_400,
+                    'D' => 86_400,
+                    'H' => 3600,
+                    'M' => 60,
+                    'S' => 1,
+                    _ => return None,
+                };
+            }
+        }
+    }
+
+    Some(sign * total)
+}
+
+fn parse_rule(value: &str) -> Result<RecurrenceRule> {
+    let mut frequency = None;
+    let mut rule = RecurrenceRule {
+        frequency: Frequency::Daily,
+        interval: 1,
+        count: None,
+        until: None,
+        by_day: Vec::new(),
+        by_month_day: Vec::new(),
+    };
+
+    for part in value.split(';') {
+        let Some((key, value)) = part.split_once('=') else {
+            continue;
+        };
+        match key.to_ascii_uppercase().as_str() {
+            "FREQ" => {
+                frequency = Some(match value.to_ascii_uppercase().as_str() {
+                    "DAILY" => Frequency::Daily,
+                    "WEEKLY" => Frequency::Weekly,
+                    "MONTHLY" => Frequency::Monthly,
+                    "YEARLY" => Frequency::Yearly,
+                    other => bail!("Unsupported FREQ={}", other),
+                })
+            }
+            "INTERVAL" => rule.interval = value.parse::<u32>().context("Invalid INTERVAL")?.max(1),
+            "COUNT" => rule.count = Some(value.parse().context("Invalid COUNT")?),
+            "UNTIL" => rule.until = Some(parse_time(value).context("Invalid UNTIL")?.0),
+            "BYDAY" => {
+                for day in value.split(',') {
+                    // The weekday code is the last two characters
+                    let split = day.char_indices().rev().nth(1).map_or(0, |(index, _)| index);
+                    let (ordinal, code) = day.split_at(split);
+                    let weekday = ICAL_WEEKDAYS
+                        .iter()
+                        .position(|w| w.eq_ignore_ascii_case(code))
+                        .with_context(|| format!("Invalid BYDAY {}", day))?;
+                    let ordinal = match ordinal.trim_start_matches('+') {
+                        "" => None,
+                        n => Some(n.parse().with_context(|| format!("Invalid BYDAY {}", day))?),
+                    };
+                    rule.by_day.push((ordinal, weekday));
+                }
+            }
+            "BYMONTHDAY" => {
+                for day in value.split(',') {
+                    rule.by_month_day.push(day.parse().context("Invalid BYMONTHDAY")?);
+                }
+            }
+            // Week starts other than Monday only matter for BYWEEKNO and the like
+            "WKST" => {}
+            other => bail!("Unsupported RRULE part {}", other),
+        }
+    }
+
+    rule.frequency = frequency.context("RRULE without FREQ")?;
+    Ok(rule)
+}
+
+/// Parses every timed VEVENT in an iCalendar document. All-day, cancelled and
+/// free (`TRANSP:TRANSPARENT`) events are skipped since they are not meetings.
+pub fn parse_ics(content: &str) -> Vec<Event> {
+    let mut events = Vec::new();
+    let mut current: Option<Vec<String>> = None;
+    let mut zones = Zones::default();
+
+    for line in unfold(content) {
+        match line.trim_end().to_ascii_uppercase().as_str() {
+            "BEGIN:VEVENT" => current = Some(Vec::new()),
+            "END:VEVENT" => {
+                if let Some(lines) = current.take() {
+                    match parse_event(&lines, &mut zones) {
+                        Ok(Some(event)) => events.push(event),
+                        Ok(None) => {}
+                        Err(e) => tracing::debug!("Skipping calendar event: {:#}", e),
+                    }
+                }
+            }
+            _ => {
+                if let Some(lines) = current.as_mut() {
+                    lines.push(line);
+                }
+            }
+        }
+    }
+
+    events
+}
+
+fn parse_event(lines: &[String], zones: &mut Zones) -> Result<Option<Event>> {
+    let mut uid = None;
+    let mut summary = String::new();
+    let mut start = None;
+    let mut end = None;
+    let mut duration = None;
+    let mut rule = None;
+    let mut unsupported_rule = None;
+    let mut exdates = Vec::new();
+    let mut recurrence_id = None;
+
+    for line in lines {
+        let Some((name, params, value)) = split_property(line) else {
+            continue;
+        };
+        let is_date = params.get("VALUE").is_some_and(|v| v.eq_ignore_ascii_case("DATE"));
+
+        match name.as_str() {
+            "UID" => uid = Some(value.to_string()),
+            "SUMMARY" => summary = unescape(value),
+            "DTSTART" => {
+                let (time, timed) = zones.parse_time(value, &params)?.context("Invalid DTSTART")?;
+                if is_date || !timed {
+                    return Ok(None);
+                }
+                start = Some(time);
+            }
+            "DTEND" => end = Some(zones.parse_time(value, &params)?.context("Invalid DTEND")?.0),
+            "DURATION" => duration = Some(parse_duration(value).context("Invalid DURATION")?),
+            "RRULE" => match parse_rule(value) {
+                Ok(parsed) => rule = Some(parsed),
+                Err(e) => unsupported_rule = Some(e),
+            },
+            "EXDATE" => {
+                for date in value.split(',') {
+                    if let Some((time, _)) = zones.parse_time(date, &params)? {
+                        exdates.push(time.timestamp()?);
+                    }
+                }
+            }
+            "RECURRENCE-ID" => {
+                let (time, _) = zones.parse_time(value, &params)?.context("Invalid RECURRENCE-ID")?;
+                recurrence_id = Some(time.timestamp()?)
+            }
+            "STATUS" if value.eq_ignore_ascii_case("CANCELLED") => return Ok(None),
+            "TRANSP" if value.eq_ignore_ascii_case("TRANSPARENT") => return Ok(None),
+            _ => {}
+        }
+    }
+
+    let start = start.context("Event without DTSTART")?;
+    if let Some(e) = unsupported_rule {
+        tracing::warn!(
+            "Calendar event '{}' ({}): {:#}; only its first occurrence is used",
+            summary,
+            uid.as_deref().unwrap_or("no UID"),
+            e
+        );
+    }
+    let duration_secs = match (end, duration) {
+        (Some(end), _) => end.timestamp()? - start.timestamp()?,
+        (None, Some(duration)) => duration,
+        (None, None) => 0,
+    };
+
+    Ok(Some(Event {
+        uid: uid.unwrap_or_default(),
+        summary,
+        start,
+        duration_secs,
+        rule,
+        exdates,
+        recurrence_id,
+    }))
+}
+
+/// Candidate start days for one recurrence period, in order.
+fn period_days(rule: &RecurrenceRule, start: &CalTime, period: u32) -> Vec<i64> {
+    let step = i64::from(period) * i64::from(rule.interval);
+    let (year, month, day) = civil_from_days(start.day);
+
+    let mut days = match rule.frequency {
+        Frequency::Daily => vec![start.day + step],
+        Frequency::Weekly => {
+            let monday = start.day - weekday(start.day) as i64 + step * 7;
+            if rule.by_day.is_empty() {
+                vec![monday + weekday(start.day) as i64]
+            } else {
+                rule.by_day.iter().map(|(_, wd)| monday + *wd as i64).collect()
+            }
+        }
+        Frequency::Monthly => {
+            let index = year * 12 + i64::from(month) - 1 + step;
+            let (year, month) = (index.div_euclid(12), (index.rem_euclid(12) + 1) as u32);
+            let length = days_in_month(year, month);
+            let first = days_from_civil(year, month, 1);
+
+            if !rule.by_day.is_empty() {
+                rule.by_day
+                    .iter()
+                    .flat_map(|&(ordinal, wd)| {
+                        let matching: Vec<i64> = (0..i64::from(length))
+                            .map(|offset| first + offset)
+                            .filter(|d| weekday(*d) == wd)
+                            .collect();
+                        match ordinal {
+                            None => matching,
+                            Some(n) if n > 0 => matching.get(n as usize - 1).copied().into_iter().collect(),
+                            Some(n) => matching
+                                .len()
+                                .checked_sub(n.unsigned_abs() as usize)
+                                .and_then(|i| matching.get(i).copied())
+                                .into_iter()
+                                .collect(),
+                        }
+                    })
+                    .collect()
+            } else {
+                let month_days = if rule.by_month_day.is_empty() { vec![day as i32] } else { rule.by_month_day.clone() };
+                month_days
+                    .into_iter()
+                    .filter_map(|d| {
+                        let d = if d < 0 { length as i32 + d + 1 } else { d };
+                        // Months too short for the day are skipped, per RFC 5545
+                        (1..=length as i32).contains(&d).then(|| first + i64::from(d) - 1)
+                    })
+                    .collect()
+            }
+        }
+        Frequency::Yearly => {
+            let year = year + step;
+            if day <= days_in_month(year, month) {
+                vec![days_from_civil(year, month, day)]
+            } else {
+                Vec::new()
+            }
+        }
+    };
+
+    days.sort_unstable();
+    days.dedup();
+    days
+}
+
+/// Expands `event` into the occurrences overlapping `[from, to)`.
+fn expand(event: &Event, from: i64, to: i64, out: &mut Vec<Occurrence>) -> Result<()> {
+    let mut push = |start: i64| {
+        let end = start + event.duration_secs.max(0);
+        if start < to && end > from && !event.exdates.contains(&start) {
+            out.push(Occurrence {
+                uid: event.uid.clone(),
+                summary: event.summary.clone(),
+                start,
+                end,
+            });
+        }
+    };
+
+    let Some(rule) = &event.rule else {
+        push(event.start.timestamp()?);
+        return Ok(());
+    };
+
+    let until = rule.until.as_ref().map(CalTime::timestamp).transpose()?;
+    let mut emitted = 0;
+
+    for period in 0..MAX_RECURRENCE_PERIODS {
+        for day in period_days(rule, &event.start, period) {
+            if day < event.start.day {
+                continue;
+            }
+
+            let start = event.start.on_day(day).timestamp()?;
+            if until.is_some_and(|until| start > until) || rule.count.is_some_and(|count| emitted >= count) {
+                return Ok(());
+            }
+            if start >= to {
+                return Ok(());
+            }
+
+            emitted += 1;
+            push(start);
+        }
+    }
+
+    Ok(())
+}
+
+/// Every occurrence overlapping `[from, to)`, with single-instance overrides
+/// (`RECURRENCE-ID`) replacing the instance of the series they modify.
+pub fn occurrences(events: &[Event], from: i64, to: i64) -> Vec<Occurrence> {
+    let mut overridden: HashMap<&str, Vec<i64>> = HashMap::new();
+    for event in events {
+        if let Some(id) = event.recurrence_id {
+            overridden.entry(event.uid.as_str()).or_default().push(id);
+        }
+    }
+
+    let mut out = Vec::new();
+    for event in events {
+        let mut event = event.clone();
+        if event.recurrence_id.is_none() && event.rule.is_some() {
+            if let Some(ids) = overridden.get(event.uid.as_str()) {
+                event.exdates.extend(ids);
+            }
+        }
+
+        if let Err(e) = expand(&event, from, to, &mut out) {
+            tracing::debug!("Skipping calendar event {}: {:#}", event.uid, e);
+        }
+    }
+
+    out.sort_by_key(|occurrence| occurrence.start);
+    out
+}
+
+fn load_events(files: &BTreeMap<PathBuf, Option<SystemTime>>) -> Vec<Event> {
+    let mut events = Vec::new();
+    for path in files.keys() {
+        match std::fs::read_to_string(path) {
+            Ok(content) => events.extend(parse_ics(&content)),
+            Err(e) => tracing::warn!("Failed to read calendar {}: {}", path.display(), e),
+        }
+    }
+    events
+}
+
+/// Holds the inhibitor from `lead_minutes` before each busy event until it ends,
+/// re-reading the calendar files whenever they change.
+pub async fn watch(daemon: Daemon, config: CalendarConfig) -> Result<()> {
+    let lead = config.lead_minutes as i64 * 60;
+    let poll = config.poll_interval_secs.max(1) as i64;
+
+    let mut files = BTreeMap::new();
+    let mut events = Vec::new();
+    let mut active = BTreeSet::new();
+
+    tracing::info!("Watching {} calendar path(s)", config.paths.len());
+
+    loop {
+        let current = crate::config::watched_files(&config.paths, "ics");
+        if current != files {
+            events = load_events(&current);
+            tracing::info!("Loaded {} event(s) from {} calendar file(s)", events.len(), current.len());
+            files = current;
+        }
+
+        let now = schedule::now();
+        let upcoming = occurrences(&events, now, now + lead + LOOKAHEAD_SECS);
+
+        let mut wanted = BTreeMap::new();
+        for occurrence in upcoming.iter().filter(|o| o.start - lead <= now && now < o.end) {
+            let source = format!("{}:{}", HOLD_KIND, occurrence.uid);
+            let reason = if occurrence.summary.is_empty() { "Calendar event".to_string() } else { occurrence.summary.clone() };
+            wanted.entry(source).or_insert(reason);
+        }
+
+        let ended: Vec<String> = active.iter().filter(|s| !wanted.contains_key(*s)).cloned().collect();
+        for source in ended {
+            tracing::info!("Calendar hold {} ended", source);
+            daemon.release_hold(&source).await?;
+            active.remove(&source);
+        }
+        for (source, reason) in &wanted {
+            if active.insert(source.clone()) {
+                tracing::info!("Calendar event '{}' starting", reason);
+            }
+            daemon.set_hold(source, reason).await?;
+        }
+
+        // Wake for the next lead time or end, but keep polling for file changes
+        let wait = upcoming
+            .iter()
+            .flat_map(|o| [o.start - lead, o.end])
+            .filter(|at| *at > now)
+            .map(|at| at - now)
+            .min()
+            .unwrap_or(poll)
+            .clamp(1, poll);
+
+        tokio::time::sleep(Duration::from_secs(wait as u64)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01 00:00 UTC, a Monday.
+    const MONDAY: i64 = 1_704_067_200;
+    const HOUR: i64 = 3600;
+    const DAY: i64 = 24 * HOUR;
+
+    fn calendar(events: &[&str]) -> String {
+        let mut ics = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\n".to_string();
+        for (index, event) in events.iter().enumerate() {
+            ics.push_str(&format!("BEGIN:VEVENT\r\nUID:event-{}\r\nSUMMARY:Meeting {}\r\n", index, index));
+            for line in event.lines() {
+                ics.push_str(line.trim());
+                ics.push_str("\r\n");
+            }
+            ics.push_str("END:VEVENT\r\n");
+        }
+        ics.push_str("END:VCALENDAR\r\n");
+        ics
+    }
+
+    fn starts(events: &[&str], from: i64, to: i64) -> Vec<i64> {
+        occurrences(&parse_ics(&calendar(events)), from, to)
+            .iter()
+            .map(|occurrence| occurrence.start)
+            .collect()
+    }
+
+    #[test]
+    fn start_with_end_or_duration() {
+        let events = parse_ics(&calendar(&[
+            "DTSTART:20240101T100000Z\nDTEND:20240101T113000Z",
+            "DTSTART:20240101T140000Z\nDURATION:PT1H15M",
+            "DTSTART:20240101T160000Z",
+        ]));
+        let found = occurrences(&events, MONDAY, MONDAY + DAY);
+
+        assert_eq!(found.len(), 3);
+        assert_eq!((found[0].start, found[0].end), (MONDAY + 10 * HOUR, MONDAY + 11 * HOUR + 30 * 60));
+        assert_eq!(found[0].uid, "event-0");
+        assert_eq!(found[0].summary, "Meeting 0");
+        assert_eq!((found[1].start, found[1].end), (MONDAY + 14 * HOUR, MONDAY + 15 * HOUR + 15 * 60));
+        assert_eq!((found[2].start, found[2].end), (MONDAY + 16 * HOUR, MONDAY + 16 * HOUR));
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("PT1H30M"), Some(5400));
+        assert_eq!(parse_duration("P1DT2H"), Some(DAY + 2 * HOUR));
+        assert_eq!(parse_duration("P2W"), Some(14 * DAY));
+        assert_eq!(parse_duration("-PT15M"), Some(-900));
+        assert_eq!(parse_duration("1H"), None);
+        assert_eq!(parse_duration("PT1X"), None);
+    }
+
+    #[test]
+    fn skips_all_day_cancelled_and_free_events() {
+        let events = parse_ics(&calendar(&[
+            "DTSTART;VALUE=DATE:20240101\nDTEND;VALUE=DATE:20240102",
+            "DTSTART:20240101",
+            "DTSTART:20240101T100000Z\nSTATUS:CANCELLED",
+            "DTSTART:20240101T100000Z\nTRANSP:TRANSPARENT",
+            "SUMMARY:No start",
+            "DTSTART:20240101T120000Z\nDTEND:20240101T130000Z",
+        ]));
+
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].uid, "event-5");
+    }
+
+    #[test]
+    fn unfolds_and_unescapes() {
+        let ics = "BEGIN:VEVENT\r\nUID:folded\r\nSUMMARY:Planning\\, part\r\n  two\\nfinal\r\nDTSTART:20240101T100000Z\r\nEND:VEVENT\r\n";
+        let events = parse_ics(ics);
+        assert_eq!(events[0].summary, "Planning, part two final");
+    }
+
+    #[test]
+    fn daily_with_count() {
+        let found = starts(&["DTSTART:20240101T090000Z\nDURATION:PT30M\nRRULE:FREQ=DAILY;COUNT=3"], MONDAY, MONDAY + 7 * DAY);
+        assert_eq!(found, [MONDAY + 9 * HOUR, MONDAY + DAY + 9 * HOUR, MONDAY + 2 * DAY + 9 * HOUR]);
+
+        // COUNT counts from DTSTART, not from the start of the range
+        let found = starts(&["DTSTART:20240101T090000Z\nRRULE:FREQ=DAILY;COUNT=3"], MONDAY + DAY + 10 * HOUR, MONDAY + 7 * DAY);
+        assert_eq!(found, [MONDAY + 2 * DAY + 9 * HOUR]);
+    }
+
+    #[test]
+    fn daily_with_interval_and_until() {
+        let found = starts(
+            &["DTSTART:20240101T090000Z\nRRULE:FREQ=DAILY;INTERVAL=2;UNTIL=20240105T090000Z"],
+            MONDAY,
+            MONDAY + 14 * DAY,
+        );
+        assert_eq!(found, [MONDAY + 9 * HOUR, MONDAY + 2 * DAY + 9 * HOUR, MONDAY + 4 * DAY + 9 * HOUR]);
+    }
+
+    #[test]
+    fn weekly_by_day_with_until_and_count() {
+        let found = starts(
+            &["DTSTART:20240101T090000Z\nRRULE:FREQ=WEEKLY;BYDAY=MO,WE;UNTIL=20240110T000000Z"],
+            MONDAY,
+            MONDAY + 28 * DAY,
+        );
+        assert_eq!(found, [MONDAY + 9 * HOUR, MONDAY + 2 * DAY + 9 * HOUR, MONDAY + 7 * DAY + 9 * HOUR]);
+
+        let found = starts(&["DTSTART:20240103T090000Z\nRRULE:FREQ=WEEKLY;COUNT=2"], MONDAY, MONDAY + 28 * DAY);
+        assert_eq!(found, [MONDAY + 2 * DAY + 9 * HOUR, MONDAY + 9 * DAY + 9 * HOUR]);
+    }
+
+    #[test]
+    fn exdates_and_overrides() {
+        let found = starts(
+            &[
+                "DTSTART:20240101T090000Z\nRRULE:FREQ=DAILY;COUNT=4\nEXDATE:20240102T090000Z,20240104T090000Z",
+            ],
+            MONDAY,
+            MONDAY + 7 * DAY,
+        );
+        assert_eq!(found, [MONDAY + 9 * HOUR, MONDAY + 2 * DAY + 9 * HOUR]);
+
+        let ics = calendar(&["DTSTART:20240101T090000Z\nRRULE:FREQ=DAILY;COUNT=2"]).replace(
+            "END:VCALENDAR",
+            "BEGIN:VEVENT\r\nUID:event-0\r\nRECURRENCE-ID:20240102T090000Z\r\nDTSTART:20240102T150000Z\r\nEND:VEVENT\r\nEND:VCALENDAR",
+        );
+        let found: Vec<i64> = occurrences(&parse_ics(&ics), MONDAY, MONDAY + 7 * DAY)
+            .iter()
+            .map(|occurrence| occurrence.start)
+            .collect();
+        assert_eq!(found, [MONDAY + 9 * HOUR, MONDAY + DAY + 15 * HOUR]);
+    }
+
+    #[test]
+    fn unsupported_rule_parts_keep_the_first_occurrence() {
+        let found = starts(&["DTSTART:20240101T090000Z\nRRULE:FREQ=YEARLY;BYMONTH=1;BYSETPOS=1"], MONDAY, MONDAY + 7 * DAY);
+        assert_eq!(found, [MONDAY + 9 * HOUR]);
+
+        // Non-ASCII BYDAY values are rejected, not split inside a character
+        let found = starts(&["DTSTART:20240101T090000Z\nRRULE:FREQ=WEEKLY;BYDAY=1é,MO"], MONDAY, MONDAY + 7 * DAY);
+        assert_eq!(found, [MONDAY + 9 * HOUR]);
+        assert!(parse_rule("FREQ=WEEKLY;BYDAY=é").is_err());
+        assert_eq!(parse_rule("FREQ=MONTHLY;BYDAY=-1FR").unwrap().by_day, [(Some(-1), 4)]);
+    }
+
+    #[test]
+    fn time_zones() {
+        if !std::path::Path::new(crate::zoneinfo::ZONEINFO_DIR).join("Europe/Berlin").is_file() {
+            return;
+        }
+
+        // 10:00 in Berlin is 09:00 UTC in winter and 08:00 UTC in summer
+        let found = starts(&["DTSTART;TZID=Europe/Berlin:20240101T100000\nDTEND;TZID=Europe/Berlin:20240101T110000"], MONDAY, MONDAY + DAY);
+        assert_eq!(found, [MONDAY + 9 * HOUR]);
+
+        let july = MONDAY + 182 * DAY;
+        let found = starts(&["DTSTART;TZID=\"/mozilla.org/20050126_1/Europe/Berlin\":20240701T100000"], july, july + DAY);
+        assert_eq!(found, [july + 8 * HOUR]);
+
+        // A weekly series keeps its local time across the DST change on 2024-03-31
+        let march = MONDAY + 84 * DAY;
+        let found = starts(
+            &["DTSTART;TZID=Europe/Berlin:20240325T100000\nRRULE:FREQ=WEEKLY;COUNT=2\nEXDATE;TZID=Europe/Berlin:20240408T100000"],
+            march,
+            march + 21 * DAY,
+        );
+        assert_eq!(found, [march + 9 * HOUR, march + 7 * DAY + 8 * HOUR]);
+
+        // Events in zones that can't be resolved are skipped rather than misplaced
+        let found = starts(&["DTSTART;TZID=W. Europe Standard Time:20240101T100000"], MONDAY, MONDAY + DAY);
+        assert!(found.is_empty());
+    }
+}
@@ -0,0 +1,606 @@
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use crate::config::{CompositorConfig, WindowRule, WindowRuleScope};
+use crate::daemon::Daemon;
+
+/// Hold kind for window rules; sources are `window:<rule name>`.
+pub const HOLD_KIND: &str = "window";
+
+/// A toplevel window as reported by the compositor.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WindowInfo {
+    /// Wayland app id or X11 class
+    pub class: String,
+    pub title: String,
+    pub fullscreen: bool,
+    pub focused: bool,
+    /// On a workspace currently shown on some output
+    pub visible: bool,
+}
+
+/// A compositor IPC connection that can list windows and wait for changes.
+pub trait CompositorBackend: Send {
+    fn name(&self) -> &'static str;
+
+    /// Current toplevel windows.
+    fn windows(&mut self) -> impl Future<Output = Result<Vec<WindowInfo>>> + Send;
+
+    /// Resolves after the next event that may change focus, visibility or fullscreen state.
+    fn wait_for_change(&mut self) -> impl Future<Output = Result<()>> + Send;
+}
+
+/// A window rule with its regexes compiled.
+pub struct WindowMatcher {
+    rule: WindowRule,
+    class: Option<Regex>,
+    title: Option<Regex>,
+}
+
+impl WindowMatcher {
+    pub fn new(rule: WindowRule) -> Result<Self> {
+        let compile = |pattern: &Option<String>, field: &str| {
+            pattern
+                .as_deref()
+                .map(Regex::new)
+                .transpose()
+                .with_context(|| format!("Invalid {} regex in window rule '{}'", field, rule.name))
+        };
+        let class = compile(&rule.class, "class")?;
+        let title = compile(&rule.title, "title")?;
+
+        Ok(Self { rule, class, title })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.rule.name
+    }
+
+    pub fn reason(&self, window: &WindowInfo) -> String {
+        self.rule
+            .reason
+            .clone()
+            .unwrap_or_else(|| format!("{} ({})", self.rule.name, window.class))
+    }
+
+    /// Every criterion that is set must match; a rule with no criteria never matches.
+    pub fn matches(&self, window: &WindowInfo) -> bool {
+        if self.rule.fullscreen.is_none() && self.class.is_none() && self.title.is_none() {
+            return false;
+        }
+
+        let in_scope = match self.rule.when {
+            WindowRuleScope::Focused => window.focused,
+            WindowRuleScope::Visible => window.visible,
+        };
+
+        in_scope
+            && self.rule.fullscreen.is_none_or(|fullscreen| fullscreen == window.fullscreen)
+            && self.class.as_ref().is_none_or(|re| re.is_match(&window.class))
+            && self.title.as_ref().is_none_or(|re| re.is_match(&window.title))
+    }
+}
+
+/// Hyprland backend using the request socket and the `.socket2.sock` event socket.
+pub struct Hyprland {
+    request_socket: PathBuf,
+    events: BufReader<UnixStream>,
+}
+
+/// Events after which the window list is re-read.
+const HYPRLAND_EVENTS: &[&str] = &[
+    "activewindow",
+    "activewindowv2",
+    "fullscreen",
+    "workspace",
+    "workspacev2",
+    "focusedmon",
+    "openwindow",
+    "closewindow",
+    "movewindow",
+    "movewindowv2",
+    "windowtitle",
+    "windowtitlev2",
+    "activespecial",
+];
+
+impl Hyprland {
+    /// Connects to the sockets in a Hyprland instance directory.
+    pub async fn connect(instance_dir: &Path) -> Result<Self> {
+        let event_socket = instance_dir.join(".socket2.sock");
+        let events = UnixStream::connect(&event_socket)
+            .await
+            .with_context(|| format!("Failed to connect to {}", event_socket.display()))?;
+
+        Ok(Self {
+            request_socket: instance_dir.join(".socket.sock"),
+            events: BufReader::new(events),
+        })
+    }
+
+    /// Finds the running instance via `$HYPRLAND_INSTANCE_SIGNATURE`.
+    pub async fn from_env() -> Result<Self> {
+        let signature = std::env::var("HYPRLAND_INSTANCE_SIGNATURE")
+            .context("HYPRLAND_INSTANCE_SIGNATURE not set")?;
+
+        let runtime_dir = std::env::var("XDG_RUNTIME_DIR").context("XDG_RUNTIME_DIR not set")?;
+        let instance_dir = PathBuf::from(runtime_dir).join("hypr").join(&signature);
+
+        // Hyprland before 0.40 kept its sockets under /tmp
+        if instance_dir.exists() {
+            Self::connect(&instance_dir).await
+        } else {
+            Self::connect(&PathBuf::from("/tmp/hypr").join(&signature)).await
+        }
+    }
+
+    async fn request(&self, command: &str) -> Result<Value> {
+        let mut stream = UnixStream::connect(&self.request_socket)
+            .await
+            .with_context(|| format!("Failed to connect to {}", self.request_socket.display()))?;
+
+        stream.write_all(command.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+
+        serde_json::from_slice(&response)
+            .with_context(|| format!("Invalid JSON in Hyprland reply to {}", command))
+    }
+}
+
+impl CompositorBackend for Hyprland {
+    fn name(&self) -> &'static str {
+        "hyprland"
+    }
+
+    async fn windows(&mut self) -> Result<Vec<WindowInfo>> {
+        let clients = self.request("j/clients").await?;
+        let monitors = self.request("j/monitors").await?;
+        let active = self.request("j/activewindow").await?;
+
+        let mut visible_workspaces = BTreeSet::new();
+        for monitor in monitors.as_array().into_iter().flatten() {
+            for key in ["activeWorkspace", "specialWorkspace"] {
+                if let Some(id) = monitor[key]["id"].as_i64().filter(|id| *id != 0) {
+                    visible_workspaces.insert(id);
+                }
+            }
+        }
+
+        let active_address = active["address"].as_str().unwrap_or_default();
+
+        Ok(clients
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|client| client["mapped"].as_bool().unwrap_or(true))
+            .map(|client| WindowInfo {
+                class: client["class"].as_str().unwrap_or_default().to_string(),
+                title: client["title"].as_str().unwrap_or_default().to_string(),
+                // A bool before Hyprland 0.42, a fullscreen mode number since
+                fullscreen: client["fullscreen"].as_bool()
+                    .unwrap_or_else(|| client["fullscreen"].as_i64().unwrap_or(0) > 0),
+                focused: !active_address.is_empty()
+                    && client["address"].as_str() == Some(active_address),
+                visible: !client["hidden"].as_bool().unwrap_or(false)
+                    && client["workspace"]["id"]
+                        .as_i64()
+                        .is_some_and(|id| visible_workspaces.contains(&id)),
+            })
+            .collect())
+    }
+
+    async fn wait_for_change(&mut self) -> Result<()> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.events.read_line(&mut line).await? == 0 {
+                bail!("Hyprland event socket closed");
+            }
+
+            let event = line.split_once(">>").map_or(line.trim(), |(event, _)| event);
+            if HYPRLAND_EVENTS.contains(&event) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+const I3_IPC_MAGIC: &[u8] = b"i3-ipc";
+const I3_IPC_SUBSCRIBE: u32 = 2;
+const I3_IPC_GET_TREE: u32 = 4;
+
+/// Sway (or i3) backend using the i3 IPC protocol.
+pub struct Sway {
+    commands: UnixStream,
+    events: UnixStream,
+}
+
+impl Sway {
+    /// Connects to the IPC socket and subscribes to window and workspace events.
+    pub async fn connect(socket: &Path) -> Result<Self> {
+        let connect = || async {
+            UnixStream::connect(socket)
+                .await
+                .with_context(|| format!("Failed to connect to {}", socket.display()))
+        };
+        let commands = connect().await?;
+        let mut events = connect().await?;
+
+        write_ipc(&mut events, I3_IPC_SUBSCRIBE, br#"["window","workspace"]"#).await?;
+        let (_, reply) = read_ipc(&mut events).await?;
+        let reply: Value = serde_json::from_slice(&reply)?;
+        if reply["success"].as_bool() != Some(true) {
+            bail!("Sway refused event subscription: {}", reply);
+        }
+
+        Ok(Self { commands, events })
+    }
+
+    /// Finds the socket via `$SWAYSOCK` or `$I3SOCK`.
+    pub async fn from_env() -> Result<Self> {
+        let socket = std::env::var("SWAYSOCK")
+            .or_else(|_| std::env::var("I3SOCK"))
+            .context("Neither SWAYSOCK nor I3SOCK is set")?;
+        Self::connect(Path::new(&socket)).await
+    }
+}
+
+async fn write_ipc(stream: &mut UnixStream, message_type: u32, payload: &[u8]) -> Result<()> {
+    let mut message = Vec::with_capacity(14 + payload.len());
+    message.extend_from_slice(I3_IPC_MAGIC);
+    message.extend_from_slice(&(payload.len() as u32).to_ne_bytes());
+    message.extend_from_slice(&message_type.to_ne_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message).await?;
+    Ok(())
+}
+
+async fn read_ipc(stream: &mut UnixStream) -> Result<(u32, Vec<u8>)> {
+    let mut header = [0u8; 14];
+    stream.read_exact(&mut header).await.context("Sway IPC socket closed")?;
+    if &header[..6] != I3_IPC_MAGIC {
+        bail!("Invalid i3 IPC header");
+    }
+
+    let length = u32::from_ne_bytes(header[6..10].try_into()?) as usize;
+    let message_type = u32::from_ne_bytes(header[10..14].try_into()?);
+
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).await?;
+    Ok((message_type, payload))
+}
+
+fn collect_sway_windows(node: &Value, windows: &mut Vec<WindowInfo>) {
+    let children = node["nodes"].as_array().into_iter().flatten()
+        .chain(node["floating_nodes"].as_array().into_iter().flatten());
+
+    let mut is_leaf = true;
+    for child in children {
+        is_leaf = false;
+        collect_sway_windows(child, windows);
+    }
+
+    let node_type = node["type"].as_str().unwrap_or_default();
+    if is_leaf && (node_type == "con" || node_type == "floating_con") {
+        let class = node["app_id"]
+            .as_str()
+            .or_else(|| node["window_properties"]["class"].as_str())
+            .unwrap_or_default();
+
+        windows.push(WindowInfo {
+            class: class.to_string(),
+            title: node["name"].as_str().unwrap_or_default().to_string(),
+            fullscreen: node["fullscreen_mode"].as_i64().unwrap_or(0) > 0,
+            focused: node["focused"].as_bool().unwrap_or(false),
+            visible: node["visible"].as_bool().unwrap_or(false),
+        });
+    }
+}
+
+impl CompositorBackend for Sway {
+    fn name(&self) -> &'static str {
+        "sway"
+    }
+
+    async fn windows(&mut self) -> Result<Vec<WindowInfo>> {
+        write_ipc(&mut self.commands, I3_IPC_GET_TREE, b"").await?;
+        let (_, payload) = read_ipc(&mut self.commands).await?;
+        let tree: Value = serde_json::from_slice(&payload).context("Invalid JSON in Sway tree")?;
+
+        let mut windows = Vec::new();
+        collect_sway_windows(&tree, &mut windows);
+        Ok(windows)
+    }
+
+    async fn wait_for_change(&mut self) -> Result<()> {
+        // Only window and workspace events are subscribed, so any event counts
+        read_ipc(&mut self.events).await?;
+        Ok(())
+    }
+}
+
+/// Evaluates window rules against a backend and keeps one hold per matching rule.
+pub async fn watch_backend<B: CompositorBackend>(
+    daemon: Daemon,
+    matchers: Vec<WindowMatcher>,
+    mut backend: B,
+) -> Result<()> {
+    tracing::info!("Watching {} windows for {} rule(s)", backend.name(), matchers.len());
+
+    loop {
+        let windows = backend.windows().await?;
+
+        for matcher in &matchers {
+            let source = format!("{}:{}", HOLD_KIND, matcher.name());
+            match windows.iter().find(|window| matcher.matches(window)) {
+                Some(window) => daemon.set_hold(&source, &matcher.reason(window)).await?,
+                None => daemon.release_hold(&source).await?,
+            }
+        }
+
+        backend.wait_for_change().await?;
+    }
+}
+
+/// Connects to the configured (or detected) compositor and evaluates its window rules.
+pub async fn watch(daemon: Daemon, config: CompositorConfig) -> Result<()> {
+    let mut matchers = Vec::new();
+    for rule in config.rules {
+        match WindowMatcher::new(rule) {
+            Ok(matcher) => matchers.push(matcher),
+            Err(e) => tracing::warn!("Skipping window rule: {:#}", e),
+        }
+    }
+
+    let backend = match config.backend.as_str() {
+        "hyprland" => "hyprland",
+        "sway" | "i3" => "sway",
+        "auto" if std::env::var_os("HYPRLAND_INSTANCE_SIGNATURE").is_some() => "hyprland",
+        "auto" if std::env::var_os("SWAYSOCK").is_some() || std::env::var_os("I3SOCK").is_some() => "sway",
+        "auto" => bail!("No supported compositor detected (Hyprland, Sway)"),
+        other => bail!("Unknown compositor backend '{}'", other),
+    };
+
+    match backend {
+        "hyprland" => watch_backend(daemon, matchers, Hyprland::from_env().await?).await,
+        _ => watch_backend(daemon, matchers, Sway::from_env().await?).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::net::UnixListener;
+    use tokio::sync::Notify;
+    use crate::Config;
+
+    fn fullscreen_rule() -> WindowMatcher {
+        WindowMatcher::new(WindowRule {
+            name: "fullscreen".to_string(),
+            fullscreen: Some(true),
+            class: None,
+            title: None,
+            when: WindowRuleScope::Focused,
+            reason: None,
+        })
+        .unwrap()
+    }
+
+    /// Waits until the daemon holds (or no longer holds) `source`.
+    async fn wait_for_hold(daemon: &Daemon, source: &str, held: bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while daemon.holds().await.iter().any(|(s, _)| s == source) != held {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("hold {} never became {}", source, held));
+    }
+
+    /// Serves Hyprland's request socket from `clients` and returns the event socket listener.
+    fn fake_hyprland(dir: &Path, clients: Arc<Mutex<Value>>) -> UnixListener {
+        let requests = UnixListener::bind(dir.join(".socket.sock")).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = requests.accept().await.unwrap();
+                let mut command = vec![0u8; 64];
+                let read = stream.read(&mut command).await.unwrap();
+                let reply = match &command[..read] {
+                    b"j/clients" => clients.lock().unwrap().clone(),
+                    b"j/monitors" => json!([{ "activeWorkspace": { "id": 1 }, "specialWorkspace": { "id": 0 } }]),
+                    b"j/activewindow" => json!({ "address": "0x1" }),
+                    other => panic!("unexpected request {:?}", String::from_utf8_lossy(other)),
+                };
+                stream.write_all(reply.to_string().as_bytes()).await.unwrap();
+            }
+        });
+        UnixListener::bind(dir.join(".socket2.sock")).unwrap()
+    }
+
+    fn hyprland_client(address: &str, class: &str, workspace: i64, fullscreen: Value) -> Value {
+        json!({
+            "address": address,
+            "class": class,
+            "title": class,
+            "mapped": true,
+            "hidden": false,
+            "workspace": { "id": workspace },
+            "fullscreen": fullscreen,
+        })
+    }
+
+    #[tokio::test]
+    async fn hyprland_reports_windows() {
+        let dir = tempfile::tempdir().unwrap();
+        let clients = Arc::new(Mutex::new(json!([
+            hyprland_client("0x1", "mpv", 1, json!(2)),
+            hyprland_client("0x2", "firefox", 2, json!(false)),
+        ])));
+        let _events = fake_hyprland(dir.path(), clients);
+
+        let mut backend = Hyprland::connect(dir.path()).await.unwrap();
+        let windows = backend.windows().await.unwrap();
+
+        assert_eq!(windows.len(), 2);
+        assert_eq!(windows[0].class, "mpv");
+        assert!(windows[0].fullscreen && windows[0].focused && windows[0].visible);
+        assert_eq!(windows[1].class, "firefox");
+        assert!(!windows[1].fullscreen && !windows[1].focused && !windows[1].visible);
+    }
+
+    #[tokio::test]
+    async fn hyprland_events_update_the_hold() {
+        let dir = tempfile::tempdir().unwrap();
+        let clients = Arc::new(Mutex::new(json!([hyprland_client("0x1", "mpv", 1, json!(false))])));
+        let events = fake_hyprland(dir.path(), clients.clone());
+
+        let daemon = Daemon::detached(Config::default());
+        let backend = Hyprland::connect(dir.path()).await.unwrap();
+        let (mut event_stream, _) = events.accept().await.unwrap();
+        tokio::spawn(watch_backend(daemon.clone(), vec![fullscreen_rule()], backend));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        wait_for_hold(&daemon, "window:fullscreen", false).await;
+
+        *clients.lock().unwrap() = json!([hyprland_client("0x1", "mpv", 1, json!(true))]);
+        // Unrelated events don't trigger a re-read; fullscreen does
+        event_stream.write_all(b"urgent>>0x1\nfullscreen>>1\n").await.unwrap();
+        wait_for_hold(&daemon, "window:fullscreen", true).await;
+        assert!(daemon.state().await.is_enabled());
+
+        *clients.lock().unwrap() = json!([hyprland_client("0x1", "mpv", 1, json!(false))]);
+        event_stream.write_all(b"fullscreen>>0\n").await.unwrap();
+        wait_for_hold(&daemon, "window:fullscreen", false).await;
+        assert!(!daemon.state().await.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn hyprland_closed_event_socket_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let events = fake_hyprland(dir.path(), Arc::new(Mutex::new(json!([]))));
+
+        let mut backend = Hyprland::connect(dir.path()).await.unwrap();
+        drop(events.accept().await.unwrap());
+        assert!(backend.wait_for_change().await.is_err());
+    }
+
+    fn sway_tree(fullscreen: bool) -> Value {
+        json!({
+            "type": "root",
+            "nodes": [{
+                "type": "output",
+                "nodes": [{
+                    "type": "workspace",
+                    "nodes": [
+                        { "type": "con", "app_id": "mpv", "name": "video.mkv", "focused": true, "visible": true,
+                          "fullscreen_mode": if fullscreen { 1 } else { 0 }, "nodes": [] },
+                        { "type": "con", "app_id": null, "window_properties": { "class": "Steam" },
+                          "name": "Steam", "focused": false, "visible": true, "fullscreen_mode": 0, "nodes": [] },
+                    ],
+                    "floating_nodes": [],
+                }],
+            }],
+        })
+    }
+
+    /// Serves i3 IPC: subscriptions succeed and get an event per `changed`
+    /// notification, GET_TREE returns `tree`.
+    fn fake_sway(socket: &Path, tree: Arc<Mutex<Value>>, changed: Arc<Notify>) {
+        let listener = UnixListener::bind(socket).unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let tree = tree.clone();
+                let changed = changed.clone();
+                tokio::spawn(async move {
+                    while let Ok((message_type, _)) = read_ipc(&mut stream).await {
+                        match message_type {
+                            I3_IPC_SUBSCRIBE => {
+                                write_ipc(&mut stream, I3_IPC_SUBSCRIBE, br#"{"success":true}"#).await.unwrap();
+                                loop {
+                                    changed.notified().await;
+                                    write_ipc(&mut stream, 0x8000_0003, br#"{"change":"fullscreen_mode"}"#)
+                                        .await
+                                        .unwrap();
+                                }
+                            }
+                            I3_IPC_GET_TREE => {
+                                let payload = tree.lock().unwrap().to_string();
+                                write_ipc(&mut stream, I3_IPC_GET_TREE, payload.as_bytes()).await.unwrap();
+                            }
+                            other => panic!("unexpected message type {}", other),
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn sway_reports_windows() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("sway-ipc.sock");
+        fake_sway(&socket, Arc::new(Mutex::new(sway_tree(true))), Arc::new(Notify::new()));
+
+        let mut backend = Sway::connect(&socket).await.unwrap();
+        let windows = backend.windows().await.unwrap();
+
+        assert_eq!(windows.len(), 2);
+        assert_eq!(windows[0].class, "mpv");
+        assert!(windows[0].fullscreen && windows[0].focused);
+        assert_eq!(windows[1].class, "Steam");
+        assert!(!windows[1].fullscreen && !windows[1].focused && windows[1].visible);
+    }
+
+    #[tokio::test]
+    async fn sway_events_update_the_hold() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("sway-ipc.sock");
+        let tree = Arc::new(Mutex::new(sway_tree(false)));
+        let changed = Arc::new(Notify::new());
+        fake_sway(&socket, tree.clone(), changed.clone());
+
+        let daemon = Daemon::detached(Config::default());
+        let backend = Sway::connect(&socket).await.unwrap();
+        tokio::spawn(watch_backend(daemon.clone(), vec![fullscreen_rule()], backend));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        wait_for_hold(&daemon, "window:fullscreen", false).await;
+
+        *tree.lock().unwrap() = sway_tree(true);
+        changed.notify_one();
+        wait_for_hold(&daemon, "window:fullscreen", true).await;
+        let holds = daemon.holds().await;
+        let (_, hold) = holds.iter().find(|(source, _)| *source == "window:fullscreen").unwrap();
+        assert_eq!(hold.reason, "fullscreen (mpv)");
+
+        *tree.lock().unwrap() = sway_tree(false);
+        changed.notify_one();
+        wait_for_hold(&daemon, "window:fullscreen", false).await;
+    }
+
+    #[tokio::test]
+    async fn sway_subscription_refusal_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("sway-ipc.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        tokio::spawn(async move {
+            let (_commands, _) = listener.accept().await.unwrap();
+            let (mut events, _) = listener.accept().await.unwrap();
+            read_ipc(&mut events).await.unwrap();
+            write_ipc(&mut events, I3_IPC_SUBSCRIBE, br#"{"success":false}"#).await.unwrap();
+        });
+
+        assert!(Sway::connect(&socket).await.is_err());
+    }
+}
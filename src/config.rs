@@ -1,6 +1,8 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -12,6 +14,113 @@ pub struct Config {
     
     #[serde(default = "default_log_level")]
     pub log_level: String,
+    
+    #[serde(default = "default_acquire_retry_secs")]
+    pub acquire_retry_secs: u64,
+    
+    #[serde(default = "default_reconcile_interval_secs")]
+    pub reconcile_interval_secs: u64,
+    
+    #[serde(default = "default_who_template")]
+    pub who_template: String,
+    
+    #[serde(default = "default_why_template")]
+    pub why_template: String,
+    
+    #[serde(default = "default_reason")]
+    pub default_reason: String,
+    
+    #[serde(default)]
+    pub process: ProcessConfig,
+    
+    #[serde(default)]
+    pub media: MediaConfig,
+    
+    #[serde(default)]
+    pub compositor: CompositorConfig,
+    
+    #[serde(default)]
+    pub power: PowerConfig,
+    
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    
+    #[serde(default)]
+    pub calendar: CalendarConfig,
+    
+    #[serde(default)]
+    pub devices: DevicesConfig,
+    
+    #[serde(default)]
+    pub network: NetworkConfig,
+    
+    #[serde(default)]
+    pub load: LoadConfig,
+    
+    #[serde(default)]
+    pub systemd: SystemdConfig,
+    
+    #[serde(default)]
+    pub remote: RemoteConfig,
+    
+    #[serde(default)]
+    pub gamepad: GamepadConfig,
+    
+    #[serde(default)]
+    pub usb: UsbConfig,
+    
+    #[serde(default)]
+    pub bluetooth: BluetoothConfig,
+    
+    #[serde(default)]
+    pub dock: DockConfig,
+    
+    #[serde(default)]
+    pub rule_engine: RuleEngineConfig,
+    
+    #[serde(default)]
+    pub plugins: Vec<PluginConfig>,
+    
+    #[serde(default)]
+    pub scripts: ScriptsConfig,
+    
+    #[serde(default)]
+    pub waybar: WaybarConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessConfig {
+    #[serde(default = "default_process_scan_interval_secs")]
+    pub scan_interval_secs: u64,
+    
+    #[serde(default)]
+    pub rules: Vec<ProcessRule>,
+}
+
+/// Keeps the inhibitor on while a matching process runs. Every criterion that is
+/// set must match; a rule with no criteria never matches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProcessRule {
+    pub name: String,
+    
+    /// Executable name, compared against comm, argv[0] and the /proc/<pid>/exe basename
+    #[serde(default)]
+    pub exe: Option<String>,
+    
+    /// Regex matched against the space-joined command line
+    #[serde(default)]
+    pub cmdline: Option<String>,
+    
+    /// Substring matched against /proc/<pid>/cgroup
+    #[serde(default)]
+    pub cgroup: Option<String>,
+    
+    /// Flatpak application id, taken from the app-flatpak-<id>-*.scope cgroup
+    #[serde(default)]
+    pub flatpak: Option<String>,
+    
+    #[serde(default)]
+    pub reason: Option<String>,
 }
 
 fn default_state_on_start() -> bool {
@@ -26,12 +135,852 @@ fn default_log_level() -> String {
     "info".to_string()
 }
 
+fn default_acquire_retry_secs() -> u64 {
+    0
+}
+
+fn default_reconcile_interval_secs() -> u64 {
+    60
+}
+
+fn default_who_template() -> String {
+    "logind-idle-control".to_string()
+}
+
+fn default_why_template() -> String {
+    "{reasons}".to_string()
+}
+
+fn default_reason() -> String {
+    "User requested idle inhibition".to_string()
+}
+
+/// Auto-inhibit while an MPRIS player is playing.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MediaConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Only count players whose current track looks like a video
+    #[serde(default)]
+    pub video_only: bool,
+    
+    /// Bus names (e.g. "mpv" or "org.mpris.MediaPlayer2.mpv") or Identity values; empty allows all
+    #[serde(default)]
+    pub allow: Vec<String>,
+    
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// Auto-inhibit based on compositor window state (Hyprland, Sway/i3).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompositorConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// "auto", "hyprland" or "sway"
+    #[serde(default = "default_compositor_backend")]
+    pub backend: String,
+    
+    #[serde(default)]
+    pub rules: Vec<WindowRule>,
+}
+
+/// Every criterion that is set must match the window; a rule with no criteria never matches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WindowRule {
+    pub name: String,
+    
+    #[serde(default)]
+    pub fullscreen: Option<bool>,
+    
+    /// Regex matched against the Wayland app id or X11 class
+    #[serde(default)]
+    pub class: Option<String>,
+    
+    /// Regex matched against the window title
+    #[serde(default)]
+    pub title: Option<String>,
+    
+    #[serde(default)]
+    pub when: WindowRuleScope,
+    
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WindowRuleScope {
+    /// Only the focused window is considered
+    #[default]
+    Focused,
+    /// Any window on a workspace shown on some output
+    Visible,
+}
+
+/// AC/battery policies, from UPower with a /sys/class/power_supply fallback.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PowerConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Force the inhibitor off while on battery
+    #[serde(default)]
+    pub ac_only: bool,
+    
+    /// Force the inhibitor off on battery below this charge
+    #[serde(default)]
+    pub min_battery_percent: Option<u8>,
+    
+    /// Hold the inhibitor while docked and on AC
+    #[serde(default)]
+    pub enable_on_ac_docked: bool,
+    
+    #[serde(default = "default_power_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+}
+
+fn default_power_poll_interval_secs() -> u64 {
+    60
+}
+
+impl Default for PowerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ac_only: false,
+            min_battery_percent: None,
+            enable_on_ac_docked: false,
+            poll_interval_secs: default_power_poll_interval_secs(),
+        }
+    }
+}
+
+/// Weekly time windows that hold the inhibitor or force it off.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScheduleConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    #[serde(default)]
+    pub rules: Vec<ScheduleRule>,
+}
+
+/// A local-time window such as weekdays 09:00-17:30. A window whose end is not
+/// after its start runs past midnight and belongs to the day it starts on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleRule {
+    pub name: String,
+    
+    /// Weekdays ("mon".."sun", or ranges like "mon-fri"); empty means every day
+    #[serde(default)]
+    pub days: Vec<String>,
+    
+    /// "HH:MM"
+    pub start: String,
+    
+    /// "HH:MM"
+    pub end: String,
+    
+    #[serde(default)]
+    pub action: ScheduleAction,
+    
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleAction {
+    /// Hold the inhibitor during the window
+    #[default]
+    Inhibit,
+    /// Force every inhibitor off during the window
+    Quiet,
+}
+
+/// Auto-inhibit around busy events in local iCalendar files (e.g. synced by vdirsyncer).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalendarConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// .ics files, or directories whose .ics files are all read; "~/" is expanded
+    #[serde(default)]
+    pub paths: Vec<String>,
+    
+    /// Minutes before an event starts to take the inhibitor
+    #[serde(default = "default_calendar_lead_minutes")]
+    pub lead_minutes: u64,
+    
+    /// Seconds between checks for changed calendar files
+    #[serde(default = "default_calendar_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+}
+
+fn default_calendar_lead_minutes() -> u64 {
+    2
+}
+
+fn default_calendar_poll_interval_secs() -> u64 {
+    30
+}
+
+impl Default for CalendarConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            paths: Vec::new(),
+            lead_minutes: default_calendar_lead_minutes(),
+            poll_interval_secs: default_calendar_poll_interval_secs(),
+        }
+    }
+}
+
+/// Auto-inhibit while one of our processes has a camera (or microphone) open.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DevicesConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Also count ALSA capture devices (/dev/snd/pcm*c)
+    #[serde(default)]
+    pub microphone: bool,
+    
+    #[serde(default = "default_devices_scan_interval_secs")]
+    pub scan_interval_secs: u64,
+}
+
+fn default_devices_scan_interval_secs() -> u64 {
+    5
+}
+
+impl Default for DevicesConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            microphone: false,
+            scan_interval_secs: default_devices_scan_interval_secs(),
+        }
+    }
+}
+
+/// Auto-inhibit while network throughput stays above a threshold.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Interfaces to sum (e.g. "wlan0"); empty means all except loopback
+    #[serde(default)]
+    pub interfaces: Vec<String>,
+    
+    /// Combined receive + transmit rate that starts the hold
+    #[serde(default = "default_network_threshold_kib_per_sec")]
+    pub threshold_kib_per_sec: u64,
+    
+    /// Rate the transfer must drop below before the hold ends (default: half the threshold)
+    #[serde(default)]
+    pub release_kib_per_sec: Option<u64>,
+    
+    /// How long the rate must stay above the threshold before holding
+    #[serde(default = "default_network_sustain_secs")]
+    pub sustain_secs: u64,
+    
+    /// How long the rate must stay below the release threshold before releasing
+    #[serde(default = "default_network_cooldown_secs")]
+    pub cooldown_secs: u64,
+    
+    #[serde(default = "default_network_sample_interval_secs")]
+    pub sample_interval_secs: u64,
+    
+    /// Colon-separated logind inhibitor types to hold
+    #[serde(default = "default_network_what")]
+    pub what: String,
+}
+
+fn default_network_threshold_kib_per_sec() -> u64 {
+    256
+}
+
+fn default_network_sustain_secs() -> u64 {
+    30
+}
+
+fn default_network_cooldown_secs() -> u64 {
+    60
+}
+
+fn default_network_sample_interval_secs() -> u64 {
+    5
+}
+
+fn default_network_what() -> String {
+    "sleep:idle".to_string()
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interfaces: Vec::new(),
+            threshold_kib_per_sec: default_network_threshold_kib_per_sec(),
+            release_kib_per_sec: None,
+            sustain_secs: default_network_sustain_secs(),
+            cooldown_secs: default_network_cooldown_secs(),
+            sample_interval_secs: default_network_sample_interval_secs(),
+            what: default_network_what(),
+        }
+    }
+}
+
+/// Auto-inhibit while the system is busy. The hold starts when any configured
+/// threshold is reached; unset thresholds are ignored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// 1-minute load average from /proc/loadavg
+    #[serde(default)]
+    pub loadavg_threshold: Option<f64>,
+    
+    /// CPU utilization in percent, from /proc/stat
+    #[serde(default)]
+    pub cpu_percent_threshold: Option<f64>,
+    
+    /// CPU pressure (`some avg10` in /proc/pressure/cpu) in percent
+    #[serde(default)]
+    pub psi_threshold: Option<f64>,
+    
+    /// Thresholds are scaled by this while holding, so the load must drop clearly to release
+    #[serde(default = "default_load_release_factor")]
+    pub release_factor: f64,
+    
+    #[serde(default = "default_load_sustain_secs")]
+    pub sustain_secs: u64,
+    
+    #[serde(default = "default_load_cooldown_secs")]
+    pub cooldown_secs: u64,
+    
+    #[serde(default = "default_load_sample_interval_secs")]
+    pub sample_interval_secs: u64,
+    
+    /// Colon-separated logind inhibitor types to hold
+    #[serde(default = "default_load_what")]
+    pub what: String,
+}
+
+fn default_load_release_factor() -> f64 {
+    0.75
+}
+
+fn default_load_sustain_secs() -> u64 {
+    60
+}
+
+fn default_load_cooldown_secs() -> u64 {
+    120
+}
+
+fn default_load_sample_interval_secs() -> u64 {
+    5
+}
+
+fn default_load_what() -> String {
+    "sleep:idle".to_string()
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            loadavg_threshold: None,
+            cpu_percent_threshold: None,
+            psi_threshold: None,
+            release_factor: default_load_release_factor(),
+            sustain_secs: default_load_sustain_secs(),
+            cooldown_secs: default_load_cooldown_secs(),
+            sample_interval_secs: default_load_sample_interval_secs(),
+            what: default_load_what(),
+        }
+    }
+}
+
+/// Auto-inhibit while matching systemd units are active or activating.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SystemdConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Unit names or globs on the user manager, e.g. "backup-*.service"
+    #[serde(default)]
+    pub user_units: Vec<String>,
+    
+    /// Unit names or globs on the system manager, e.g. "dnf-makecache.service"
+    #[serde(default)]
+    pub system_units: Vec<String>,
+    
+    /// Colon-separated logind inhibitor types to hold
+    #[serde(default = "default_systemd_what")]
+    pub what: String,
+}
+
+fn default_systemd_what() -> String {
+    "sleep:idle".to_string()
+}
+
+impl Default for SystemdConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            user_units: Vec::new(),
+            system_units: Vec::new(),
+            what: default_systemd_what(),
+        }
+    }
+}
+
+/// Auto-inhibit while someone is connected remotely.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Follow this user's logind sessions with Remote=yes (SSH, xrdp)
+    #[serde(default = "default_remote_sessions")]
+    pub sessions: bool,
+    
+    /// Remote-desktop server process names (as in /proc/<pid>/comm); each counts
+    /// while it has an established TCP connection
+    #[serde(default = "default_remote_processes")]
+    pub processes: Vec<String>,
+    
+    #[serde(default = "default_remote_scan_interval_secs")]
+    pub scan_interval_secs: u64,
+}
+
+fn default_remote_sessions() -> bool {
+    true
+}
+
+fn default_remote_processes() -> Vec<String> {
+    ["wayvnc", "x11vnc", "Xvnc", "krfb", "gnome-remote-de", "sunshine"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
+
+fn default_remote_scan_interval_secs() -> u64 {
+    10
+}
+
+impl Default for RemoteConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            sessions: default_remote_sessions(),
+            processes: default_remote_processes(),
+            scan_interval_secs: default_remote_scan_interval_secs(),
+        }
+    }
+}
+
+/// Counts joystick and gamepad input as presence.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GamepadConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Device name substrings or `vendor:product` ids (e.g. "045e:0b12"); empty means every controller
+    #[serde(default)]
+    pub devices: Vec<String>,
+    
+    /// Keep the hold this long after the last input
+    #[serde(default = "default_gamepad_hold_secs")]
+    pub hold_secs: u64,
+    
+    /// Stick movement below this share of the axis range is treated as noise
+    #[serde(default = "default_gamepad_deadzone_percent")]
+    pub deadzone_percent: f64,
+    
+    /// How often /sys/class/input is checked for plugged or removed controllers
+    #[serde(default = "default_gamepad_rescan_interval_secs")]
+    pub rescan_interval_secs: u64,
+    
+    #[serde(default = "default_gamepad_what")]
+    pub what: String,
+}
+
+fn default_gamepad_hold_secs() -> u64 {
+    30
+}
+
+fn default_gamepad_deadzone_percent() -> f64 {
+    10.0
+}
+
+fn default_gamepad_rescan_interval_secs() -> u64 {
+    5
+}
+
+fn default_gamepad_what() -> String {
+    "idle".to_string()
+}
+
+impl Default for GamepadConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            devices: Vec::new(),
+            hold_secs: default_gamepad_hold_secs(),
+            deadzone_percent: default_gamepad_deadzone_percent(),
+            rescan_interval_secs: default_gamepad_rescan_interval_secs(),
+            what: default_gamepad_what(),
+        }
+    }
+}
+
+/// Auto-inhibit while specific USB devices (a clicker, dock or token) are attached.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsbConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    #[serde(default)]
+    pub rules: Vec<UsbRule>,
+    
+    #[serde(default = "default_usb_what")]
+    pub what: String,
+}
+
+/// Keeps the inhibitor on while a matching device is attached. Every criterion
+/// that is set must match; a rule with no criteria never matches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsbRule {
+    pub name: String,
+    
+    /// `vendor:product` in hex, e.g. "17ef:a396"
+    #[serde(default)]
+    pub id: Option<String>,
+    
+    /// sysfs attributes of the device that must match exactly, e.g. `serial` or `manufacturer`
+    #[serde(default)]
+    pub attributes: BTreeMap<String, String>,
+    
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+fn default_usb_what() -> String {
+    "sleep:idle".to_string()
+}
+
+impl Default for UsbConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            rules: Vec::new(),
+            what: default_usb_what(),
+        }
+    }
+}
+
+/// Auto-inhibit while a Bluetooth device (a headset, a phone) is connected.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BluetoothConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Device addresses, e.g. "AA:BB:CC:DD:EE:FF"
+    #[serde(default)]
+    pub devices: Vec<String>,
+    
+    #[serde(default = "default_bluetooth_what")]
+    pub what: String,
+}
+
+fn default_bluetooth_what() -> String {
+    "sleep:idle".to_string()
+}
+
+impl Default for BluetoothConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            devices: Vec::new(),
+            what: default_bluetooth_what(),
+        }
+    }
+}
+
+/// Blocks lid-switch handling while docked with external displays.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DockConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Only count as docked while on AC power
+    #[serde(default = "default_dock_require_ac")]
+    pub require_ac: bool,
+    
+    /// Connected external outputs (from /sys/class/drm) needed to count as docked
+    #[serde(default = "default_dock_min_external_outputs")]
+    pub min_external_outputs: usize,
+    
+    /// Fallback re-check interval; changes are normally picked up from uevents
+    #[serde(default = "default_dock_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    
+    #[serde(default = "default_dock_what")]
+    pub what: String,
+}
+
+fn default_dock_require_ac() -> bool {
+    true
+}
+
+fn default_dock_min_external_outputs() -> usize {
+    1
+}
+
+fn default_dock_poll_interval_secs() -> u64 {
+    30
+}
+
+fn default_dock_what() -> String {
+    "handle-lid-switch".to_string()
+}
+
+impl Default for DockConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            require_ac: default_dock_require_ac(),
+            min_external_outputs: default_dock_min_external_outputs(),
+            poll_interval_secs: default_dock_poll_interval_secs(),
+            what: default_dock_what(),
+        }
+    }
+}
+
+/// Rules combining the other sources, e.g. "media AND power:ac AND NOT schedule:night".
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RuleEngineConfig {
+    /// Hold/suppression kinds (e.g. "media", "schedule") that only feed rule
+    /// conditions and no longer act on the inhibitor by themselves
+    #[serde(default)]
+    pub passive: Vec<String>,
+    
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleConfig {
+    pub name: String,
+    
+    /// Boolean expression over condition names with AND, OR, NOT and parentheses
+    pub when: String,
+    
+    #[serde(default)]
+    pub action: RuleAction,
+    
+    /// The highest-priority true rule decides; release wins ties
+    #[serde(default)]
+    pub priority: i32,
+    
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleAction {
+    /// Keep the inhibitor on
+    #[default]
+    Hold,
+    /// Force every inhibitor off
+    Release,
+}
+
+/// An executable that reports conditions as newline-delimited JSON on stdout,
+/// e.g. `{"condition": "vpn", "value": true}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginConfig {
+    pub name: String,
+    
+    /// Program and arguments
+    pub command: Vec<String>,
+    
+    /// Restart the plugin if it prints nothing for this long; 0 disables the timeout
+    #[serde(default = "default_plugin_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_plugin_timeout_secs() -> u64 {
+    300
+}
+
+/// Rhai scripts that decide holds from the daemon's state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    
+    /// Script files, or directories whose *.rhai files are loaded
+    #[serde(default)]
+    pub paths: Vec<String>,
+    
+    /// Abort a script run after this many operations; must be at least 1
+    #[serde(default = "default_script_max_operations")]
+    pub max_operations: u64,
+    
+    /// Maximum function call nesting
+    #[serde(default = "default_script_max_call_depth")]
+    pub max_call_depth: usize,
+    
+    /// How often scripts are re-run and checked for changes on disk
+    #[serde(default = "default_script_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_script_max_operations() -> u64 {
+    100_000
+}
+
+fn default_script_max_call_depth() -> usize {
+    32
+}
+
+fn default_script_interval_secs() -> u64 {
+    5
+}
+
+impl Default for ScriptsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            paths: Vec::new(),
+            max_operations: default_script_max_operations(),
+            max_call_depth: default_script_max_call_depth(),
+            interval_secs: default_script_interval_secs(),
+        }
+    }
+}
+
+/// Templates for `monitor --format waybar`. Placeholders: {icon}, {state},
+/// {count} and {reasons}.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WaybarConfig {
+    #[serde(default = "default_waybar_text")]
+    pub text: String,
+    
+    /// First tooltip line; the active holds and the next schedule change follow it
+    #[serde(default = "default_waybar_tooltip")]
+    pub tooltip: String,
+    
+    #[serde(default = "default_waybar_icon_enabled")]
+    pub icon_enabled: String,
+    
+    #[serde(default = "default_waybar_icon_disabled")]
+    pub icon_disabled: String,
+    
+    #[serde(default = "default_waybar_icon_failed")]
+    pub icon_failed: String,
+}
+
+fn default_waybar_text() -> String {
+    "{icon}".to_string()
+}
+
+fn default_waybar_tooltip() -> String {
+    "Idle inhibitor {state}".to_string()
+}
+
+fn default_waybar_icon_enabled() -> String {
+    "☕".to_string()
+}
+
+fn default_waybar_icon_disabled() -> String {
+    "💤".to_string()
+}
+
+fn default_waybar_icon_failed() -> String {
+    "⚠".to_string()
+}
+
+impl Default for WaybarConfig {
+    fn default() -> Self {
+        Self {
+            text: default_waybar_text(),
+            tooltip: default_waybar_tooltip(),
+            icon_enabled: default_waybar_icon_enabled(),
+            icon_disabled: default_waybar_icon_disabled(),
+            icon_failed: default_waybar_icon_failed(),
+        }
+    }
+}
+
+fn default_compositor_backend() -> String {
+    "auto".to_string()
+}
+
+impl Default for CompositorConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            backend: default_compositor_backend(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+fn default_process_scan_interval_secs() -> u64 {
+    5
+}
+
+impl Default for ProcessConfig {
+    fn default() -> Self {
+        Self {
+            scan_interval_secs: default_process_scan_interval_secs(),
+            rules: Vec::new(),
+        }
+    }
+}
+
 impl Default for Config {
     fn default() -> Self {
         Self {
             state_on_start: default_state_on_start(),
             disable_on_lock: default_disable_on_lock(),
             log_level: default_log_level(),
+            acquire_retry_secs: default_acquire_retry_secs(),
+            reconcile_interval_secs: default_reconcile_interval_secs(),
+            who_template: default_who_template(),
+            why_template: default_why_template(),
+            default_reason: default_reason(),
+            process: ProcessConfig::default(),
+            media: MediaConfig::default(),
+            compositor: CompositorConfig::default(),
+            power: PowerConfig::default(),
+            schedule: ScheduleConfig::default(),
+            calendar: CalendarConfig::default(),
+            devices: DevicesConfig::default(),
+            network: NetworkConfig::default(),
+            load: LoadConfig::default(),
+            systemd: SystemdConfig::default(),
+            remote: RemoteConfig::default(),
+            gamepad: GamepadConfig::default(),
+            usb: UsbConfig::default(),
+            bluetooth: BluetoothConfig::default(),
+            dock: DockConfig::default(),
+            rule_engine: RuleEngineConfig::default(),
+            plugins: Vec::new(),
+            scripts: ScriptsConfig::default(),
+            waybar: WaybarConfig::default(),
         }
     }
 }
@@ -68,3 +1017,37 @@ impl Config {
             .join("config.toml")
     }
 }
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Resolves configured paths to files with their modification times, so
+/// callers can tell when to reload. Directories contribute every file with
+/// `extension` directly inside them; `~/` is expanded.
+pub(crate) fn watched_files(paths: &[String], extension: &str) -> BTreeMap<PathBuf, Option<SystemTime>> {
+    let mut files = BTreeMap::new();
+    let mut add = |path: &Path| {
+        let modified = std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+        files.insert(path.to_path_buf(), modified);
+    };
+
+    for path in paths.iter().map(|path| expand_home(path)) {
+        match std::fs::read_dir(&path) {
+            Ok(entries) => {
+                for entry in entries.flatten() {
+                    let file = entry.path();
+                    if file.extension().is_some_and(|ext| ext.eq_ignore_ascii_case(extension)) {
+                        add(&file);
+                    }
+                }
+            }
+            Err(_) => add(&path),
+        }
+    }
+
+    files
+}
@@ -0,0 +1,604 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+use crate::config::RuleAction;
+use crate::dbus::{self, InhibitorLock, RuleInfo};
+use crate::holds::{self, Holds};
+use crate::pidwatch::PidWatch;
+use crate::rules::{self, Rule};
+use crate::state;
+use crate::{Config, SessionInfo, State};
+
+/// Delay before restarting a failed watcher or plugin; doubles up to `MAX_BACKOFF`.
+pub const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// A watcher or plugin that ran at least this long is considered healthy again.
+pub const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// Reason of the suppression a manual disable adds over automatic holds.
+const MANUAL_DISABLE: &str = "Disabled manually";
+
+/// Shared daemon state. Cheap to clone; every clone drives the same inhibitor.
+#[derive(Clone)]
+pub struct Daemon {
+    config: Arc<Config>,
+    session: Arc<SessionInfo>,
+    inner: Arc<Mutex<Inner>>,
+    rules: Arc<Vec<Rule>>,
+    /// Bumped after every apply so observers can re-read the state.
+    changes: Arc<watch::Sender<u64>>,
+    repairs: Arc<AtomicU32>,
+    /// Tracks holds without touching logind, D-Bus or the state files (for tests).
+    detached: bool,
+}
+
+struct Inner {
+    state: State,
+    holds: Holds,
+    /// Policies currently forcing the inhibitor off regardless of holds, by source.
+    suppressions: BTreeMap<String, String>,
+    /// Latest measurement from each sampling watcher, for `status -v`.
+    readings: BTreeMap<String, String>,
+    /// Named facts published by providers for rule conditions (e.g. `power:ac`) that are true.
+    facts: BTreeSet<String>,
+    /// The rule currently deciding the outcome, if any.
+    applied_rule: Option<String>,
+    inhibitor: Option<InhibitorLock>,
+    /// The what/why strings the current inhibitor was taken with.
+    what: String,
+    why: String,
+    /// Bumped by every apply, so one that waited on logind can tell it was overtaken.
+    generation: u64,
+}
+
+impl Daemon {
+    pub fn new(config: Config, session: SessionInfo) -> Self {
+        let mut rules = Vec::new();
+        for rule in config.rule_engine.rules.clone() {
+            match Rule::new(rule) {
+                Ok(rule) => rules.push(rule),
+                Err(e) => warn!("Skipping rule: {:#}", e),
+            }
+        }
+
+        Self {
+            config: Arc::new(config),
+            session: Arc::new(session),
+            inner: Arc::new(Mutex::new(Inner {
+                state: State::Disabled,
+                holds: Holds::default(),
+                suppressions: BTreeMap::new(),
+                readings: BTreeMap::new(),
+                facts: BTreeSet::new(),
+                applied_rule: None,
+                inhibitor: None,
+                what: String::new(),
+                why: String::new(),
+                generation: 0,
+            })),
+            rules: Arc::new(rules),
+            changes: Arc::new(watch::channel(0).0),
+            repairs: Arc::new(AtomicU32::new(0)),
+            detached: false,
+        }
+    }
+
+    /// A daemon that only keeps track of holds, suppressions and the state they
+    /// imply, so watchers can be tested without a session.
+    #[cfg(test)]
+    pub(crate) fn detached(config: Config) -> Self {
+        let session = SessionInfo {
+            id: "test".to_string(),
+            path: zbus::zvariant::OwnedObjectPath::try_from("/test").unwrap(),
+        };
+        Self { detached: true, ..Self::new(config, session) }
+    }
+
+    /// The `what` of the inhibitor the daemon holds, empty when it holds none.
+    #[cfg(test)]
+    pub(crate) async fn inhibited_what(&self) -> String {
+        self.inner.lock().await.what.clone()
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn session(&self) -> &SessionInfo {
+        &self.session
+    }
+
+    pub async fn state(&self) -> State {
+        self.inner.lock().await.state.clone()
+    }
+
+    pub async fn holds(&self) -> Holds {
+        self.inner.lock().await.holds.clone()
+    }
+
+    pub async fn suppressions(&self) -> BTreeMap<String, String> {
+        self.inner.lock().await.suppressions.clone()
+    }
+
+    pub async fn facts(&self) -> BTreeSet<String> {
+        self.inner.lock().await.facts.clone()
+    }
+
+    /// Notifies the receiver whenever holds, suppressions, facts or the state may have changed.
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.changes.subscribe()
+    }
+
+    pub async fn readings(&self) -> BTreeMap<String, String> {
+        self.inner.lock().await.readings.clone()
+    }
+
+    /// Records a watcher's latest measurement; it is reported, never acted on.
+    pub async fn set_reading(&self, source: &str, value: String) {
+        self.inner.lock().await.readings.insert(source.to_string(), value);
+    }
+
+    /// Publishes a named fact for rules and scripts; rules are re-evaluated when it changes.
+    pub async fn set_fact(&self, name: &str, value: bool) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+        let changed = if value {
+            inner.facts.insert(name.to_string())
+        } else {
+            inner.facts.remove(name)
+        };
+        drop(inner);
+
+        if changed {
+            self.apply(false).await?;
+        }
+        Ok(())
+    }
+
+    /// Evaluates every rule against the current conditions without acting on them.
+    pub async fn explain_rules(&self) -> Vec<RuleInfo> {
+        let inner = self.inner.lock().await;
+        let condition = |name: &str| Self::condition(&inner, name);
+
+        self.rules
+            .iter()
+            .map(|rule| RuleInfo {
+                name: rule.config.name.clone(),
+                action: match rule.config.action {
+                    RuleAction::Hold => "hold".to_string(),
+                    RuleAction::Release => "release".to_string(),
+                },
+                priority: rule.config.priority,
+                matched: rule.expr.eval(&condition),
+                applied: inner.applied_rule.as_deref() == Some(rule.config.name.as_str()),
+                explanation: rule.expr.explain(&condition),
+            })
+            .collect()
+    }
+
+    pub fn repair_count(&self) -> u32 {
+        self.repairs.load(Ordering::Relaxed)
+    }
+
+    /// Re-applies the manual holds persisted by a previous run. Automatic holds
+    /// come back on their own once their watchers start.
+    pub async fn restore(&self) -> Result<()> {
+        let user_holds = state::load_user_holds().unwrap_or_else(|e| {
+            warn!("Ignoring persisted holds: {:#}", e);
+            Default::default()
+        });
+        info!("Restoring {} manual hold(s) (hold file: {:?})", user_holds.len(), state::user_holds_path());
+
+        if user_holds.is_empty() {
+            State::Disabled.save()?;
+            return Ok(());
+        }
+
+        let mut inner = self.inner.lock().await;
+        for (source, reason) in user_holds {
+            inner.holds.insert(source, reason);
+        }
+        drop(inner);
+
+        self.apply(true).await
+    }
+
+    /// Handles an `Enable`/`Disable`/`Toggle` control request, optionally with a client reason.
+    ///
+    /// `Enable` adds a manual hold. `Disable` releases the manual holds, and if
+    /// automatic holds would still keep the inhibitor on, overrides them with a
+    /// manual suppression that lifts once nothing holds the inhibitor any more
+    /// (or on the next `Enable`). `Toggle` is `Disable` while the inhibitor is on
+    /// or failing, and `Enable` otherwise.
+    pub async fn handle_signal(&self, signal_name: &str, reason: Option<String>) -> Result<()> {
+        info!("Received D-Bus signal: {} (reason: {:?})", signal_name, reason);
+
+        let mut inner = self.inner.lock().await;
+        let action = match signal_name {
+            "Toggle" if inner.state == State::Disabled => "Enable",
+            "Toggle" => "Disable",
+            other => other,
+        };
+
+        let changed = match action {
+            "Enable" => {
+                let lifted = inner.suppressions.remove(holds::USER).is_some();
+                self.add_user_hold(&mut inner.holds, reason) | lifted
+            }
+            "Disable" => {
+                let released = inner.holds.remove_kind(holds::USER);
+                let overridden = !self.effective_holds(&inner).is_empty()
+                    && inner
+                        .suppressions
+                        .insert(holds::USER.to_string(), MANUAL_DISABLE.to_string())
+                        .is_none();
+                if overridden {
+                    info!("Overriding automatic holds until they end");
+                }
+                released | overridden
+            }
+            _ => return Ok(()),
+        };
+
+        let user_holds = inner.holds.of_kind(holds::USER);
+        let suppressions: Vec<String> = inner
+            .suppressions
+            .keys()
+            .filter(|source| source.as_str() != holds::USER)
+            .cloned()
+            .collect();
+        drop(inner);
+
+        if changed && !self.detached {
+            state::save_user_holds(&user_holds)?;
+        }
+        if action == "Enable" && !suppressions.is_empty() {
+            info!("Inhibitor stays off while suppressed by {}", suppressions.join(", "));
+        }
+
+        self.apply(changed).await
+    }
+
+    /// Releases the manual holds without overriding automatic ones, e.g. on lock.
+    pub async fn release_user_holds(&self) -> Result<()> {
+        let changed = self.inner.lock().await.holds.remove_kind(holds::USER);
+        if changed && !self.detached {
+            state::save_user_holds(&BTreeMap::new())?;
+        }
+        self.apply(changed).await
+    }
+
+    /// Adds or updates an idle hold from an automatic source.
+    pub async fn set_hold(&self, source: &str, reason: &str) -> Result<()> {
+        self.set_hold_what(source, holds::DEFAULT_WHAT, reason).await
+    }
+
+    /// Adds or updates a hold for a specific logind `what`.
+    pub async fn set_hold_what(&self, source: &str, what: &str, reason: &str) -> Result<()> {
+        let changed = self.inner.lock().await.holds.insert_what(source, what, reason);
+        if changed {
+            info!("Hold {} active ({}): {}", source, what, reason);
+            self.apply(true).await?;
+        }
+        Ok(())
+    }
+
+    /// Adds a hold that is released automatically once `pid` exits.
+    pub async fn hold_for_pid(&self, pid: u32, source: String, what: &str, reason: &str) -> Result<()> {
+        let watch = PidWatch::open(pid)?;
+        self.set_hold_what(&source, what, reason).await?;
+
+        let daemon = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = watch.exited().await {
+                warn!("Lost track of PID {}, releasing its hold: {:#}", watch.pid(), e);
+            } else {
+                info!("PID {} exited", watch.pid());
+            }
+            if let Err(e) = daemon.release_hold(&source).await {
+                error!("Failed to release hold {}: {}", source, e);
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Releases a hold from an automatic source.
+    pub async fn release_hold(&self, source: &str) -> Result<()> {
+        let changed = self.inner.lock().await.holds.remove(source);
+        if changed {
+            info!("Hold {} released", source);
+            self.apply(true).await?;
+        }
+        Ok(())
+    }
+
+    /// Forces the inhibitor off while `source` says so; holds are kept and
+    /// resume once every suppression is cleared.
+    pub async fn set_suppression(&self, source: &str, reason: &str) -> Result<()> {
+        let previous = self
+            .inner
+            .lock()
+            .await
+            .suppressions
+            .insert(source.to_string(), reason.to_string());
+        if previous.as_deref() != Some(reason) {
+            info!("Suppression {} active: {}", source, reason);
+            self.apply(true).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn clear_suppression(&self, source: &str) -> Result<()> {
+        let removed = self.inner.lock().await.suppressions.remove(source);
+        if removed.is_some() {
+            info!("Suppression {} cleared", source);
+            self.apply(true).await?;
+        }
+        Ok(())
+    }
+
+    /// Drops every hold, suppression, fact and reading of `kind`, e.g. once the
+    /// watcher that published them has stopped.
+    pub async fn release_kind(&self, kind: &str) -> Result<()> {
+        let of_kind = |source: &str| holds::source_kind(source) == kind;
+
+        let mut inner = self.inner.lock().await;
+        let mut changed = inner.holds.remove_kind(kind);
+        let suppressions = inner.suppressions.len();
+        inner.suppressions.retain(|source, _| !of_kind(source));
+        changed |= inner.suppressions.len() != suppressions;
+        let facts = inner.facts.len();
+        inner.facts.retain(|fact| !of_kind(fact));
+        changed |= inner.facts.len() != facts;
+        inner.readings.retain(|source, _| !of_kind(source));
+        drop(inner);
+
+        if changed {
+            info!("Released everything from {}", kind);
+            self.apply(true).await?;
+        }
+        Ok(())
+    }
+
+    /// Runs a watcher for the daemon's lifetime. Whenever it stops, everything it
+    /// published under `kind` is released so no stale hold or suppression outlives
+    /// it, and it is restarted with exponential backoff. A watcher that finishes
+    /// without an error had nothing to watch and is not restarted.
+    pub fn spawn_watcher<F, Fut>(&self, name: &'static str, kind: &'static str, watcher: F) -> JoinHandle<()>
+    where
+        F: Fn(Daemon) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let daemon = self.clone();
+        tokio::spawn(async move {
+            let mut backoff = INITIAL_BACKOFF;
+            loop {
+                let started = Instant::now();
+                let result = watcher(daemon.clone()).await;
+
+                if let Err(e) = daemon.release_kind(kind).await {
+                    error!("Failed to release {} holds: {:#}", kind, e);
+                }
+                let Err(e) = result else {
+                    info!("{} finished", name);
+                    return;
+                };
+
+                if started.elapsed() >= STABLE_RUN {
+                    backoff = INITIAL_BACKOFF;
+                }
+                warn!("{} exited: {:#}; restarting in {}s", name, e, backoff.as_secs());
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        })
+    }
+
+    /// Retries acquisition if the last attempt failed.
+    pub async fn retry_failed(&self) -> Result<()> {
+        if !self.inner.lock().await.state.is_failed() {
+            return Ok(());
+        }
+
+        info!("Retrying inhibitor acquisition");
+        self.apply(false).await
+    }
+
+    /// Re-acquires the inhibitor if we believe it is held but logind no longer lists it,
+    /// e.g. after logind restarted or the fd was lost.
+    pub async fn reconcile(&self) -> Result<()> {
+        if self.inner.lock().await.inhibitor.is_none() {
+            return Ok(());
+        }
+
+        if dbus::inhibitor_registered().await? {
+            return Ok(());
+        }
+
+        let count = self.repairs.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("Inhibitor missing from logind, re-acquiring (repair #{})", count);
+
+        self.inner.lock().await.inhibitor = None;
+        self.apply(false).await
+    }
+
+    /// Releases everything on shutdown.
+    pub async fn shutdown(&self) {
+        self.inner.lock().await.inhibitor = None;
+    }
+
+    fn add_user_hold(&self, holds: &mut Holds, reason: Option<String>) -> bool {
+        match reason.filter(|r| !r.trim().is_empty()) {
+            Some(reason) => holds.insert(format!("{}:{}", holds::USER, reason), reason),
+            None => holds.insert(holds::USER, self.config.default_reason.clone()),
+        }
+    }
+
+    /// Holds that can turn the inhibitor on; passive kinds only feed rule conditions.
+    fn effective_holds(&self, inner: &Inner) -> Holds {
+        inner.holds.without_kinds(&self.config.rule_engine.passive)
+    }
+
+    /// A condition is true when a hold, suppression or fact is named after it or
+    /// nested under it. Rule actions themselves never count, so rules can't feed back.
+    fn condition(inner: &Inner, name: &str) -> bool {
+        let counts = |source: &str| holds::source_kind(source) != rules::HOLD_KIND && holds::source_matches(source, name);
+
+        inner.holds.iter().any(|(source, _)| counts(source))
+            || inner.suppressions.keys().any(|source| counts(source))
+            || inner.facts.iter().any(|fact| holds::source_matches(fact, name))
+    }
+
+    /// Replaces the previous rule action with the one of the current winning rule.
+    fn evaluate_rules(&self, inner: &mut Inner) {
+        if self.rules.is_empty() {
+            return;
+        }
+
+        inner.holds.remove_kind(rules::HOLD_KIND);
+        inner.suppressions.retain(|source, _| holds::source_kind(source) != rules::HOLD_KIND);
+
+        let winner = rules::winner(&self.rules, &|name| Self::condition(inner, name));
+        let name = winner.map(|rule| rule.config.name.clone());
+        if name != inner.applied_rule {
+            match &name {
+                Some(name) => info!("Rule {} now decides the inhibitor", name),
+                None => info!("No rule matches any more"),
+            }
+            inner.applied_rule = name;
+        }
+
+        if let Some(rule) = winner {
+            match rule.config.action {
+                RuleAction::Hold => {
+                    inner.holds.insert(rule.source(), rule.reason());
+                }
+                RuleAction::Release => {
+                    inner.suppressions.insert(rule.source(), rule.reason());
+                }
+            }
+        }
+    }
+
+    fn render(&self, template: &str, holds: &Holds) -> String {
+        render_template(template, &self.session.id, holds)
+    }
+
+    /// Brings the logind inhibitor in line with the current holds, then persists and
+    /// broadcasts the result. Signals are emitted when the state changed or `notify` is set.
+    async fn apply(&self, notify: bool) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+        let previous = inner.state.clone();
+        inner.generation += 1;
+        let generation = inner.generation;
+
+        self.evaluate_rules(&mut inner);
+
+        let passive = &self.config.rule_engine.passive;
+        let effective = self.effective_holds(&inner);
+        // A manual disable overrides only until nothing holds the inhibitor any more
+        if effective.is_empty() && inner.suppressions.remove(holds::USER).is_some() {
+            info!("Manual disable lifted, no holds remain");
+        }
+        let suppressed = inner
+            .suppressions
+            .keys()
+            .any(|source| !passive.iter().any(|kind| kind == holds::source_kind(source)));
+
+        let new_state = if effective.is_empty() || suppressed {
+            inner.inhibitor = None;
+            inner.what.clear();
+            inner.why.clear();
+            State::Disabled
+        } else {
+            let what = effective.what();
+            let why = self.render(&self.config.why_template, &effective);
+            if inner.inhibitor.is_some() && inner.what == what && inner.why == why {
+                State::Enabled
+            } else if self.detached {
+                inner.what = what;
+                inner.why = why;
+                State::Enabled
+            } else {
+                let who = self.render(&self.config.who_template, &effective);
+
+                // Logind may be slow or restarting, so nothing else waits on the lock
+                // meanwhile. The new inhibitor is taken before the old one drops, so
+                // there is no gap.
+                drop(inner);
+                let acquired = InhibitorLock::acquire(&what, &who, &why).await;
+                inner = self.inner.lock().await;
+
+                if inner.generation != generation {
+                    // A later apply decided in the meantime and its outcome stands
+                    return Ok(());
+                }
+
+                match acquired {
+                    Ok(lock) => {
+                        inner.inhibitor = Some(lock);
+                        inner.what = what;
+                        inner.why = why;
+                        State::Enabled
+                    }
+                    Err(e) if inner.inhibitor.is_some() => {
+                        warn!("Failed to refresh inhibitor, keeping previous one: {:#}", e);
+                        State::Enabled
+                    }
+                    Err(e) => {
+                        error!("Failed to acquire inhibitor lock: {:#}", e);
+                        State::Failed(format!("{:#}", e))
+                    }
+                }
+            }
+        };
+
+        // The state reports idle inhibition. Holds that only block e.g. the lid
+        // switch keep their inhibitor, but don't count as enabled.
+        let new_state = if new_state.is_enabled() && !holds::blocks_idle(&inner.what) {
+            State::Disabled
+        } else {
+            new_state
+        };
+
+        inner.state = new_state.clone();
+        if !self.detached {
+            inner.state.save()?;
+        }
+        drop(inner);
+        self.changes.send_modify(|generation| *generation += 1);
+
+        if self.detached || (new_state == previous && !notify) {
+            return Ok(());
+        }
+
+        info!("State changed to: {}", new_state);
+
+        if let Some(reason) = new_state.failure_reason() {
+            if let Err(e) = dbus::emit_state_failed(&self.session, reason).await {
+                error!("Failed to emit StateFailed signal: {}", e);
+            }
+        }
+
+        if let Err(e) = dbus::emit_state_changed(&self.session, new_state.is_enabled()).await {
+            error!("Failed to emit StateChanged signal: {}", e);
+        }
+
+        Ok(())
+    }
+}
+
+/// Fills a who/why template's `{session}`, `{count}` and `{reasons}` placeholders.
+pub fn render_template(template: &str, session: &str, holds: &Holds) -> String {
+    template
+        .replace("{session}", session)
+        .replace("{count}", &holds.len().to_string())
+        .replace("{reasons}", &holds.reasons().join("; "))
+}
@@ -1,14 +1,22 @@
 use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
 use zbus::{proxy, Connection};
-use zbus::zvariant::{OwnedFd, OwnedObjectPath};
+use zbus::zvariant::{ObjectPath, OwnedFd, OwnedObjectPath, Type};
 use crate::session::SessionInfo;
+use crate::waybar::MonitorFormat;
+
+/// One entry of logind's `ListInhibitors`: what, who, why, mode, uid, pid.
+type InhibitorEntry = (String, String, String, String, u32, u32);
+
+/// One entry of logind's `ListSessions`: id, uid, user name, seat, object path.
+pub(crate) type SessionEntry = (String, u32, String, String, OwnedObjectPath);
 
 #[proxy(
     interface = "org.freedesktop.login1.Manager",
     default_service = "org.freedesktop.login1",
     default_path = "/org/freedesktop/login1"
 )]
-trait Login1Manager {
+pub(crate) trait Login1Manager {
     fn inhibit(
         &self,
         what: &str,
@@ -17,7 +25,77 @@ trait Login1Manager {
         mode: &str,
     ) -> zbus::Result<OwnedFd>;
     
+    fn list_inhibitors(&self) -> zbus::Result<Vec<InhibitorEntry>>;
+    
+    #[zbus(property)]
+    fn docked(&self) -> zbus::Result<bool>;
+    
     fn get_session_by_pid(&self, pid: u32) -> zbus::Result<(String, OwnedObjectPath)>;
+    
+    fn get_session(&self, session_id: &str) -> zbus::Result<OwnedObjectPath>;
+    
+    fn list_sessions(&self) -> zbus::Result<Vec<SessionEntry>>;
+    
+    #[zbus(signal)]
+    fn session_new(&self, session_id: &str, object_path: ObjectPath<'_>) -> zbus::Result<()>;
+    
+    #[zbus(signal)]
+    fn session_removed(&self, session_id: &str, object_path: ObjectPath<'_>) -> zbus::Result<()>;
+}
+
+#[proxy(interface = "com.logind.IdleControl")]
+trait IdleControl {
+    fn list_system_inhibitors(&self) -> zbus::Result<Vec<SystemInhibitor>>;
+    
+    fn add_hold(&self, what: &str, reason: &str) -> zbus::Result<String>;
+    
+    fn release_hold(&self, id: &str) -> zbus::Result<()>;
+    
+    fn attach_pid(&self, pid: u32, reason: &str) -> zbus::Result<String>;
+    
+    fn list_holds(&self) -> zbus::Result<Vec<HoldInfo>>;
+    
+    fn list_suppressions(&self) -> zbus::Result<Vec<(String, String)>>;
+    
+    fn list_readings(&self) -> zbus::Result<Vec<(String, String)>>;
+    
+    fn explain_rules(&self) -> zbus::Result<Vec<RuleInfo>>;
+    
+    #[zbus(property)]
+    fn repair_count(&self) -> zbus::Result<u32>;
+}
+
+/// An inhibitor as reported by logind, flagged when it was taken by this daemon.
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct SystemInhibitor {
+    pub what: String,
+    pub who: String,
+    pub why: String,
+    pub mode: String,
+    pub uid: u32,
+    pub pid: u32,
+    pub ours: bool,
+}
+
+/// A hold as reported by the daemon's `ListHolds`.
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct HoldInfo {
+    pub source: String,
+    pub what: String,
+    pub reason: String,
+}
+
+/// A rule's current evaluation, as reported by `ExplainRules`.
+#[derive(Debug, Clone, Serialize, Deserialize, Type)]
+pub struct RuleInfo {
+    pub name: String,
+    pub action: String,
+    pub priority: i32,
+    pub matched: bool,
+    /// Whether this rule is the one currently deciding the outcome
+    pub applied: bool,
+    /// The expression with every condition's current value
+    pub explanation: String,
 }
 
 pub struct InhibitorLock {
@@ -25,7 +103,7 @@ pub struct InhibitorLock {
 }
 
 impl InhibitorLock {
-    pub async fn acquire() -> Result<Self> {
+    pub async fn acquire(what: &str, who: &str, why: &str) -> Result<Self> {
         let connection = Connection::system()
             .await
             .context("Failed to connect to system D-Bus")?;
@@ -36,15 +114,15 @@ impl InhibitorLock {
         
         let fd = proxy
             .inhibit(
-                "idle",
-                "logind-idle-control",
-                "User requested idle inhibition",
+                what,
+                who,
+                why,
                 "block",
             )
             .await
             .context("Failed to acquire inhibitor lock from logind")?;
         
-        tracing::info!("Acquired idle inhibitor lock");
+        tracing::info!("Acquired {} inhibitor lock ({}: {})", what, who, why);
         
         Ok(Self { _fd: fd })
     }
@@ -52,18 +130,228 @@ impl InhibitorLock {
 
 impl Drop for InhibitorLock {
     fn drop(&mut self) {
-        tracing::info!("Released idle inhibitor lock");
+        tracing::info!("Released inhibitor lock");
     }
 }
 
+/// Lists every inhibitor known to logind, marking the ones held by this process.
+pub async fn list_system_inhibitors() -> Result<Vec<SystemInhibitor>> {
+    let connection = Connection::system()
+        .await
+        .context("Failed to connect to system D-Bus")?;
+    
+    let proxy = Login1ManagerProxy::new(&connection)
+        .await
+        .context("Failed to create logind proxy")?;
+    
+    let inhibitors = proxy
+        .list_inhibitors()
+        .await
+        .context("Failed to list logind inhibitors")?;
+    
+    let own_pid = std::process::id();
+    Ok(inhibitors
+        .into_iter()
+        .map(|(what, who, why, mode, uid, pid)| SystemInhibitor {
+            ours: pid == own_pid,
+            what,
+            who,
+            why,
+            mode,
+            uid,
+            pid,
+        })
+        .collect())
+}
+
+/// Checks whether logind still lists an inhibitor taken by this process.
+pub async fn inhibitor_registered() -> Result<bool> {
+    Ok(list_system_inhibitors().await?.iter().any(|i| i.ours))
+}
+
+/// Whether logind considers the machine docked (dock or external display attached).
+pub async fn is_docked() -> Result<bool> {
+    let connection = Connection::system()
+        .await
+        .context("Failed to connect to system D-Bus")?;
+    
+    let proxy = Login1ManagerProxy::builder(&connection)
+        .cache_properties(zbus::proxy::CacheProperties::No)
+        .build()
+        .await
+        .context("Failed to create logind proxy")?;
+    
+    proxy.docked().await.context("Failed to read logind Docked property")
+}
+
+/// Asks the running daemon for logind's inhibitors so its own entries are marked.
+pub async fn query_system_inhibitors() -> Result<Vec<SystemInhibitor>> {
+    let proxy = daemon_proxy().await?;
+    
+    proxy
+        .list_system_inhibitors()
+        .await
+        .context("Failed to query daemon for system inhibitors")
+}
+
+/// Asks the running daemon for its active holds.
+pub async fn query_holds() -> Result<Vec<HoldInfo>> {
+    let proxy = daemon_proxy().await?;
+    
+    proxy
+        .list_holds()
+        .await
+        .context("Failed to query daemon for holds")
+}
+
+/// Asks the running daemon which policies are forcing the inhibitor off, as (source, reason).
+pub async fn query_suppressions() -> Result<Vec<(String, String)>> {
+    let proxy = daemon_proxy().await?;
+    
+    proxy
+        .list_suppressions()
+        .await
+        .context("Failed to query daemon for suppressions")
+}
+
+/// Asks the running daemon for the latest measurements of its watchers, as (source, value).
+pub async fn query_readings() -> Result<Vec<(String, String)>> {
+    let proxy = daemon_proxy().await?;
+    
+    proxy
+        .list_readings()
+        .await
+        .context("Failed to query daemon for readings")
+}
+
+/// Asks the running daemon to evaluate its rules and explain the result.
+pub async fn query_rules() -> Result<Vec<RuleInfo>> {
+    let proxy = daemon_proxy().await?;
+    
+    proxy
+        .explain_rules()
+        .await
+        .context("Failed to query daemon for rules")
+}
+
+/// Asks the running daemon to hold the inhibitor until `pid` exits. Returns the hold id.
+pub async fn attach_pid(pid: u32, reason: &str) -> Result<String> {
+    let proxy = daemon_proxy().await?;
+    
+    proxy
+        .attach_pid(pid, reason)
+        .await
+        .with_context(|| format!("Failed to attach to PID {}", pid))
+}
+
+/// A hold registered with the running daemon on behalf of this process.
+///
+/// The daemon also drops it on its own when this process exits.
+pub struct DaemonHold {
+    proxy: IdleControlProxy<'static>,
+    id: String,
+}
+
+impl DaemonHold {
+    pub async fn register(what: &str, reason: &str) -> Result<Self> {
+        let proxy = daemon_proxy().await?;
+        
+        let id = proxy
+            .add_hold(what, reason)
+            .await
+            .context("Failed to register hold with daemon")?;
+        
+        Ok(Self { proxy, id })
+    }
+    
+    pub async fn release(self) -> Result<()> {
+        self.proxy
+            .release_hold(&self.id)
+            .await
+            .context("Failed to release hold")
+    }
+}
+
+async fn daemon_proxy() -> Result<IdleControlProxy<'static>> {
+    let session = crate::session::get_current_session().await?;
+    
+    let connection = Connection::session()
+        .await
+        .context("Failed to connect to session D-Bus")?;
+    
+    IdleControlProxy::builder(&connection)
+        .destination(get_bus_name_for_session(&session))?
+        .path(get_object_path_for_session(&session))?
+        .build()
+        .await
+        .context("Failed to create daemon proxy")
+}
+
 fn get_object_path_for_session(session: &SessionInfo) -> String {
     format!("/com/logind/IdleControl/session_{}", session.id.replace('-', "_"))
 }
 
-pub async fn emit_signal(signal_name: &str) -> Result<()> {
+fn get_bus_name_for_session(session: &SessionInfo) -> String {
+    format!("com.logind.IdleControl.session_{}", session.id.replace('-', "_"))
+}
+
+/// Claims the per-session bus name and exports the daemon object at the session path.
+pub async fn serve<I>(session: &SessionInfo, iface: I) -> Result<Connection>
+where
+    I: zbus::object_server::Interface,
+{
+    let object_path = get_object_path_for_session(session);
+    let bus_name = get_bus_name_for_session(session);
+    
+    let connection = zbus::connection::Builder::session()
+        .context("Failed to connect to session D-Bus")?
+        .name(bus_name.as_str())?
+        .serve_at(object_path.as_str(), iface)?
+        .build()
+        .await
+        .with_context(|| format!("Failed to export {} as {}", object_path, bus_name))?;
+    
+    tracing::info!("Serving {} on {}", object_path, bus_name);
+    
+    Ok(connection)
+}
+
+pub async fn emit_signal(signal_name: &str, reason: Option<&str>) -> Result<()> {
     let session = crate::session::get_current_session().await?;
     let object_path = get_object_path_for_session(&session);
     
+    let connection = Connection::session()
+        .await
+        .context("Failed to connect to session D-Bus")?;
+    
+    let sent = match reason {
+        Some(reason) => connection
+            .emit_signal(
+                None::<()>,
+                object_path.as_str(),
+                "com.logind.IdleControl",
+                signal_name,
+                &(reason,),
+            )
+            .await,
+        None => connection
+            .emit_signal(
+                None::<()>,
+                object_path.as_str(),
+                "com.logind.IdleControl",
+                signal_name,
+                &(),
+            )
+            .await,
+    };
+    sent.context("Failed to emit D-Bus signal")?;
+    
+    Ok(())
+}
+
+pub async fn emit_state_changed(session: &SessionInfo, enabled: bool) -> Result<()> {
+    let object_path = get_object_path_for_session(session);
+    
     let connection = Connection::session()
         .await
         .context("Failed to connect to session D-Bus")?;
@@ -73,16 +361,18 @@ pub async fn emit_signal(signal_name: &str) -> Result<()> {
             None::<()>,
             object_path.as_str(),
             "com.logind.IdleControl",
-            signal_name,
-            &(),
+            "StateChanged",
+            &(enabled,),
         )
         .await
-        .context("Failed to emit D-Bus signal")?;
+        .context("Failed to emit StateChanged signal")?;
+    
+    tracing::debug!("Emitted StateChanged({}) on {}", enabled, object_path);
     
     Ok(())
 }
 
-pub async fn emit_state_changed(session: &SessionInfo, enabled: bool) -> Result<()> {
+pub async fn emit_state_failed(session: &SessionInfo, reason: &str) -> Result<()> {
     let object_path = get_object_path_for_session(session);
     
     let connection = Connection::session()
@@ -94,13 +384,13 @@ pub async fn emit_state_changed(session: &SessionInfo, enabled: bool) -> Result<
             None::<()>,
             object_path.as_str(),
             "com.logind.IdleControl",
-            "StateChanged",
-            &(enabled,),
+            "StateFailed",
+            &(reason,),
         )
         .await
-        .context("Failed to emit StateChanged signal")?;
+        .context("Failed to emit StateFailed signal")?;
     
-    tracing::debug!("Emitted StateChanged({}) on {}", enabled, object_path);
+    tracing::debug!("Emitted StateFailed({}) on {}", reason, object_path);
     
     Ok(())
 }
@@ -151,14 +441,17 @@ where
 }
 
 
-pub async fn monitor_state_changes() -> Result<()> {
-    use std::io::Write;
+/// How often Waybar output is re-rendered without a state change.
+const WAYBAR_REFRESH: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Prints the current state, then every change, in `format`.
+pub async fn monitor_state_changes(format: MonitorFormat) -> Result<()> {
     
     let session = crate::session::get_current_session().await?;
     
-    let state = crate::State::load()?;
-    println!("{}", state);
-    std::io::stdout().flush()?;
+    let waybar = (format == MonitorFormat::Waybar).then(crate::waybar::Renderer::load);
+    let mut printer = StatePrinter { waybar, last: None };
+    printer.print(&crate::State::load()?).await?;
     
     let (tx_state, mut rx_state) = tokio::sync::mpsc::channel::<bool>(10);
     let (tx_event, mut rx_event) = tokio::sync::mpsc::channel::<()>(10);
@@ -187,20 +480,25 @@ pub async fn monitor_state_changes() -> Result<()> {
         }
     });
     
+    let mut refresh = tokio::time::interval(WAYBAR_REFRESH);
+    refresh.tick().await;
+    
     loop {
         tokio::select! {
             Some(enabled) = rx_state.recv() => {
                 if enabled {
-                    println!("1");
+                    printer.print(&crate::State::Enabled).await?;
                 } else {
-                    println!("0");
+                    // Disabled or Failed; the daemon saves the state before signalling
+                    printer.print(&crate::State::load()?).await?;
                 }
-                std::io::stdout().flush()?;
             }
             Some(()) = rx_event.recv() => {
-                let state = crate::State::load()?;
-                println!("{}", state);
-                std::io::stdout().flush()?;
+                printer.print(&crate::State::load()?).await?;
+            }
+            // Keeps the tooltip's schedule countdown current
+            _ = refresh.tick(), if format == MonitorFormat::Waybar => {
+                printer.print(&crate::State::load()?).await?;
             }
             else => break,
         }
@@ -209,6 +507,35 @@ pub async fn monitor_state_changes() -> Result<()> {
     Ok(())
 }
 
+/// Writes monitor output, skipping Waybar updates that would repeat the last line.
+struct StatePrinter {
+    /// Set for `--format waybar`
+    waybar: Option<crate::waybar::Renderer>,
+    last: Option<String>,
+}
+
+impl StatePrinter {
+    async fn print(&mut self, state: &crate::State) -> Result<()> {
+        use std::io::Write;
+        
+        let line = match &self.waybar {
+            None => state.to_string(),
+            Some(renderer) => {
+                let line = renderer.render(state).await;
+                if self.last.as_deref() == Some(line.as_str()) {
+                    return Ok(());
+                }
+                self.last = Some(line.clone());
+                line
+            }
+        };
+        
+        println!("{}", line);
+        std::io::stdout().flush()?;
+        Ok(())
+    }
+}
+
 async fn monitor_state_changed_signals(
     _session: &SessionInfo,
     object_path: &str,
@@ -343,7 +670,7 @@ async fn monitor_unlock_signal(
 
 pub async fn listen_signals<F>(session: &SessionInfo, mut callback: F) -> Result<()>
 where
-    F: FnMut(&str) + Send + 'static,
+    F: FnMut(&str, Option<String>) + Send + 'static,
 {
     use futures_util::StreamExt;
     use zbus::MatchRule;
@@ -376,7 +703,8 @@ where
                             if let Some(member) = msg.header().member() {
                                 let member_str = member.as_str();
                                 if member_str == "Enable" || member_str == "Disable" || member_str == "Toggle" {
-                                    callback(member_str);
+                                    let reason = msg.body().deserialize::<String>().ok();
+                                    callback(member_str, reason);
                                 }
                             }
                         }
@@ -433,3 +761,47 @@ where
     
     Ok(())
 }
+
+pub async fn listen_logind_restarts<F>(mut callback: F) -> Result<()>
+where
+    F: FnMut() + Send + 'static,
+{
+    use futures_util::StreamExt;
+    use zbus::MatchRule;
+    
+    let connection = Connection::system()
+        .await
+        .context("Failed to connect to system D-Bus")?;
+    
+    let match_rule = MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .sender("org.freedesktop.DBus")?
+        .interface("org.freedesktop.DBus")?
+        .member("NameOwnerChanged")?
+        .add_arg("org.freedesktop.login1")?
+        .build();
+    
+    let proxy = zbus::fdo::DBusProxy::new(&connection).await?;
+    proxy.add_match_rule(match_rule).await?;
+    
+    let mut stream = zbus::MessageStream::from(&connection);
+    
+    tracing::info!("Watching org.freedesktop.login1 for owner changes");
+    
+    while let Some(msg) = stream.next().await {
+        if let Ok(msg) = msg {
+            if let Some(member) = msg.header().member() {
+                if member.as_str() == "NameOwnerChanged" {
+                    if let Ok((name, _old, new)) = msg.body().deserialize::<(String, String, String)>() {
+                        if name == "org.freedesktop.login1" && !new.is_empty() {
+                            tracing::info!("logind owner changed to {}", new);
+                            callback();
+                        }
+                    }
+                }
+            }
+        }
+    }
+    
+    Ok(())
+}
@@ -0,0 +1,271 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::config::DevicesConfig;
+use crate::daemon::Daemon;
+use crate::process::ProcessScanner;
+
+/// Hold kind for capture devices; sources are `device:<process name>`.
+pub const HOLD_KIND: &str = "device";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeviceKind {
+    Camera,
+    Microphone,
+}
+
+impl DeviceKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            DeviceKind::Camera => "camera",
+            DeviceKind::Microphone => "microphone",
+        }
+    }
+}
+
+/// A capture device held open by a process.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceUse {
+    pub pid: u32,
+    pub comm: String,
+    pub kind: DeviceKind,
+    /// Device node, e.g. /dev/video0
+    pub node: String,
+    /// Human-readable device name from sysfs, if known
+    pub name: Option<String>,
+}
+
+/// Finds open capture devices by reading `/proc/<pid>/fd` links.
+pub struct DeviceScanner {
+    processes: ProcessScanner,
+    proc_root: PathBuf,
+    sysfs_root: PathBuf,
+    microphone: bool,
+}
+
+impl DeviceScanner {
+    pub fn new(proc_root: impl Into<PathBuf>, sysfs_root: impl Into<PathBuf>, uid: u32, microphone: bool) -> Self {
+        let proc_root = proc_root.into();
+        Self {
+            processes: ProcessScanner::new(proc_root.clone(), uid),
+            proc_root,
+            sysfs_root: sysfs_root.into(),
+            microphone,
+        }
+    }
+
+    /// Scanner for the real /proc and /sys, limited to our own user's processes.
+    pub fn for_current_user(microphone: bool) -> Self {
+        Self::new("/proc", "/sys", nix::unistd::getuid().as_raw(), microphone)
+    }
+
+    /// Which kind of capture device a device node is, if any. ALSA capture PCMs
+    /// are named `pcmC<card>D<device>c`; playback ones end in `p`.
+    pub fn classify(&self, node: &str) -> Option<DeviceKind> {
+        let name = node.strip_prefix("/dev/")?;
+        if name.strip_prefix("video").is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())) {
+            return Some(DeviceKind::Camera);
+        }
+        if self.microphone && name.strip_prefix("snd/pcmC").is_some_and(|pcm| pcm.ends_with('c')) {
+            return Some(DeviceKind::Microphone);
+        }
+        None
+    }
+
+    /// The device's name as the kernel reports it, e.g. "Integrated Camera".
+    fn device_name(&self, kind: DeviceKind, node: &str) -> Option<String> {
+        let file = match kind {
+            DeviceKind::Camera => {
+                let dev = node.strip_prefix("/dev/")?;
+                self.sysfs_root.join("class/video4linux").join(dev).join("name")
+            }
+            DeviceKind::Microphone => {
+                let card = node.strip_prefix("/dev/snd/pcmC")?.split('D').next()?;
+                self.sysfs_root.join(format!("class/sound/card{}", card)).join("id")
+            }
+        };
+
+        std::fs::read_to_string(file)
+            .ok()
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty())
+    }
+
+    pub fn scan(&self) -> Result<Vec<DeviceUse>> {
+        let mut uses = BTreeSet::new();
+
+        for process in self.processes.scan()? {
+            let fd_dir = self.proc_root.join(process.pid.to_string()).join("fd");
+            // Processes can exit mid-scan; skip anything we can't read
+            let Ok(entries) = std::fs::read_dir(&fd_dir) else {
+                continue;
+            };
+
+            for entry in entries.flatten() {
+                let Some(node) = read_target(&entry.path()) else {
+                    continue;
+                };
+                if let Some(kind) = self.classify(&node) {
+                    uses.insert(DeviceUse {
+                        pid: process.pid,
+                        comm: process.comm.clone(),
+                        kind,
+                        name: self.device_name(kind, &node),
+                        node,
+                    });
+                }
+            }
+        }
+
+        Ok(uses.into_iter().collect())
+    }
+}
+
+fn read_target(link: &Path) -> Option<String> {
+    std::fs::read_link(link)
+        .ok()
+        .and_then(|target| target.to_str().map(str::to_string))
+}
+
+/// Polls open file descriptors and keeps one hold per process using a capture device.
+pub async fn watch(daemon: Daemon, config: DevicesConfig) -> Result<()> {
+    let scanner = DeviceScanner::for_current_user(config.microphone);
+
+    tracing::info!("Watching capture devices (microphone: {})", config.microphone);
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.scan_interval_secs.max(1)));
+    let mut active = BTreeSet::new();
+
+    loop {
+        interval.tick().await;
+
+        let mut by_process: BTreeMap<String, Vec<DeviceUse>> = BTreeMap::new();
+        for device in scanner.scan()? {
+            by_process.entry(device.comm.clone()).or_default().push(device);
+        }
+
+        let ended: Vec<String> = active.iter().filter(|s| !by_process.contains_key(*s)).cloned().collect();
+        for comm in ended {
+            tracing::info!("{} closed its capture devices", comm);
+            daemon.release_hold(&format!("{}:{}", HOLD_KIND, comm)).await?;
+            active.remove(&comm);
+        }
+
+        for (comm, devices) in &by_process {
+            let mut labels: Vec<String> = devices
+                .iter()
+                .map(|d| match &d.name {
+                    Some(name) => format!("{} ({})", d.kind.label(), name),
+                    None => d.kind.label().to_string(),
+                })
+                .collect();
+            labels.sort();
+            labels.dedup();
+
+            if active.insert(comm.clone()) {
+                let nodes: Vec<&str> = devices.iter().map(|d| d.node.as_str()).collect();
+                tracing::info!("{} (PID {}) opened {}", comm, devices[0].pid, nodes.join(", "));
+            }
+            let reason = format!("{} is using the {}", comm, labels.join(" and "));
+            daemon.set_hold(&format!("{}:{}", HOLD_KIND, comm), &reason).await?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, MetadataExt};
+
+    /// Adds a process to a fake procfs with symlinks standing in for its open fds.
+    fn add_process(proc_root: &Path, pid: u32, comm: &str, nodes: &[&str]) {
+        let fd_dir = proc_root.join(pid.to_string()).join("fd");
+        std::fs::create_dir_all(&fd_dir).unwrap();
+        std::fs::write(proc_root.join(pid.to_string()).join("comm"), format!("{}\n", comm)).unwrap();
+        for (fd, node) in nodes.iter().enumerate() {
+            symlink(node, fd_dir.join(fd.to_string())).unwrap();
+        }
+    }
+
+    fn write(path: PathBuf, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    fn owner(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().uid()
+    }
+
+    #[test]
+    fn classifies_nodes() {
+        let scanner = DeviceScanner::new("/nonexistent", "/nonexistent", 0, true);
+        assert_eq!(scanner.classify("/dev/video0"), Some(DeviceKind::Camera));
+        assert_eq!(scanner.classify("/dev/video12"), Some(DeviceKind::Camera));
+        assert_eq!(scanner.classify("/dev/snd/pcmC0D0c"), Some(DeviceKind::Microphone));
+        for node in ["/dev/video", "/dev/videox", "/dev/snd/pcmC0D0p", "/dev/snd/controlC0", "/dev/null", "video0"] {
+            assert_eq!(scanner.classify(node), None, "{}", node);
+        }
+
+        let cameras_only = DeviceScanner::new("/nonexistent", "/nonexistent", 0, false);
+        assert_eq!(cameras_only.classify("/dev/snd/pcmC0D0c"), None);
+        assert_eq!(cameras_only.classify("/dev/video0"), Some(DeviceKind::Camera));
+    }
+
+    #[test]
+    fn scans_open_devices() {
+        let dir = tempfile::tempdir().unwrap();
+        let proc_root = dir.path().join("proc");
+        let sysfs_root = dir.path().join("sys");
+        add_process(&proc_root, 100, "firefox", &["/dev/video0", "/dev/snd/pcmC1D0c", "/dev/null"]);
+        add_process(&proc_root, 200, "mpv", &["/dev/snd/pcmC0D0p", "/home/user/video.mkv"]);
+        add_process(&proc_root, 300, "arecord", &["/dev/snd/pcmC0D0c"]);
+        std::fs::create_dir_all(proc_root.join("self")).unwrap();
+        write(sysfs_root.join("class/video4linux/video0/name"), "Integrated Camera\n");
+        write(sysfs_root.join("class/sound/card1/id"), "USB\n");
+
+        let scanner = DeviceScanner::new(&proc_root, &sysfs_root, owner(dir.path()), true);
+        let uses = scanner.scan().unwrap();
+        assert_eq!(
+            uses,
+            vec![
+                DeviceUse {
+                    pid: 100,
+                    comm: "firefox".into(),
+                    kind: DeviceKind::Camera,
+                    node: "/dev/video0".into(),
+                    name: Some("Integrated Camera".into()),
+                },
+                DeviceUse {
+                    pid: 100,
+                    comm: "firefox".into(),
+                    kind: DeviceKind::Microphone,
+                    node: "/dev/snd/pcmC1D0c".into(),
+                    name: Some("USB".into()),
+                },
+                DeviceUse {
+                    pid: 300,
+                    comm: "arecord".into(),
+                    kind: DeviceKind::Microphone,
+                    node: "/dev/snd/pcmC0D0c".into(),
+                    name: None,
+                },
+            ]
+        );
+
+        let cameras_only = DeviceScanner::new(&proc_root, &sysfs_root, owner(dir.path()), false);
+        let kinds: Vec<_> = cameras_only.scan().unwrap().into_iter().map(|d| (d.pid, d.kind)).collect();
+        assert_eq!(kinds, vec![(100, DeviceKind::Camera)]);
+    }
+
+    #[test]
+    fn skips_other_users() {
+        let dir = tempfile::tempdir().unwrap();
+        let proc_root = dir.path().join("proc");
+        add_process(&proc_root, 100, "firefox", &["/dev/video0"]);
+
+        let other_uid = owner(dir.path()).wrapping_add(1);
+        let scanner = DeviceScanner::new(&proc_root, dir.path().join("sys"), other_uid, true);
+        assert!(scanner.scan().unwrap().is_empty());
+    }
+}
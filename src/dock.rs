@@ -0,0 +1,218 @@
+use anyhow::{Context, Result};
+use futures_util::FutureExt;
+use std::path::Path;
+use std::time::Duration;
+use crate::config::DockConfig;
+use crate::daemon::Daemon;
+use crate::holds;
+use crate::power::{self, SYSFS_POWER_SUPPLY};
+use crate::uevent::{parse_uevent, UeventSocket};
+
+/// Hold source for docked mode.
+pub const HOLD_SOURCE: &str = "dock";
+
+pub const SYSFS_DRM: &str = "/sys/class/drm";
+
+/// Connector types of built-in panels, which never count as a dock.
+const INTERNAL_CONNECTORS: &[&str] = &["eDP", "LVDS", "DSI"];
+
+/// Connector status is updated shortly after the hotplug uevent.
+const SETTLE: Duration = Duration::from_millis(500);
+
+/// Connected external outputs under a `drm` class directory, e.g. `DP-1`.
+pub fn external_outputs(root: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(root) else {
+        return Vec::new();
+    };
+
+    let mut outputs: Vec<String> = entries
+        .flatten()
+        .filter_map(|entry| {
+            // Connectors are `card<N>-<type>-<index>`, e.g. card1-HDMI-A-1
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let (card, connector) = name.split_once('-')?;
+            if !card.starts_with("card") {
+                return None;
+            }
+            let (kind, _) = connector.rsplit_once('-')?;
+            if INTERNAL_CONNECTORS.contains(&kind) {
+                return None;
+            }
+
+            let status = std::fs::read_to_string(entry.path().join("status")).ok()?;
+            (status.trim() == "connected").then(|| connector.to_string())
+        })
+        .collect();
+
+    outputs.sort();
+    outputs.dedup();
+    outputs
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DockStatus {
+    pub outputs: Vec<String>,
+    pub on_ac: bool,
+}
+
+impl DockStatus {
+    /// Reads outputs and AC state from sysfs. Machines without a power supply
+    /// entry (desktops) count as being on AC.
+    pub fn read(drm_root: &Path, power_root: &Path) -> Self {
+        let on_ac = power::read_sysfs(power_root).map_or(true, |(on_battery, _)| !on_battery);
+        Self { outputs: external_outputs(drm_root), on_ac }
+    }
+
+    pub fn is_docked(&self, config: &DockConfig) -> bool {
+        self.outputs.len() >= config.min_external_outputs.max(1) && (self.on_ac || !config.require_ac)
+    }
+
+    pub fn reason(&self) -> String {
+        let power = if self.on_ac { " on AC" } else { "" };
+        format!("Docked with {}{}", self.outputs.join(", "), power)
+    }
+}
+
+/// Takes a `handle-lid-switch` block (by default) while external displays are
+/// connected, so closing the lid in a dock doesn't suspend. Re-checks on DRM and
+/// power-supply uevents, with a slow poll as a fallback.
+pub async fn watch(daemon: Daemon, config: DockConfig) -> Result<()> {
+    holds::validate_what(&config.what)?;
+
+    let socket = UeventSocket::open()?;
+    let mut interval = tokio::time::interval(Duration::from_secs(config.poll_interval_secs.max(1)));
+
+    tracing::info!("Watching for docked mode (blocking {})", config.what);
+
+    let mut docked = false;
+    loop {
+        let status = DockStatus::read(Path::new(SYSFS_DRM), Path::new(SYSFS_POWER_SUPPLY));
+        let now_docked = status.is_docked(&config);
+
+        if now_docked {
+            if !docked {
+                tracing::info!("{}, holding {}", status.reason(), config.what);
+            }
+            daemon.set_hold_what(HOLD_SOURCE, &config.what, &status.reason()).await?;
+        } else if docked {
+            tracing::info!("Undocked (outputs: {:?}, on AC: {}), releasing", status.outputs, status.on_ac);
+            daemon.release_hold(HOLD_SOURCE).await?;
+        }
+        docked = now_docked;
+
+        let hotplug = async {
+            loop {
+                match socket.recv().await {
+                    Ok(message) => {
+                        let relevant = parse_uevent(&message)
+                            .is_some_and(|event| matches!(event.subsystem(), Some("drm" | "power_supply")));
+                        if relevant {
+                            return Ok(());
+                        }
+                    }
+                    Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => return Ok(()),
+                    Err(e) => return Err(e).context("Failed to receive uevents"),
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = interval.tick() => {}
+            result = hotplug => {
+                result?;
+                tokio::time::sleep(SETTLE).await;
+                while let Some(Ok(_)) = socket.recv().now_or_never() {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+
+    fn add_connector(drm_root: &Path, name: &str, status: &str) {
+        let dir = drm_root.join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("status"), format!("{}\n", status)).unwrap();
+    }
+
+    fn add_supply(power_root: &Path, name: &str, attributes: &[(&str, &str)]) {
+        let dir = power_root.join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (attribute, value) in attributes {
+            std::fs::write(dir.join(attribute), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    #[test]
+    fn counts_connected_external_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        add_connector(dir.path(), "card1-eDP-1", "connected");
+        add_connector(dir.path(), "card1-DP-2", "connected");
+        add_connector(dir.path(), "card1-HDMI-A-1", "connected");
+        add_connector(dir.path(), "card1-DP-3", "disconnected");
+        add_connector(dir.path(), "card0-DP-2", "connected");
+        add_connector(dir.path(), "renderD128", "connected");
+        std::fs::create_dir_all(dir.path().join("card1-DP-4")).unwrap();
+        std::fs::write(dir.path().join("version"), "drm 1.1.0\n").unwrap();
+
+        assert_eq!(external_outputs(dir.path()), ["DP-2", "HDMI-A-1"]);
+        assert!(external_outputs(&dir.path().join("missing")).is_empty());
+    }
+
+    #[test]
+    fn docked_needs_outputs_and_ac() {
+        let dir = tempfile::tempdir().unwrap();
+        let (drm, power) = (dir.path().join("drm"), dir.path().join("power"));
+        std::fs::create_dir_all(&drm).unwrap();
+        add_connector(&drm, "card1-eDP-1", "connected");
+        add_supply(&power, "AC", &[("type", "Mains"), ("online", "0")]);
+        add_supply(&power, "BAT0", &[("type", "Battery"), ("status", "Discharging"), ("capacity", "80")]);
+
+        let config = DockConfig::default();
+        let status = DockStatus::read(&drm, &power);
+        assert_eq!(status, DockStatus { outputs: Vec::new(), on_ac: false });
+        assert!(!status.is_docked(&config));
+
+        add_connector(&drm, "card1-DP-2", "connected");
+        let status = DockStatus::read(&drm, &power);
+        assert!(!status.is_docked(&config));
+        assert!(status.is_docked(&DockConfig { require_ac: false, ..DockConfig::default() }));
+
+        add_supply(&power, "AC", &[("online", "1")]);
+        add_supply(&power, "BAT0", &[("status", "Charging")]);
+        let status = DockStatus::read(&drm, &power);
+        assert!(status.is_docked(&config));
+        assert!(!status.is_docked(&DockConfig { min_external_outputs: 2, ..DockConfig::default() }));
+        assert_eq!(status.reason(), "Docked with DP-2 on AC");
+
+        // Desktops without a power supply entry count as on AC
+        let status = DockStatus::read(&drm, &dir.path().join("missing"));
+        assert!(status.on_ac && status.is_docked(&config));
+    }
+
+    #[tokio::test]
+    async fn lid_switch_hold_is_not_idle_inhibition() {
+        let daemon = Daemon::detached(Config::default());
+
+        daemon.set_hold_what(HOLD_SOURCE, "handle-lid-switch", "Docked with DP-2 on AC").await.unwrap();
+        assert_eq!(daemon.inhibited_what().await, "handle-lid-switch");
+        assert!(!daemon.state().await.is_enabled());
+
+        daemon.set_hold("media:mpv", "Playing").await.unwrap();
+        assert!(daemon.state().await.is_enabled());
+        daemon.release_hold("media:mpv").await.unwrap();
+        assert!(!daemon.state().await.is_enabled());
+
+        // Disabling manually overrides the dock's block too
+        daemon.handle_signal("Disable", None).await.unwrap();
+        assert_eq!(daemon.inhibited_what().await, "");
+        assert!(daemon.holds().await.iter().any(|(source, _)| source == HOLD_SOURCE));
+
+        // Once undocked, the override lifts on its own
+        daemon.release_hold(HOLD_SOURCE).await.unwrap();
+        assert!(daemon.suppressions().await.is_empty());
+    }
+}
@@ -0,0 +1,459 @@
+use anyhow::{Context, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Poll};
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use crate::config::GamepadConfig;
+use crate::daemon::Daemon;
+use crate::holds;
+
+/// Hold source, also used for the controller reading.
+pub const HOLD_SOURCE: &str = "gamepad";
+
+pub const EV_KEY: u16 = 0x01;
+pub const EV_ABS: u16 = 0x03;
+
+/// BTN_JOYSTICK up to (not including) BTN_DIGI covers joystick and gamepad buttons.
+const CONTROLLER_BUTTONS: std::ops::Range<usize> = 0x120..0x140;
+
+/// Size of the kernel's `struct input_event`, which depends on the word size.
+pub const EVENT_SIZE: usize = std::mem::size_of::<libc::input_event>();
+
+/// One evdev event, without its timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl InputEvent {
+    /// Decodes a `struct input_event`; type, code and value are its last 8 bytes.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let tail = bytes.get(EVENT_SIZE.checked_sub(8)?..EVENT_SIZE)?;
+        Some(Self {
+            kind: u16::from_ne_bytes([tail[0], tail[1]]),
+            code: u16::from_ne_bytes([tail[2], tail[3]]),
+            value: i32::from_ne_bytes([tail[4], tail[5], tail[6], tail[7]]),
+        })
+    }
+}
+
+/// Set bits of a sysfs capability bitmap: hex `unsigned long` words, most significant first.
+pub fn bitmap_bits(bitmap: &str) -> Vec<usize> {
+    let word_bits = usize::BITS as usize;
+    bitmap
+        .split_whitespace()
+        .rev()
+        .enumerate()
+        .filter_map(|(index, word)| Some((index, u64::from_str_radix(word, 16).ok()?)))
+        .flat_map(|(index, word)| {
+            (0..word_bits)
+                .filter(move |bit| word >> bit & 1 == 1)
+                .map(move |bit| index * word_bits + bit)
+        })
+        .collect()
+}
+
+/// An evdev joystick or gamepad.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Controller {
+    /// Device node, e.g. /dev/input/event7
+    pub node: PathBuf,
+    pub name: String,
+    /// `vendor:product` in lowercase hex
+    pub id: String,
+    /// Absolute axes the device reports
+    pub axes: Vec<u16>,
+}
+
+/// Finds controllers through sysfs, so tests can point it at a fake tree.
+pub struct ControllerScanner {
+    sysfs_root: PathBuf,
+    dev_root: PathBuf,
+    devices: Vec<String>,
+}
+
+impl ControllerScanner {
+    pub fn new(sysfs_root: impl Into<PathBuf>, dev_root: impl Into<PathBuf>, devices: Vec<String>) -> Self {
+        Self {
+            sysfs_root: sysfs_root.into(),
+            dev_root: dev_root.into(),
+            devices: devices.into_iter().map(|device| device.to_lowercase()).collect(),
+        }
+    }
+
+    /// An empty device list accepts every controller; otherwise an entry has to
+    /// match the `vendor:product` id or be part of the name.
+    fn matches(&self, controller: &Controller) -> bool {
+        let name = controller.name.to_lowercase();
+        self.devices.is_empty()
+            || self
+                .devices
+                .iter()
+                .any(|device| *device == controller.id || name.contains(device.as_str()))
+    }
+
+    pub fn scan(&self) -> Vec<Controller> {
+        let class = self.sysfs_root.join("class/input");
+        let Ok(entries) = std::fs::read_dir(&class) else {
+            return Vec::new();
+        };
+
+        let read = |path: &Path| std::fs::read_to_string(path).map(|s| s.trim().to_string()).unwrap_or_default();
+
+        let mut controllers: Vec<Controller> = entries
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("event"))
+            .filter_map(|entry| {
+                let device = entry.path().join("device");
+                let keys = bitmap_bits(&read(&device.join("capabilities/key")));
+                if !keys.iter().any(|key| CONTROLLER_BUTTONS.contains(key)) {
+                    return None;
+                }
+
+                Some(Controller {
+                    node: self.dev_root.join(entry.file_name()),
+                    name: read(&device.join("name")),
+                    id: format!("{}:{}", read(&device.join("id/vendor")), read(&device.join("id/product"))).to_lowercase(),
+                    axes: bitmap_bits(&read(&device.join("capabilities/abs")))
+                        .into_iter()
+                        .filter_map(|axis| u16::try_from(axis).ok())
+                        .collect(),
+                })
+            })
+            .filter(|controller| self.matches(controller))
+            .collect();
+
+        controllers.sort_by(|a, b| a.node.cmp(&b.node));
+        controllers
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Axis {
+    last: i32,
+    threshold: f64,
+}
+
+/// Tells deliberate input from analog-stick noise.
+#[derive(Debug, Clone)]
+pub struct ActivityFilter {
+    deadzone: f64,
+    axes: BTreeMap<u16, Axis>,
+}
+
+impl ActivityFilter {
+    pub fn new(deadzone_percent: f64) -> Self {
+        Self { deadzone: deadzone_percent / 100.0, axes: BTreeMap::new() }
+    }
+
+    /// Registers an axis range and its resting value. Axes without a range count every event.
+    pub fn set_axis(&mut self, code: u16, value: i32, minimum: i32, maximum: i32) {
+        let range = (maximum as f64 - minimum as f64).abs();
+        self.axes.insert(code, Axis { last: value, threshold: (range * self.deadzone).max(1.0) });
+    }
+
+    /// Button presses count, as does an axis moving past the deadzone since it last counted.
+    pub fn is_activity(&mut self, event: &InputEvent) -> bool {
+        match event.kind {
+            EV_KEY => event.value != 0,
+            EV_ABS => match self.axes.get_mut(&event.code) {
+                Some(axis) => {
+                    let moved = (event.value as f64 - axis.last as f64).abs() >= axis.threshold;
+                    if moved {
+                        axis.last = event.value;
+                    }
+                    moved
+                }
+                None => true,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// EVIOCGABS(axis): _IOR('E', 0x40 + axis, struct input_absinfo)
+fn eviocgabs(axis: u16) -> libc::c_ulong {
+    let size = std::mem::size_of::<libc::input_absinfo>() as libc::c_ulong;
+    (2 << 30) | (size << 16) | ((b'E' as libc::c_ulong) << 8) | (0x40 + axis as libc::c_ulong)
+}
+
+/// A non-blocking evdev device node.
+pub struct EventDevice {
+    fd: AsyncFd<File>,
+}
+
+impl EventDevice {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let fd = AsyncFd::new(file).context("Failed to register input device with the runtime")?;
+        Ok(Self { fd })
+    }
+
+    /// A filter seeded with each axis' range and current value.
+    pub fn filter(&self, axes: &[u16], deadzone_percent: f64) -> ActivityFilter {
+        let mut filter = ActivityFilter::new(deadzone_percent);
+        for &axis in axes {
+            let mut info: libc::input_absinfo = unsafe { std::mem::zeroed() };
+            // SAFETY: EVIOCGABS fills exactly one input_absinfo
+            if unsafe { libc::ioctl(self.fd.get_ref().as_raw_fd(), eviocgabs(axis) as _, &mut info) } == 0 {
+                filter.set_axis(axis, info.value, info.minimum, info.maximum);
+            }
+        }
+        filter
+    }
+}
+
+impl AsyncRead for EventDevice {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let result = guard.try_io(|fd| {
+                let mut file: &File = fd.get_ref();
+                let read = file.read(buf.initialize_unfilled())?;
+                buf.advance(read);
+                Ok(())
+            });
+            if let Ok(result) = result {
+                return Poll::Ready(result);
+            }
+        }
+    }
+}
+
+/// Reads events from a device, or any injected stream of `struct input_event`s,
+/// sending `name` on `activity` for deliberate input. Ends with the stream.
+pub async fn read_events<R: AsyncRead + Unpin>(
+    mut reader: R,
+    mut filter: ActivityFilter,
+    name: String,
+    activity: mpsc::Sender<String>,
+) -> Result<()> {
+    let mut buf = [0u8; EVENT_SIZE];
+    loop {
+        match reader.read_exact(&mut buf).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e).context("Failed to read input events"),
+        }
+
+        let Some(event) = InputEvent::parse(&buf) else {
+            continue;
+        };
+        // A full channel already means activity is pending
+        if filter.is_activity(&event) {
+            if let Err(mpsc::error::TrySendError::Closed(_)) = activity.try_send(name.clone()) {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Keeps a short rolling hold alive while a joystick or gamepad is in use, since
+/// controller input doesn't reset the compositor's idle timer.
+pub async fn watch(daemon: Daemon, config: GamepadConfig) -> Result<()> {
+    holds::validate_what(&config.what)?;
+
+    let scanner = ControllerScanner::new("/sys", "/dev/input", config.devices.clone());
+    let hold = Duration::from_secs(config.hold_secs.max(1));
+    let (activity_tx, mut activity) = mpsc::channel::<String>(1);
+
+    let mut readers: BTreeMap<PathBuf, (String, JoinHandle<()>)> = BTreeMap::new();
+    let mut unreadable: BTreeSet<PathBuf> = BTreeSet::new();
+    let mut deadline: Option<Instant> = None;
+    let mut rescan = tokio::time::interval(Duration::from_secs(config.rescan_interval_secs.max(1)));
+
+    tracing::info!("Watching controllers ({} device filter(s))", config.devices.len());
+
+    loop {
+        let expired = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = rescan.tick() => {
+                readers.retain(|node, (name, handle)| {
+                    if handle.is_finished() {
+                        tracing::info!("Controller {} ({}) went away", name, node.display());
+                    }
+                    !handle.is_finished()
+                });
+
+                for controller in scanner.scan() {
+                    if readers.contains_key(&controller.node) {
+                        continue;
+                    }
+                    let device = match EventDevice::open(&controller.node) {
+                        Ok(device) => device,
+                        Err(e) => {
+                            if unreadable.insert(controller.node.clone()) {
+                                tracing::warn!("Skipping controller {}: {:#}", controller.name, e);
+                            }
+                            continue;
+                        }
+                    };
+                    unreadable.remove(&controller.node);
+
+                    tracing::info!("Watching controller {} ({})", controller.name, controller.node.display());
+                    let filter = device.filter(&controller.axes, config.deadzone_percent);
+                    let activity_tx = activity_tx.clone();
+                    let name = controller.name.clone();
+                    let handle = tokio::spawn(async move {
+                        if let Err(e) = read_events(device, filter, name.clone(), activity_tx).await {
+                            tracing::debug!("Controller {} stopped: {:#}", name, e);
+                        }
+                    });
+                    readers.insert(controller.node, (controller.name, handle));
+                }
+
+                let names: Vec<&str> = readers.values().map(|(name, _)| name.as_str()).collect();
+                daemon.set_reading(HOLD_SOURCE, names.join(", ")).await;
+            }
+            Some(name) = activity.recv() => {
+                if deadline.is_none() {
+                    tracing::info!("Controller activity on {}", name);
+                }
+                daemon
+                    .set_hold_what(HOLD_SOURCE, &config.what, &format!("Controller in use ({})", name))
+                    .await?;
+                deadline = Some(Instant::now() + hold);
+            }
+            _ = expired => {
+                tracing::info!("No controller activity for {}s, releasing", hold.as_secs());
+                daemon.release_hold(HOLD_SOURCE).await?;
+                deadline = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    const EV_SYN: u16 = 0x00;
+    const BTN_SOUTH: u16 = 0x130;
+    const ABS_X: u16 = 0x00;
+    const ABS_Y: u16 = 0x01;
+
+    /// A `struct input_event` with a zero timestamp.
+    fn event(kind: u16, code: u16, value: i32) -> Vec<u8> {
+        let mut bytes = vec![0u8; EVENT_SIZE - 8];
+        bytes.extend_from_slice(&kind.to_ne_bytes());
+        bytes.extend_from_slice(&code.to_ne_bytes());
+        bytes.extend_from_slice(&value.to_ne_bytes());
+        bytes
+    }
+
+    /// Feeds `events` through `read_events` and returns how many counted as activity.
+    async fn activity_count(filter: ActivityFilter, events: &[(u16, u16, i32)]) -> usize {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let (tx, mut rx) = mpsc::channel(events.len().max(1));
+        let reading = tokio::spawn(read_events(reader, filter, "pad".to_string(), tx));
+
+        for &(kind, code, value) in events {
+            let bytes = event(kind, code, value);
+            // Split events across writes to exercise partial reads
+            let (head, tail) = bytes.split_at(5);
+            writer.write_all(head).await.unwrap();
+            writer.write_all(tail).await.unwrap();
+        }
+        drop(writer);
+        reading.await.unwrap().unwrap();
+
+        let mut count = 0;
+        while let Ok(name) = rx.try_recv() {
+            assert_eq!(name, "pad");
+            count += 1;
+        }
+        count
+    }
+
+    fn stick_filter() -> ActivityFilter {
+        let mut filter = ActivityFilter::new(10.0);
+        filter.set_axis(ABS_X, 0, -32768, 32767);
+        filter.set_axis(ABS_Y, 0, -32768, 32767);
+        filter
+    }
+
+    #[test]
+    fn parses_events() {
+        let bytes = event(EV_KEY, BTN_SOUTH, 1);
+        assert_eq!(InputEvent::parse(&bytes), Some(InputEvent { kind: EV_KEY, code: BTN_SOUTH, value: 1 }));
+        assert_eq!(InputEvent::parse(&event(EV_ABS, ABS_Y, -42)).unwrap().value, -42);
+        assert_eq!(InputEvent::parse(&bytes[..EVENT_SIZE - 1]), None);
+    }
+
+    #[test]
+    fn bitmap_bits_reads_words_least_significant_last() {
+        let word_bits = usize::BITS as usize;
+        assert_eq!(bitmap_bits("0"), Vec::<usize>::new());
+        assert_eq!(bitmap_bits("5"), [0, 2]);
+        assert_eq!(bitmap_bits("3 0"), [word_bits, word_bits + 1]);
+        assert_eq!(bitmap_bits("1 0 80000000"), [31, 2 * word_bits]);
+        // BTN_SOUTH is 0x130, bit 48 of the fifth 64-bit word
+        if word_bits == 64 {
+            assert!(bitmap_bits("7fdb000000000000 0 0 0 0").contains(&(BTN_SOUTH as usize)));
+        }
+        assert_eq!(bitmap_bits("zz 1"), [0]);
+        assert_eq!(bitmap_bits(""), Vec::<usize>::new());
+    }
+
+    #[tokio::test]
+    async fn button_presses_count() {
+        let events = [
+            (EV_KEY, BTN_SOUTH, 1),
+            (EV_SYN, 0, 0),
+            (EV_KEY, BTN_SOUTH, 0),
+            (EV_SYN, 0, 0),
+            (EV_KEY, BTN_SOUTH, 2),
+        ];
+        // Press and autorepeat count; release and sync don't
+        assert_eq!(activity_count(stick_filter(), &events).await, 2);
+    }
+
+    #[tokio::test]
+    async fn stick_drift_stays_inside_the_deadzone() {
+        let drift: Vec<_> = [120, -300, 800, 2000, -1500, 6000, 400]
+            .into_iter()
+            .flat_map(|value| [(EV_ABS, ABS_X, value), (EV_ABS, ABS_Y, -value), (EV_SYN, 0, 0)])
+            .collect();
+        assert_eq!(activity_count(stick_filter(), &drift).await, 0);
+    }
+
+    #[tokio::test]
+    async fn deliberate_stick_movement_counts() {
+        let events = [
+            // A full push counts once, then noise around the new position doesn't
+            (EV_ABS, ABS_X, 32000),
+            (EV_ABS, ABS_X, 31500),
+            (EV_ABS, ABS_X, 32767),
+            // Letting go is movement too
+            (EV_ABS, ABS_X, 0),
+            (EV_ABS, ABS_Y, -7000),
+        ];
+        assert_eq!(activity_count(stick_filter(), &events).await, 3);
+
+        // Axes without a known range count every event
+        assert_eq!(activity_count(ActivityFilter::new(10.0), &[(EV_ABS, 0x10, 1), (EV_ABS, 0x10, 0)]).await, 2);
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Hold kind used for manual enable/disable/toggle requests.
+pub const USER: &str = "user";
+
+/// Hold kind for holds registered by other processes over D-Bus.
+pub const CLIENT: &str = "client";
+
+/// Hold kind for holds tied to an arbitrary PID via `attach`.
+pub const ATTACH: &str = "attach";
+
+/// Inhibitor type used when a source doesn't ask for anything else.
+pub const DEFAULT_WHAT: &str = "idle";
+
+const VALID_WHATS: &[&str] = &[
+    "shutdown",
+    "sleep",
+    "idle",
+    "handle-power-key",
+    "handle-suspend-key",
+    "handle-hibernate-key",
+    "handle-lid-switch",
+];
+
+/// Checks a colon-separated logind `what` list before it reaches logind.
+pub fn validate_what(what: &str) -> Result<()> {
+    if what.is_empty() {
+        bail!("Empty inhibitor type");
+    }
+    for item in what.split(':') {
+        if !VALID_WHATS.contains(&item) {
+            bail!("Unknown inhibitor type '{}' (expected one of {})", item, VALID_WHATS.join(", "));
+        }
+    }
+    Ok(())
+}
+
+/// Whether a colon-separated `what` list includes the idle inhibitor.
+pub fn blocks_idle(what: &str) -> bool {
+    what.split(':').any(|item| item == "idle")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hold {
+    pub what: String,
+    pub reason: String,
+}
+
+/// Everything currently asking the daemon to keep the inhibitor, keyed by source.
+///
+/// Sources are named `<kind>` or `<kind>:<detail>` (e.g. `user`, `user:presenting`),
+/// so every hold of one kind can be released together.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Holds {
+    entries: BTreeMap<String, Hold>,
+}
+
+impl Holds {
+    /// Adds or updates an idle hold. Returns whether anything changed.
+    pub fn insert(&mut self, source: impl Into<String>, reason: impl Into<String>) -> bool {
+        self.insert_what(source, DEFAULT_WHAT, reason)
+    }
+    
+    /// Adds or updates a hold for the given logind `what`. Returns whether anything changed.
+    pub fn insert_what(
+        &mut self,
+        source: impl Into<String>,
+        what: impl Into<String>,
+        reason: impl Into<String>,
+    ) -> bool {
+        let hold = Hold { what: what.into(), reason: reason.into() };
+        let previous = self.entries.insert(source.into(), hold.clone());
+        previous.as_ref() != Some(&hold)
+    }
+    
+    /// Removes a single hold. Returns whether it existed.
+    pub fn remove(&mut self, source: &str) -> bool {
+        self.entries.remove(source).is_some()
+    }
+    
+    /// Removes every hold of the given kind. Returns whether any existed.
+    pub fn remove_kind(&mut self, kind: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|source, _| source_kind(source) != kind);
+        self.entries.len() != before
+    }
+    
+    /// Source and reason of every hold of the given kind.
+    pub fn of_kind(&self, kind: &str) -> BTreeMap<String, String> {
+        self.entries
+            .iter()
+            .filter(|(source, _)| source_kind(source) == kind)
+            .map(|(source, hold)| (source.clone(), hold.reason.clone()))
+            .collect()
+    }
+    
+    pub fn has_kind(&self, kind: &str) -> bool {
+        self.entries.keys().any(|source| source_kind(source) == kind)
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Hold)> {
+        self.entries.iter().map(|(source, hold)| (source.as_str(), hold))
+    }
+    
+    /// The holds whose kind is not listed in `kinds`.
+    pub fn without_kinds(&self, kinds: &[String]) -> Holds {
+        Holds {
+            entries: self
+                .entries
+                .iter()
+                .filter(|(source, _)| !kinds.iter().any(|kind| kind == source_kind(source)))
+                .map(|(source, hold)| (source.clone(), hold.clone()))
+                .collect(),
+        }
+    }
+    
+    /// Distinct reasons in source order.
+    pub fn reasons(&self) -> Vec<&str> {
+        let mut reasons: Vec<&str> = Vec::new();
+        for hold in self.entries.values() {
+            if !reasons.contains(&hold.reason.as_str()) {
+                reasons.push(&hold.reason);
+            }
+        }
+        reasons
+    }
+    
+    /// Union of every hold's `what`, colon-separated in a stable order.
+    pub fn what(&self) -> String {
+        let whats: BTreeSet<&str> = self
+            .entries
+            .values()
+            .flat_map(|hold| hold.what.split(':'))
+            .collect();
+        whats.into_iter().collect::<Vec<_>>().join(":")
+    }
+}
+
+pub fn source_kind(source: &str) -> &str {
+    source.split_once(':').map_or(source, |(kind, _)| kind)
+}
+
+/// Whether `source` is `name` itself or nested under it, so `schedule` matches
+/// `schedule:night` but not `schedules`.
+pub fn source_matches(source: &str, name: &str) -> bool {
+    source
+        .strip_prefix(name)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(':'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_kind_drops_only_that_kind() {
+        let mut holds = Holds::default();
+        holds.insert(USER, "manual");
+        holds.insert("user:presenting", "Presenting");
+        holds.insert("users", "not a user hold");
+        holds.insert("process:obs", "OBS running");
+
+        assert!(holds.remove_kind(USER));
+        assert!(!holds.has_kind(USER));
+        let sources: Vec<&str> = holds.iter().map(|(source, _)| source).collect();
+        assert_eq!(sources, ["process:obs", "users"]);
+
+        assert!(!holds.remove_kind(USER));
+    }
+
+    #[test]
+    fn insert_reports_changes() {
+        let mut holds = Holds::default();
+        assert!(holds.insert("media", "Playing"));
+        assert!(!holds.insert("media", "Playing"));
+        assert!(holds.insert("media", "Paused"));
+        assert!(holds.insert_what("media", "sleep", "Paused"));
+        assert_eq!(holds.len(), 1);
+    }
+
+    #[test]
+    fn source_matching_respects_segments() {
+        assert!(source_matches("schedule", "schedule"));
+        assert!(source_matches("schedule:night", "schedule"));
+        assert!(source_matches("process:obs:1", "process:obs"));
+        assert!(!source_matches("schedules", "schedule"));
+        assert!(!source_matches("schedule", "schedule:night"));
+        assert_eq!(source_kind("process:obs"), "process");
+        assert_eq!(source_kind("user"), "user");
+    }
+
+    #[test]
+    fn what_is_a_sorted_union() {
+        let mut holds = Holds::default();
+        assert_eq!(holds.what(), "");
+
+        holds.insert("media", "Playing");
+        holds.insert_what("dock", "handle-lid-switch", "Docked");
+        holds.insert_what("network", "sleep:idle", "Downloading");
+        assert_eq!(holds.what(), "handle-lid-switch:idle:sleep");
+        assert!(validate_what(&holds.what()).is_ok());
+    }
+
+    #[test]
+    fn without_kinds_and_reasons() {
+        let mut holds = Holds::default();
+        holds.insert("process:a", "Busy");
+        holds.insert("process:b", "Busy");
+        holds.insert("load", "High load");
+
+        assert_eq!(holds.reasons(), ["High load", "Busy"]);
+        let effective = holds.without_kinds(&["process".to_string()]);
+        assert_eq!(effective.len(), 1);
+        assert!(effective.has_kind("load"));
+    }
+
+    #[test]
+    fn validate_what_rejects_unknown_types() {
+        assert!(validate_what("idle:sleep").is_ok());
+        assert!(validate_what("").is_err());
+        assert!(validate_what("idle:nap").is_err());
+        assert!(validate_what("idle:").is_err());
+    }
+
+    #[test]
+    fn blocks_idle_needs_the_idle_type() {
+        assert!(blocks_idle("idle"));
+        assert!(blocks_idle("handle-lid-switch:idle"));
+        assert!(!blocks_idle("handle-lid-switch"));
+        assert!(!blocks_idle("sleep"));
+        assert!(!blocks_idle(""));
+    }
+}
@@ -0,0 +1,86 @@
+use std::time::{Duration, Instant};
+
+/// Debounces a threshold check: the state only flips after the new condition
+/// has held for `sustain` (turning on) or `cooldown` (turning off).
+#[derive(Debug, Clone)]
+pub struct Hysteresis {
+    sustain: Duration,
+    cooldown: Duration,
+    active: bool,
+    /// When the condition started disagreeing with `active`
+    pending_since: Option<Instant>,
+}
+
+impl Hysteresis {
+    pub fn new(sustain: Duration, cooldown: Duration) -> Self {
+        Self { sustain, cooldown, active: false, pending_since: None }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Feeds the latest observation and returns whether the state is now active.
+    pub fn update(&mut self, above: bool, now: Instant) -> bool {
+        if above == self.active {
+            self.pending_since = None;
+            return self.active;
+        }
+
+        let since = *self.pending_since.get_or_insert(now);
+        let needed = if self.active { self.cooldown } else { self.sustain };
+        if now.duration_since(since) >= needed {
+            self.active = above;
+            self.pending_since = None;
+        }
+
+        self.active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flips_after_hold_down() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut state = Hysteresis::new(Duration::from_secs(5), Duration::from_secs(20));
+
+        assert!(!state.update(true, at(0)));
+        assert!(!state.update(true, at(4)));
+        assert!(state.update(true, at(5)));
+
+        assert!(state.update(false, at(10)));
+        assert!(state.update(false, at(29)));
+        assert!(!state.update(false, at(30)));
+        assert!(!state.is_active());
+    }
+
+    #[test]
+    fn interruptions_restart_the_timer() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut state = Hysteresis::new(Duration::from_secs(5), Duration::from_secs(5));
+
+        assert!(!state.update(true, at(0)));
+        assert!(!state.update(false, at(4)));
+        assert!(!state.update(true, at(6)));
+        assert!(!state.update(true, at(10)));
+        assert!(state.update(true, at(11)));
+
+        assert!(state.update(false, at(12)));
+        assert!(state.update(true, at(16)));
+        assert!(state.update(false, at(20)));
+        assert!(!state.update(false, at(25)));
+    }
+
+    #[test]
+    fn zero_durations_follow_immediately() {
+        let now = Instant::now();
+        let mut state = Hysteresis::new(Duration::ZERO, Duration::ZERO);
+        assert!(state.update(true, now));
+        assert!(!state.update(false, now));
+    }
+}
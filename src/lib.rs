@@ -1,8 +1,36 @@
+pub mod bluetooth;
+pub mod calendar;
+pub mod compositor;
 pub mod config;
+pub mod daemon;
 pub mod dbus;
+pub mod devices;
+pub mod dock;
+pub mod gamepad;
+pub mod holds;
+pub mod hysteresis;
+pub mod load;
+pub mod mpris;
+pub mod network;
+pub mod pidwatch;
+pub mod plugins;
+pub mod power;
+pub mod process;
+pub mod remote;
+pub mod rules;
+pub mod run;
+pub mod schedule;
+pub mod scripting;
 pub mod state;
+pub mod systemd;
+pub mod uevent;
+pub mod usb;
+pub mod waybar;
+pub mod zoneinfo;
+pub mod service;
 pub mod session;
 
 pub use config::Config;
+pub use daemon::Daemon;
 pub use state::State;
 pub use session::{SessionInfo, get_current_session};
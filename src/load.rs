@@ -0,0 +1,276 @@
+use anyhow::{bail, Context, Result};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use crate::config::LoadConfig;
+use crate::daemon::Daemon;
+use crate::holds;
+use crate::hysteresis::Hysteresis;
+
+/// Hold source, also used for the load reading.
+pub const HOLD_SOURCE: &str = "load";
+
+/// One sample of every load signal; missing ones are `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LoadSample {
+    /// 1-minute load average
+    pub loadavg: Option<f64>,
+    /// CPU utilization since the previous sample, in percent
+    pub cpu_percent: Option<f64>,
+    /// `some avg10` from /proc/pressure/cpu, in percent
+    pub psi: Option<f64>,
+}
+
+impl std::fmt::Display for LoadSample {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(loadavg) = self.loadavg {
+            parts.push(format!("loadavg {:.2}", loadavg));
+        }
+        if let Some(cpu) = self.cpu_percent {
+            parts.push(format!("cpu {:.0}%", cpu));
+        }
+        if let Some(psi) = self.psi {
+            parts.push(format!("psi {:.1}%", psi));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
+pub fn parse_loadavg(content: &str) -> Option<f64> {
+    content.split_whitespace().next()?.parse().ok()
+}
+
+/// (busy, total) jiffies from the aggregate `cpu` line of /proc/stat.
+pub fn parse_stat(content: &str) -> Option<(u64, u64)> {
+    let line = content.lines().find(|line| line.starts_with("cpu "))?;
+    let fields: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .map(|field| field.parse().ok())
+        .collect::<Option<_>>()?;
+
+    // user nice system idle iowait irq softirq steal; guest time is already in user
+    let total: u64 = fields.iter().take(8).sum();
+    let idle = fields.get(3)? + fields.get(4).copied().unwrap_or(0);
+    Some((total - idle, total))
+}
+
+/// `avg10` of the `some` line of a PSI file.
+pub fn parse_psi(content: &str) -> Option<f64> {
+    content
+        .lines()
+        .find(|line| line.starts_with("some "))?
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("avg10="))?
+        .parse()
+        .ok()
+}
+
+/// Reads load signals from a procfs mount.
+pub struct LoadSampler {
+    proc_root: PathBuf,
+    psi: bool,
+    last_stat: Option<(u64, u64)>,
+}
+
+impl LoadSampler {
+    pub fn new(proc_root: impl Into<PathBuf>, psi: bool) -> Self {
+        Self { proc_root: proc_root.into(), psi, last_stat: None }
+    }
+
+    fn read(&self, name: &str) -> Result<String> {
+        let path = self.proc_root.join(name);
+        std::fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))
+    }
+
+    pub fn sample(&mut self) -> Result<LoadSample> {
+        let loadavg = parse_loadavg(&self.read("loadavg")?);
+
+        let stat = parse_stat(&self.read("stat")?);
+        let cpu_percent = match (self.last_stat, stat) {
+            (Some((busy0, total0)), Some((busy1, total1))) if total1 > total0 => {
+                Some(busy1.saturating_sub(busy0) as f64 / (total1 - total0) as f64 * 100.0)
+            }
+            _ => None,
+        };
+        self.last_stat = stat;
+
+        // Kernels without CONFIG_PSI have no /proc/pressure
+        let psi = if self.psi {
+            self.read("pressure/cpu").ok().and_then(|content| parse_psi(&content))
+        } else {
+            None
+        };
+
+        Ok(LoadSample { loadavg, cpu_percent, psi })
+    }
+}
+
+/// Whether any configured signal is at or above its threshold scaled by `factor`.
+fn above(config: &LoadConfig, sample: &LoadSample, factor: f64) -> bool {
+    let exceeds = |value: Option<f64>, threshold: Option<f64>| match (value, threshold) {
+        (Some(value), Some(threshold)) => value >= threshold * factor,
+        _ => false,
+    };
+
+    exceeds(sample.loadavg, config.loadavg_threshold)
+        || exceeds(sample.cpu_percent, config.cpu_percent_threshold)
+        || exceeds(sample.psi, config.psi_threshold)
+}
+
+/// Feeds a sample into the hold state and returns whether to hold.
+fn update(config: &LoadConfig, state: &mut Hysteresis, sample: &LoadSample, now: Instant) -> bool {
+    // Once held, the load has to fall clearly below the thresholds to release
+    let factor = if state.is_active() { config.release_factor } else { 1.0 };
+    state.update(above(config, sample, factor), now)
+}
+
+/// Holds the configured inhibitor while the system stays busy, e.g. during long
+/// compiles or renders.
+pub async fn watch(daemon: Daemon, config: LoadConfig) -> Result<()> {
+    holds::validate_what(&config.what)?;
+    if config.loadavg_threshold.is_none() && config.cpu_percent_threshold.is_none() && config.psi_threshold.is_none() {
+        bail!("No load thresholds configured");
+    }
+
+    let mut sampler = LoadSampler::new("/proc", config.psi_threshold.is_some());
+    let mut state = Hysteresis::new(
+        Duration::from_secs(config.sustain_secs),
+        Duration::from_secs(config.cooldown_secs),
+    );
+
+    tracing::info!(
+        "Watching system load (loadavg >= {:?}, cpu >= {:?}%, psi >= {:?}%)",
+        config.loadavg_threshold, config.cpu_percent_threshold, config.psi_threshold
+    );
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.sample_interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+
+        let sample = sampler.sample()?;
+        daemon.set_reading(HOLD_SOURCE, sample.to_string()).await;
+
+        let was_active = state.is_active();
+        match (was_active, update(&config, &mut state, &sample, Instant::now())) {
+            (false, true) => {
+                tracing::info!("System busy ({}), holding {}", sample, config.what);
+                daemon.set_hold_what(HOLD_SOURCE, &config.what, "System is busy").await?;
+            }
+            (true, false) => {
+                tracing::info!("System load dropped ({}), releasing", sample);
+                daemon.release_hold(HOLD_SOURCE).await?;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(user: u64, system: u64, idle: u64, iowait: u64) -> String {
+        format!(
+            "cpu  {} 0 {} {} {} 0 0 0 0 0\ncpu0 {} 0 {} {} {} 0 0 0 0 0\nintr 12345\n",
+            user, system, idle, iowait, user, system, idle, iowait
+        )
+    }
+
+    fn write_proc(root: &std::path::Path, stat: &str, psi: Option<&str>) {
+        std::fs::write(root.join("loadavg"), "1.50 0.80 0.40 2/345 6789\n").unwrap();
+        std::fs::write(root.join("stat"), stat).unwrap();
+        if let Some(psi) = psi {
+            std::fs::create_dir_all(root.join("pressure")).unwrap();
+            std::fs::write(root.join("pressure/cpu"), psi).unwrap();
+        }
+    }
+
+    #[test]
+    fn parses_loadavg_and_stat() {
+        assert_eq!(parse_loadavg("1.50 0.80 0.40 2/345 6789\n"), Some(1.5));
+        assert_eq!(parse_loadavg(""), None);
+
+        // idle and iowait count as idle; steal is busy
+        assert_eq!(parse_stat("cpu  100 10 50 800 40 5 5 2 0 0\n"), Some((172, 1012)));
+        // Old kernels have only four fields
+        assert_eq!(parse_stat("cpu  100 10 50 800\n"), Some((160, 960)));
+        assert_eq!(parse_stat("cpu0 100 10 50 800\n"), None);
+        assert_eq!(parse_stat("cpu  100 x 50 800\n"), None);
+        assert_eq!(parse_stat("cpu  100 10 50\n"), None);
+    }
+
+    #[test]
+    fn parses_psi() {
+        let content = "some avg10=12.34 avg60=5.00 avg300=1.00 total=123456\n\
+                       full avg10=99.00 avg60=0.00 avg300=0.00 total=0\n";
+        assert_eq!(parse_psi(content), Some(12.34));
+
+        // Only the `some` line counts, wherever it is
+        let content = "full avg10=99.00 avg60=0.00 avg300=0.00 total=0\n\
+                       some avg10=0.50 avg60=0.00 avg300=0.00 total=0\n";
+        assert_eq!(parse_psi(content), Some(0.5));
+
+        assert_eq!(parse_psi("full avg10=99.00 avg60=0.00 avg300=0.00 total=0\n"), None);
+        assert_eq!(parse_psi("some avg60=5.00 avg300=1.00 total=123456\n"), None);
+        assert_eq!(parse_psi("some avg10=high avg60=5.00\n"), None);
+        assert_eq!(parse_psi("someavg10=1.00\n"), None);
+        assert_eq!(parse_psi(""), None);
+    }
+
+    #[test]
+    fn samples_cpu_between_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sampler = LoadSampler::new(dir.path(), true);
+
+        write_proc(dir.path(), &stat(100, 100, 800, 0), Some("some avg10=3.00 avg60=0.00 avg300=0.00 total=0\n"));
+        let first = sampler.sample().unwrap();
+        assert_eq!(first, LoadSample { loadavg: Some(1.5), cpu_percent: None, psi: Some(3.0) });
+
+        // 150 busy out of 200 jiffies
+        write_proc(dir.path(), &stat(200, 150, 830, 20), None);
+        assert_eq!(sampler.sample().unwrap().cpu_percent, Some(75.0));
+
+        // Counters going backwards (e.g. a CPU went offline) give no reading
+        write_proc(dir.path(), &stat(150, 100, 800, 0), None);
+        assert_eq!(sampler.sample().unwrap().cpu_percent, None);
+
+        // and the next sample measures from the lower counters
+        write_proc(dir.path(), &stat(160, 110, 880, 0), None);
+        assert_eq!(sampler.sample().unwrap().cpu_percent, Some(20.0));
+    }
+
+    #[test]
+    fn missing_psi_is_no_reading() {
+        let dir = tempfile::tempdir().unwrap();
+        write_proc(dir.path(), &stat(100, 100, 800, 0), None);
+
+        assert_eq!(LoadSampler::new(dir.path(), true).sample().unwrap().psi, None);
+        assert!(LoadSampler::new(dir.path().join("missing"), false).sample().is_err());
+    }
+
+    #[test]
+    fn releases_below_the_release_factor() {
+        let config = LoadConfig {
+            cpu_percent_threshold: Some(80.0),
+            release_factor: 0.5,
+            ..LoadConfig::default()
+        };
+        let mut state = Hysteresis::new(Duration::ZERO, Duration::ZERO);
+        let now = Instant::now();
+        let cpu = |percent| LoadSample { cpu_percent: Some(percent), ..LoadSample::default() };
+
+        // Between the release and hold levels does not start a hold
+        assert!(!update(&config, &mut state, &cpu(60.0), now));
+        assert!(update(&config, &mut state, &cpu(80.0), now));
+        // but keeps one going
+        assert!(update(&config, &mut state, &cpu(60.0), now));
+        assert!(update(&config, &mut state, &cpu(40.0), now));
+        assert!(!update(&config, &mut state, &cpu(39.0), now));
+        assert!(!update(&config, &mut state, &cpu(60.0), now));
+
+        // Signals without a threshold never hold
+        let loadavg = LoadSample { loadavg: Some(100.0), psi: Some(100.0), ..LoadSample::default() };
+        assert!(!update(&config, &mut state, &loadavg, now));
+    }
+}
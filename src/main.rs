@@ -1,8 +1,11 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use logind_idle_control::{dbus, Config, State, get_current_session};
+use logind_idle_control::{bluetooth, calendar, compositor, dbus, devices, dock, gamepad, holds, load, mpris, network, plugins, power, process, remote, run, schedule, service, scripting, systemd, usb, Config, Daemon, State, get_current_session};
+use logind_idle_control::session::LOCKED_FACT;
+use logind_idle_control::waybar::MonitorFormat;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::Notify;
 use tracing::{error, info, warn};
 
 #[derive(Parser)]
@@ -15,15 +18,54 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
-    Enable,
+    Enable {
+        /// Why the inhibitor is needed (shown in `systemd-inhibit --list`)
+        #[arg(long)]
+        reason: Option<String>,
+    },
     Disable,
-    Toggle,
-    Status,
+    Toggle {
+        /// Why the inhibitor is needed, used when toggling on
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    Status {
+        /// Also show the daemon's active holds and attached PIDs
+        #[arg(short, long)]
+        verbose: bool,
+        /// List every logind inhibitor, not just this daemon's state
+        #[arg(long)]
+        all: bool,
+    },
     Config,
-    Monitor,
+    /// Print the state on every change
+    Monitor {
+        #[arg(long, value_enum, default_value_t = MonitorFormat::Plain)]
+        format: MonitorFormat,
+    },
     #[command(name = "state-path")]
     StatePath,
     Daemon,
+    /// Run a command while holding an inhibitor, like systemd-inhibit
+    Run {
+        /// Colon-separated logind inhibitor types
+        #[arg(long, default_value = "idle")]
+        what: String,
+        /// Why the inhibitor is needed (default: the command line)
+        #[arg(long)]
+        reason: Option<String>,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Explain which rules are currently true and why
+    Rules,
+    /// Hold an inhibitor until an already running process exits
+    Attach {
+        pid: u32,
+        /// Why the inhibitor is needed (default: the process name)
+        #[arg(long)]
+        reason: Option<String>,
+    },
 }
 
 #[tokio::main]
@@ -31,28 +73,38 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
     
     match cli.command {
-        Commands::Enable => {
-            dbus::emit_signal("Enable").await?;
+        Commands::Enable { reason } => {
+            dbus::emit_signal("Enable", reason.as_deref()).await?;
             println!("Idle inhibitor enabled");
         }
         Commands::Disable => {
-            dbus::emit_signal("Disable").await?;
+            dbus::emit_signal("Disable", None).await?;
             println!("Idle inhibitor disabled");
         }
-        Commands::Toggle => {
-            dbus::emit_signal("Toggle").await?;
+        Commands::Toggle { reason } => {
+            dbus::emit_signal("Toggle", reason.as_deref()).await?;
             println!("Idle inhibitor toggled");
         }
-        Commands::Status => {
+        Commands::Status { verbose, all } => {
             let state = State::load()?;
             println!("{}", state);
+            if let Some(reason) = state.failure_reason() {
+                eprintln!("Inhibitor acquisition failed: {}", reason);
+            }
+            print_next_transition()?;
+            if verbose {
+                print_holds().await?;
+            }
+            if all {
+                print_system_inhibitors().await?;
+            }
         }
         Commands::Config => {
             println!("Config TUI coming soon!");
             println!("Edit config file at: {:?}", logind_idle_control::Config::config_path());
         }
-        Commands::Monitor => {
-            dbus::monitor_state_changes().await?;
+        Commands::Monitor { format } => {
+            dbus::monitor_state_changes(format).await?;
         }
         Commands::StatePath => {
             let path = State::state_path();
@@ -61,8 +113,138 @@ async fn main() -> Result<()> {
         Commands::Daemon => {
             run_daemon().await?;
         }
+        Commands::Rules => {
+            print_rules().await?;
+        }
+        Commands::Attach { pid, reason } => {
+            let id = dbus::attach_pid(pid, reason.as_deref().unwrap_or("")).await?;
+            let comm = process::command_name(pid).unwrap_or_else(|| "?".to_string());
+            println!("Holding idle inhibitor until PID {} ({}) exits [{}]", pid, comm, id);
+        }
+        Commands::Run { what, reason, command } => {
+            let code = run::run(&what, reason, &command).await?;
+            std::process::exit(code);
+        }
+    }
+    
+    Ok(())
+}
+
+/// Goes to stderr so the first stdout line stays the bare state.
+fn print_next_transition() -> Result<()> {
+    let config = Config::load()?;
+    if !config.schedule.enabled {
+        return Ok(());
     }
     
+    let schedule = schedule::Schedule::new(config.schedule.rules)?;
+    let now = schedule::now();
+    let Some(transition) = schedule.next_transition(now)? else {
+        return Ok(());
+    };
+    
+    eprintln!("Next schedule transition: {}", transition.describe(now));
+    
+    Ok(())
+}
+
+async fn print_holds() -> Result<()> {
+    let holds = dbus::query_holds().await?;
+    
+    println!();
+    println!("Holds:");
+    if holds.is_empty() {
+        println!("  (none)");
+    }
+    for hold in &holds {
+        println!("  {:<24} {:<12} {}", hold.source, hold.what, hold.reason);
+    }
+    
+    let suppressions = dbus::query_suppressions().await?;
+    if !suppressions.is_empty() {
+        println!();
+        println!("Suppressed (inhibitor forced off):");
+        for (source, reason) in &suppressions {
+            println!("  {:<24} {}", source, reason);
+        }
+    }
+    
+    let readings = dbus::query_readings().await?;
+    if !readings.is_empty() {
+        println!();
+        println!("Readings:");
+        for (source, value) in &readings {
+            println!("  {:<24} {}", source, value);
+        }
+    }
+    
+    let attached: Vec<u32> = holds
+        .iter()
+        .filter(|hold| holds::source_kind(&hold.source) == holds::ATTACH)
+        .filter_map(|hold| hold.source.split_once(':')?.1.parse().ok())
+        .collect();
+    
+    if !attached.is_empty() {
+        println!();
+        println!("Attached PIDs:");
+        for pid in attached {
+            let comm = process::command_name(pid).unwrap_or_else(|| "?".to_string());
+            println!("  {:>8}  {}", pid, comm);
+        }
+    }
+    
+    Ok(())
+}
+
+async fn print_rules() -> Result<()> {
+    let rules = dbus::query_rules().await?;
+    
+    if rules.is_empty() {
+        println!("No rules configured");
+        return Ok(());
+    }
+    
+    for rule in &rules {
+        println!(
+            "{} {:<24} {:<8} priority {:<4} {}",
+            if rule.applied { "*" } else { " " },
+            rule.name,
+            rule.action,
+            rule.priority,
+            if rule.matched { "true" } else { "false" },
+        );
+        println!("    {}", rule.explanation);
+    }
+    println!("* = rule currently deciding the inhibitor");
+    
+    Ok(())
+}
+
+async fn print_system_inhibitors() -> Result<()> {
+    let inhibitors = match dbus::query_system_inhibitors().await {
+        Ok(inhibitors) => inhibitors,
+        Err(e) => {
+            eprintln!("Daemon unavailable ({:#}), querying logind directly", e);
+            dbus::list_system_inhibitors().await?
+        }
+    };
+    
+    println!();
+    println!("  {:<32} {:<24} {:<6} {:>6} {:>8}  WHY", "WHAT", "WHO", "MODE", "UID", "PID");
+    for inhibitor in &inhibitors {
+        println!(
+            "{} {:<32} {:<24} {:<6} {:>6} {:>8}  {}",
+            if inhibitor.ours { "*" } else { " " },
+            inhibitor.what,
+            inhibitor.who,
+            inhibitor.mode,
+            inhibitor.uid,
+            inhibitor.pid,
+            inhibitor.why,
+        );
+    }
+    println!("{} inhibitor(s), * = held by this daemon", inhibitors.len());
+    
     Ok(())
 }
 
@@ -78,31 +260,24 @@ async fn run_daemon() -> Result<()> {
     info!("Starting logind-idle-control daemon for session {} ({})", 
         session.id, session.path);
     
-    let state = Arc::new(Mutex::new(State::Disabled));
-    let inhibitor_lock = Arc::new(Mutex::new(None::<dbus::InhibitorLock>));
-    let session_info = Arc::new(session.clone());
+    let daemon = Daemon::new(config.clone(), session.clone());
+    daemon.restore().await?;
     
-    {
-        let mut s = state.lock().await;
-        *s = State::load().unwrap_or(State::Disabled);
-        s.save()?;
-        info!("Initial state: {} (state file: {:?})", *s, State::state_path());
-    }
+    let _service_connection = dbus::serve(
+        &session,
+        service::IdleControlService::new(daemon.clone()),
+    ).await?;
     
-    let state_clone = Arc::clone(&state);
-    let inhibitor_clone = Arc::clone(&inhibitor_lock);
-    let session_for_control = (*session_info).clone();
+    let daemon_for_control = daemon.clone();
+    let session_for_control = session.clone();
     
     let control_handle = tokio::spawn(async move {
-        let session_clone = session_for_control.clone();
-        if let Err(e) = dbus::listen_signals(&session_for_control, move |signal_name| {
+        if let Err(e) = dbus::listen_signals(&session_for_control, move |signal_name, reason| {
             let signal_owned = signal_name.to_string();
-            let state = Arc::clone(&state_clone);
-            let inhibitor = Arc::clone(&inhibitor_clone);
-            let session = session_clone.clone();
+            let daemon = daemon_for_control.clone();
             
             tokio::spawn(async move {
-                if let Err(e) = handle_signal(&signal_owned, state, inhibitor, Arc::new(session)).await {
+                if let Err(e) = daemon.handle_signal(&signal_owned, reason).await {
                     error!("Error handling signal {}: {}", signal_owned, e);
                 }
             });
@@ -112,21 +287,30 @@ async fn run_daemon() -> Result<()> {
         }
     });
     
-    let lock_handle = if config.disable_on_lock {
-        let state_clone = Arc::clone(&state);
-        let inhibitor_clone = Arc::clone(&inhibitor_lock);
-        let session_for_lock = (*session_info).clone();
+    if !config.disable_on_lock {
+        info!("Disable on lock is disabled in config");
+    }
+    
+    // Lock state is always tracked so rules and scripts can use `session:locked`
+    let lock_handle = {
+        let daemon_for_lock = daemon.clone();
+        let session_for_lock = session.clone();
+        let disable_on_lock = config.disable_on_lock;
         
-        Some(tokio::spawn(async move {
-            let session_clone = session_for_lock.clone();
+        tokio::spawn(async move {
             if let Err(e) = dbus::listen_lock_signals(&session_for_lock, move || {
-                let state = Arc::clone(&state_clone);
-                let inhibitor = Arc::clone(&inhibitor_clone);
-                let session = session_clone.clone();
+                let daemon = daemon_for_lock.clone();
                 
                 tokio::spawn(async move {
-                    info!("Lock detected, disabling idle inhibitor");
-                    if let Err(e) = handle_signal("Disable", state, inhibitor, Arc::new(session)).await {
+                    if let Err(e) = daemon.set_fact(LOCKED_FACT, true).await {
+                        error!("Error recording lock state: {}", e);
+                    }
+                    if !disable_on_lock {
+                        info!("Lock detected");
+                        return;
+                    }
+                    info!("Lock detected, releasing manual holds");
+                    if let Err(e) = daemon.release_user_holds().await {
                         error!("Error handling lock signal: {}", e);
                     }
                 });
@@ -134,18 +318,23 @@ async fn run_daemon() -> Result<()> {
             .await {
                 warn!("Lock signal listener exited: {}", e);
             }
-        }))
-    } else {
-        info!("Disable on lock is disabled in config");
-        None
+        })
     };
     
     let unlock_handle = {
-        let session_for_unlock = (*session_info).clone();
+        let daemon_for_unlock = daemon.clone();
+        let session_for_unlock = session.clone();
         
         tokio::spawn(async move {
             if let Err(e) = dbus::listen_unlock_signals(&session_for_unlock, move || {
-                info!("Unlock detected");
+                let daemon = daemon_for_unlock.clone();
+                
+                tokio::spawn(async move {
+                    info!("Unlock detected");
+                    if let Err(e) = daemon.set_fact(LOCKED_FACT, false).await {
+                        error!("Error recording lock state: {}", e);
+                    }
+                });
             })
             .await {
                 warn!("Unlock signal listener exited: {}", e);
@@ -153,67 +342,199 @@ async fn run_daemon() -> Result<()> {
         })
     };
     
-    tokio::signal::ctrl_c().await?;
-    info!("Received shutdown signal");
+    let retry_handle = if config.acquire_retry_secs > 0 {
+        let daemon = daemon.clone();
+        let period = Duration::from_secs(config.acquire_retry_secs);
+        
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                if let Err(e) = daemon.retry_failed().await {
+                    error!("Error retrying inhibitor acquisition: {}", e);
+                }
+            }
+        }))
+    } else {
+        None
+    };
     
-    control_handle.abort();
-    if let Some(handle) = lock_handle {
-        handle.abort();
+    // Automatic hold sources
+    let mut watcher_handles = Vec::new();
+    
+    if !config.process.rules.is_empty() {
+        let process_config = config.process.clone();
+        watcher_handles.push(daemon.spawn_watcher("Process watcher", process::HOLD_KIND, move |daemon| {
+            process::watch(daemon, process_config.clone())
+        }));
     }
-    unlock_handle.abort();
     
-    let mut lock = inhibitor_lock.lock().await;
-    *lock = None;
+    if config.media.enabled {
+        let media_config = config.media.clone();
+        watcher_handles.push(daemon.spawn_watcher("MPRIS watcher", mpris::HOLD_SOURCE, move |daemon| {
+            mpris::watch(daemon, media_config.clone())
+        }));
+    }
     
-    Ok(())
-}
-
-async fn handle_signal(
-    signal_name: &str,
-    state: Arc<Mutex<State>>,
-    inhibitor: Arc<Mutex<Option<dbus::InhibitorLock>>>,
-    session: Arc<logind_idle_control::SessionInfo>,
-) -> Result<()> {
-    info!("Received D-Bus signal: {}", signal_name);
-    
-    let mut current_state = state.lock().await;
-    
-    let new_state = match signal_name {
-        "Enable" => State::Enabled,
-        "Disable" => State::Disabled,
-        "Toggle" => current_state.toggle(),
-        _ => return Ok(()),
-    };
+    if config.compositor.enabled {
+        let compositor_config = config.compositor.clone();
+        watcher_handles.push(daemon.spawn_watcher("Compositor watcher", compositor::HOLD_KIND, move |daemon| {
+            compositor::watch(daemon, compositor_config.clone())
+        }));
+    }
     
-    *current_state = new_state;
-    current_state.save()?;
+    if config.power.enabled {
+        let power_config = config.power.clone();
+        watcher_handles.push(daemon.spawn_watcher("Power watcher", power::SUPPRESSION_SOURCE, move |daemon| {
+            power::watch(daemon, power_config.clone())
+        }));
+    }
     
-    info!("State changed to: {}", new_state);
+    if config.schedule.enabled {
+        let schedule_config = config.schedule.clone();
+        watcher_handles.push(daemon.spawn_watcher("Schedule watcher", schedule::HOLD_KIND, move |daemon| {
+            schedule::watch(daemon, schedule_config.clone())
+        }));
+    }
     
-    let mut lock_guard = inhibitor.lock().await;
-    if new_state.is_enabled() {
-        if lock_guard.is_none() {
-            match dbus::InhibitorLock::acquire().await {
-                Ok(lock) => {
-                    *lock_guard = Some(lock);
+    if config.calendar.enabled {
+        let calendar_config = config.calendar.clone();
+        watcher_handles.push(daemon.spawn_watcher("Calendar watcher", calendar::HOLD_KIND, move |daemon| {
+            calendar::watch(daemon, calendar_config.clone())
+        }));
+    }
+    
+    if config.devices.enabled {
+        let devices_config = config.devices.clone();
+        watcher_handles.push(daemon.spawn_watcher("Capture device watcher", devices::HOLD_KIND, move |daemon| {
+            devices::watch(daemon, devices_config.clone())
+        }));
+    }
+    
+    if config.network.enabled {
+        let network_config = config.network.clone();
+        watcher_handles.push(daemon.spawn_watcher("Network watcher", network::HOLD_SOURCE, move |daemon| {
+            network::watch(daemon, network_config.clone())
+        }));
+    }
+    
+    if config.load.enabled {
+        let load_config = config.load.clone();
+        watcher_handles.push(daemon.spawn_watcher("Load watcher", load::HOLD_SOURCE, move |daemon| {
+            load::watch(daemon, load_config.clone())
+        }));
+    }
+    
+    if config.systemd.enabled {
+        let systemd_config = config.systemd.clone();
+        watcher_handles.push(daemon.spawn_watcher("systemd unit watcher", systemd::HOLD_KIND, move |daemon| {
+            systemd::watch(daemon, systemd_config.clone())
+        }));
+    }
+    
+    if config.remote.enabled {
+        let remote_config = config.remote.clone();
+        watcher_handles.push(daemon.spawn_watcher("Remote access watcher", remote::HOLD_KIND, move |daemon| {
+            remote::watch(daemon, remote_config.clone())
+        }));
+    }
+    
+    if config.gamepad.enabled {
+        let gamepad_config = config.gamepad.clone();
+        watcher_handles.push(daemon.spawn_watcher("Gamepad watcher", gamepad::HOLD_SOURCE, move |daemon| {
+            gamepad::watch(daemon, gamepad_config.clone())
+        }));
+    }
+    
+    if config.usb.enabled {
+        let usb_config = config.usb.clone();
+        watcher_handles.push(daemon.spawn_watcher("USB watcher", usb::HOLD_KIND, move |daemon| {
+            usb::watch(daemon, usb_config.clone())
+        }));
+    }
+    
+    if config.bluetooth.enabled {
+        let bluetooth_config = config.bluetooth.clone();
+        watcher_handles.push(daemon.spawn_watcher("Bluetooth watcher", bluetooth::HOLD_KIND, move |daemon| {
+            bluetooth::watch(daemon, bluetooth_config.clone())
+        }));
+    }
+    
+    if config.dock.enabled {
+        let dock_config = config.dock.clone();
+        watcher_handles.push(daemon.spawn_watcher("Dock watcher", dock::HOLD_SOURCE, move |daemon| {
+            dock::watch(daemon, dock_config.clone())
+        }));
+    }
+    
+    if !config.plugins.is_empty() {
+        let plugin_configs = config.plugins.clone();
+        watcher_handles.push(daemon.spawn_watcher("Plugin supervisor", plugins::FACT_KIND, move |daemon| {
+            plugins::watch(daemon, plugin_configs.clone())
+        }));
+    }
+    
+    if config.scripts.enabled {
+        let scripts_config = config.scripts.clone();
+        watcher_handles.push(daemon.spawn_watcher("Script watcher", scripting::HOLD_KIND, move |daemon| {
+            scripting::watch(daemon, scripts_config.clone())
+        }));
+    }
+    
+    let reconcile_now = Arc::new(Notify::new());
+    
+    let logind_watch_handle = {
+        let reconcile_now = Arc::clone(&reconcile_now);
+        
+        tokio::spawn(async move {
+            if let Err(e) = dbus::listen_logind_restarts(move || {
+                reconcile_now.notify_one();
+            })
+            .await {
+                warn!("logind owner listener exited: {}", e);
+            }
+        })
+    };
+    
+    let reconcile_handle = {
+        let daemon = daemon.clone();
+        let reconcile_now = Arc::clone(&reconcile_now);
+        let period = Duration::from_secs(config.reconcile_interval_secs);
+        
+        tokio::spawn(async move {
+            let mut interval = (!period.is_zero()).then(|| tokio::time::interval(period));
+            loop {
+                match interval.as_mut() {
+                    Some(interval) => tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = reconcile_now.notified() => {}
+                    },
+                    None => reconcile_now.notified().await,
                 }
-                Err(e) => {
-                    error!("Failed to acquire inhibitor lock: {}", e);
+                
+                if let Err(e) = daemon.reconcile().await {
+                    warn!("Inhibitor reconciliation failed: {}", e);
                 }
             }
-        }
-    } else {
-        *lock_guard = None;
-    }
+        })
+    };
     
-    drop(lock_guard);
-    drop(current_state);
+    tokio::signal::ctrl_c().await?;
+    info!("Received shutdown signal");
     
-    if let Err(e) = dbus::emit_state_changed(&session, new_state.is_enabled()).await {
-        error!("Failed to emit StateChanged signal: {}", e);
+    control_handle.abort();
+    lock_handle.abort();
+    unlock_handle.abort();
+    if let Some(handle) = retry_handle {
+        handle.abort();
+    }
+    for handle in watcher_handles {
+        handle.abort();
     }
+    logind_watch_handle.abort();
+    reconcile_handle.abort();
+    
+    daemon.shutdown().await;
     
     Ok(())
 }
-
-
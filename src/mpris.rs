@@ -0,0 +1,172 @@
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use zbus::proxy::CacheProperties;
+use zbus::zvariant::OwnedValue;
+use zbus::{proxy, Connection};
+use crate::config::MediaConfig;
+use crate::daemon::Daemon;
+
+/// Hold source for media playback.
+pub const HOLD_SOURCE: &str = "media";
+
+const MPRIS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+
+const VIDEO_EXTENSIONS: &[&str] = &[
+    "mp4", "m4v", "mkv", "webm", "avi", "mov", "wmv", "flv", "mpg", "mpeg", "ts", "ogv",
+];
+
+#[proxy(
+    interface = "org.mpris.MediaPlayer2",
+    default_path = "/org/mpris/MediaPlayer2"
+)]
+trait MediaPlayer2 {
+    #[zbus(property)]
+    fn identity(&self) -> zbus::Result<String>;
+}
+
+#[proxy(
+    interface = "org.mpris.MediaPlayer2.Player",
+    default_path = "/org/mpris/MediaPlayer2"
+)]
+trait Player {
+    #[zbus(property)]
+    fn playback_status(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn metadata(&self) -> zbus::Result<HashMap<String, OwnedValue>>;
+}
+
+/// Whether a player may hold the inhibitor, by bus name (with or without the
+/// `org.mpris.MediaPlayer2.` prefix, instance suffixes ignored) or `Identity`.
+pub fn player_allowed(config: &MediaConfig, bus_name: &str, identity: &str) -> bool {
+    let matches = |entry: &String| {
+        let short = bus_name.strip_prefix(MPRIS_PREFIX).unwrap_or(bus_name);
+        entry == bus_name
+            || short == entry
+            || short.starts_with(&format!("{}.", entry))
+            || entry.eq_ignore_ascii_case(identity)
+    };
+
+    if config.deny.iter().any(matches) {
+        return false;
+    }
+    config.allow.is_empty() || config.allow.iter().any(matches)
+}
+
+/// Best-effort guess from the track URL; MPRIS has no "has video" flag.
+pub fn is_video(metadata: &HashMap<String, OwnedValue>) -> bool {
+    let Some(url) = metadata
+        .get("xesam:url")
+        .and_then(|value| <&str>::try_from(&**value).ok())
+    else {
+        return false;
+    };
+
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    path.rsplit_once('.')
+        .map(|(_, ext)| VIDEO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/// Identities of the allowed players that are currently playing.
+async fn playing_players(connection: &Connection, config: &MediaConfig) -> Result<Vec<String>> {
+    let names = zbus::fdo::DBusProxy::new(connection)
+        .await?
+        .list_names()
+        .await
+        .context("Failed to list session bus names")?;
+
+    let mut playing = Vec::new();
+    for name in names.iter().filter(|name| name.starts_with(MPRIS_PREFIX)) {
+        let root = MediaPlayer2Proxy::builder(connection)
+            .destination(name.as_str())?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+        let identity = root.identity().await.unwrap_or_else(|_| name.to_string());
+
+        if !player_allowed(config, name, &identity) {
+            continue;
+        }
+
+        let player = PlayerProxy::builder(connection)
+            .destination(name.as_str())?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+
+        // Players can vanish between ListNames and the property read
+        if player.playback_status().await.ok().as_deref() != Some("Playing") {
+            continue;
+        }
+
+        if config.video_only && !player.metadata().await.map(|m| is_video(&m)).unwrap_or(false) {
+            continue;
+        }
+
+        playing.push(identity);
+    }
+
+    Ok(playing)
+}
+
+/// Holds an idle inhibitor while any allowed MPRIS player reports `Playing`.
+pub async fn watch(daemon: Daemon, config: MediaConfig) -> Result<()> {
+    use futures_util::StreamExt;
+    use zbus::MatchRule;
+
+    let connection = Connection::session()
+        .await
+        .context("Failed to connect to session D-Bus")?;
+
+    let owner_rule = MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .sender("org.freedesktop.DBus")?
+        .interface("org.freedesktop.DBus")?
+        .member("NameOwnerChanged")?
+        .arg0ns("org.mpris.MediaPlayer2")?
+        .build();
+
+    let properties_rule = MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .path("/org/mpris/MediaPlayer2")?
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .build();
+
+    let proxy = zbus::fdo::DBusProxy::new(&connection).await?;
+    proxy.add_match_rule(owner_rule).await?;
+    proxy.add_match_rule(properties_rule).await?;
+
+    let mut stream = zbus::MessageStream::from(&connection);
+
+    tracing::info!("Watching MPRIS players (video only: {})", config.video_only);
+
+    let mut last: Option<Vec<String>> = None;
+    loop {
+        let playing = playing_players(&connection, &config).await?;
+
+        if last.as_ref() != Some(&playing) {
+            if playing.is_empty() {
+                daemon.release_hold(HOLD_SOURCE).await?;
+            } else {
+                daemon.set_hold(HOLD_SOURCE, &format!("{} playing", playing.join(", "))).await?;
+            }
+            last = Some(playing);
+        }
+
+        // Any owner or property change re-evaluates every player
+        loop {
+            match stream.next().await {
+                Some(Ok(msg)) => {
+                    let member = msg.header().member().map(|m| m.to_string());
+                    if matches!(member.as_deref(), Some("NameOwnerChanged" | "PropertiesChanged")) {
+                        break;
+                    }
+                }
+                Some(Err(_)) => continue,
+                None => bail!("Session bus connection closed"),
+            }
+        }
+    }
+}
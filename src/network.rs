@@ -0,0 +1,312 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use crate::config::NetworkConfig;
+use crate::daemon::Daemon;
+use crate::holds;
+use crate::hysteresis::Hysteresis;
+
+/// Hold source, also used for the throughput reading.
+pub const HOLD_SOURCE: &str = "network";
+
+/// Byte counters for one interface from /proc/net/dev.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceCounters {
+    pub name: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// Parses the `/proc/net/dev` table.
+pub fn parse_net_dev(content: &str) -> Vec<InterfaceCounters> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let (name, stats) = line.split_once(':')?;
+            let fields: Vec<u64> = stats
+                .split_whitespace()
+                .map(|field| field.parse().ok())
+                .collect::<Option<_>>()?;
+            // rx: bytes packets errs drop fifo frame compressed multicast, then tx
+            Some(InterfaceCounters {
+                name: name.trim().to_string(),
+                rx_bytes: *fields.first()?,
+                tx_bytes: *fields.get(8)?,
+            })
+        })
+        .collect()
+}
+
+/// (rx, tx) bytes per interface
+type Counters = BTreeMap<String, (u64, u64)>;
+
+/// Turns successive counter readings into a combined rx+tx rate.
+pub struct ThroughputSampler {
+    net_dev: PathBuf,
+    interfaces: Vec<String>,
+    /// Previous sample time and counters
+    last: Option<(Instant, Counters)>,
+}
+
+impl ThroughputSampler {
+    /// Samples `interfaces`, or every interface except loopback when empty.
+    pub fn new(proc_root: impl Into<PathBuf>, interfaces: Vec<String>) -> Self {
+        Self {
+            net_dev: proc_root.into().join("net/dev"),
+            interfaces,
+            last: None,
+        }
+    }
+
+    fn counters(&self) -> Result<Counters> {
+        let content = std::fs::read_to_string(&self.net_dev)
+            .with_context(|| format!("Failed to read {}", self.net_dev.display()))?;
+
+        Ok(parse_net_dev(&content)
+            .into_iter()
+            .filter(|iface| {
+                if self.interfaces.is_empty() {
+                    iface.name != "lo"
+                } else {
+                    self.interfaces.contains(&iface.name)
+                }
+            })
+            .map(|iface| (iface.name, (iface.rx_bytes, iface.tx_bytes)))
+            .collect())
+    }
+
+    /// Bytes per second since the previous call; `None` on the first sample.
+    pub fn sample(&mut self, now: Instant) -> Result<Option<f64>> {
+        let counters = self.counters()?;
+        let rate = self.last.as_ref().map(|(at, previous)| {
+            let elapsed = now.duration_since(*at).as_secs_f64().max(f64::EPSILON);
+            let transferred: u64 = counters
+                .iter()
+                .map(|(name, &(rx, tx))| match previous.get(name) {
+                    Some(&(rx_before, tx_before)) => delta(rx_before, rx) + delta(tx_before, tx),
+                    // A new interface only contributes from its next sample
+                    None => 0,
+                })
+                .sum();
+            transferred as f64 / elapsed
+        });
+
+        self.last = Some((now, counters));
+        Ok(rate)
+    }
+}
+
+/// Bytes counted between two readings of one counter. Counters restart from zero
+/// when an interface goes away and comes back, or wrap on 32-bit kernels; then
+/// only the count since the restart is known.
+fn delta(before: u64, now: u64) -> u64 {
+    if now >= before {
+        now - before
+    } else {
+        now
+    }
+}
+
+/// Applies the enter and release thresholds to successive rates, debounced
+/// by [`Hysteresis`].
+pub struct TransferDetector {
+    threshold: f64,
+    release: f64,
+    state: Hysteresis,
+}
+
+impl TransferDetector {
+    pub fn new(threshold: f64, release: f64, sustain: Duration, cooldown: Duration) -> Self {
+        Self { threshold, release, state: Hysteresis::new(sustain, cooldown) }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state.is_active()
+    }
+
+    /// Feeds the latest rate and returns whether a transfer is in progress.
+    pub fn update(&mut self, rate: f64, now: Instant) -> bool {
+        let limit = if self.state.is_active() { self.release } else { self.threshold };
+        self.state.update(rate >= limit, now)
+    }
+}
+
+pub fn format_rate(bytes_per_sec: f64) -> String {
+    const UNITS: [&str; 4] = ["B/s", "KiB/s", "MiB/s", "GiB/s"];
+    let mut value = bytes_per_sec;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Holds the configured inhibitor while throughput stays above the threshold,
+/// and releases it once it has stayed below the release threshold for a while.
+pub async fn watch(daemon: Daemon, config: NetworkConfig) -> Result<()> {
+    holds::validate_what(&config.what)?;
+
+    let threshold = config.threshold_kib_per_sec as f64 * 1024.0;
+    let release = config
+        .release_kib_per_sec
+        .unwrap_or(config.threshold_kib_per_sec / 2) as f64
+        * 1024.0;
+
+    let mut sampler = ThroughputSampler::new("/proc", config.interfaces.clone());
+    let mut detector = TransferDetector::new(
+        threshold,
+        release,
+        Duration::from_secs(config.sustain_secs),
+        Duration::from_secs(config.cooldown_secs),
+    );
+
+    tracing::info!(
+        "Watching network throughput on {} (threshold {})",
+        if config.interfaces.is_empty() { "all interfaces".to_string() } else { config.interfaces.join(", ") },
+        format_rate(threshold)
+    );
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.sample_interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+
+        let now = Instant::now();
+        let Some(rate) = sampler.sample(now)? else {
+            continue;
+        };
+        daemon.set_reading(HOLD_SOURCE, format_rate(rate)).await;
+
+        match (detector.is_active(), detector.update(rate, now)) {
+            (false, true) => {
+                tracing::info!("Network transfer at {}, holding {}", format_rate(rate), config.what);
+                daemon.set_hold_what(HOLD_SOURCE, &config.what, "Network transfer in progress").await?;
+            }
+            (true, false) => {
+                tracing::info!("Network transfer dropped to {}, releasing", format_rate(rate));
+                daemon.release_hold(HOLD_SOURCE).await?;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NET_DEV: &str = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:  1000      10    0    0    0     0          0         0  1000      10    0    0    0     0       0          0
+  eth0: 5000      50    0    0    0     0          0         0   700       7    0    0    0     0       0          0
+wlan0:18446744073709551615 1 0 0 0 0 0 0 1 1 0 0 0 0 0 0
+";
+
+    fn write_net_dev(root: &std::path::Path, counters: &[(&str, u64, u64)]) {
+        let mut content = String::from("Inter-| header\n face | header\n");
+        for (name, rx, tx) in counters {
+            content.push_str(&format!("{:>6}: {} 0 0 0 0 0 0 0 {} 0 0 0 0 0 0 0\n", name, rx, tx));
+        }
+        std::fs::create_dir_all(root.join("net")).unwrap();
+        std::fs::write(root.join("net/dev"), content).unwrap();
+    }
+
+    #[test]
+    fn parses_net_dev() {
+        let counters = parse_net_dev(NET_DEV);
+        assert_eq!(
+            counters,
+            vec![
+                InterfaceCounters { name: "lo".into(), rx_bytes: 1000, tx_bytes: 1000 },
+                InterfaceCounters { name: "eth0".into(), rx_bytes: 5000, tx_bytes: 700 },
+                InterfaceCounters { name: "wlan0".into(), rx_bytes: u64::MAX, tx_bytes: 1 },
+            ]
+        );
+        assert!(parse_net_dev("eth0: 1 2 3\nbogus: a b c d e f g h i j\n").is_empty());
+    }
+
+    #[test]
+    fn samples_rates() {
+        let dir = tempfile::tempdir().unwrap();
+        let start = Instant::now();
+        let mut sampler = ThroughputSampler::new(dir.path(), Vec::new());
+
+        write_net_dev(dir.path(), &[("lo", 0, 0), ("eth0", 1000, 1000)]);
+        assert_eq!(sampler.sample(start).unwrap(), None);
+
+        // Loopback traffic doesn't count
+        write_net_dev(dir.path(), &[("lo", 1_000_000, 1_000_000), ("eth0", 3000, 2000)]);
+        assert_eq!(sampler.sample(start + Duration::from_secs(2)).unwrap(), Some(1500.0));
+
+        let mut only_lo = ThroughputSampler::new(dir.path(), vec!["lo".into()]);
+        only_lo.sample(start).unwrap();
+        write_net_dev(dir.path(), &[("lo", 1_000_100, 1_000_000), ("eth0", 3000, 2000)]);
+        assert_eq!(only_lo.sample(start + Duration::from_secs(1)).unwrap(), Some(100.0));
+    }
+
+    #[test]
+    fn counter_resets_and_wraps() {
+        let dir = tempfile::tempdir().unwrap();
+        let start = Instant::now();
+        let mut sampler = ThroughputSampler::new(dir.path(), Vec::new());
+
+        write_net_dev(dir.path(), &[("eth0", 4_000_000_000, 0), ("wlan0", 10_000, 0)]);
+        sampler.sample(start).unwrap();
+
+        // eth0's 32-bit counter wrapped to 500; wlan0 kept counting
+        write_net_dev(dir.path(), &[("eth0", 500, 0), ("wlan0", 11_000, 0)]);
+        assert_eq!(sampler.sample(start + Duration::from_secs(1)).unwrap(), Some(1500.0));
+
+        // A new interface only counts once it has a previous sample
+        write_net_dev(dir.path(), &[("eth0", 600, 0), ("wlan0", 11_000, 0), ("usb0", 9_999, 0)]);
+        assert_eq!(sampler.sample(start + Duration::from_secs(2)).unwrap(), Some(100.0));
+
+        write_net_dev(dir.path(), &[("eth0", 600, 0), ("wlan0", 11_000, 0), ("usb0", 10_099, 0)]);
+        assert_eq!(sampler.sample(start + Duration::from_secs(3)).unwrap(), Some(100.0));
+    }
+
+    #[test]
+    fn wraps_each_direction_separately() {
+        let dir = tempfile::tempdir().unwrap();
+        let start = Instant::now();
+        let mut sampler = ThroughputSampler::new(dir.path(), Vec::new());
+
+        write_net_dev(dir.path(), &[("eth0", 4_294_967_000, 50_000_000_000)]);
+        sampler.sample(start).unwrap();
+
+        // rx wrapped to 200 while tx moved on by 1000; the large tx total must
+        // not be counted again
+        write_net_dev(dir.path(), &[("eth0", 200, 50_000_001_000)]);
+        assert_eq!(sampler.sample(start + Duration::from_secs(1)).unwrap(), Some(1200.0));
+
+        // And the other way round
+        write_net_dev(dir.path(), &[("eth0", 700, 300)]);
+        assert_eq!(sampler.sample(start + Duration::from_secs(2)).unwrap(), Some(800.0));
+    }
+
+    #[test]
+    fn detects_sustained_transfers() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut detector = TransferDetector::new(1000.0, 500.0, Duration::from_secs(10), Duration::from_secs(30));
+
+        // A short burst isn't enough
+        assert!(!detector.update(2000.0, at(0)));
+        assert!(!detector.update(2000.0, at(5)));
+        assert!(!detector.update(100.0, at(8)));
+        assert!(!detector.update(2000.0, at(12)));
+        assert!(!detector.update(2000.0, at(21)));
+        assert!(detector.update(2000.0, at(22)));
+
+        // Between the two thresholds the transfer continues
+        assert!(detector.update(700.0, at(40)));
+        assert!(detector.update(400.0, at(50)));
+        assert!(detector.update(600.0, at(70)));
+        assert!(detector.update(400.0, at(80)));
+        assert!(detector.update(400.0, at(109)));
+        assert!(!detector.update(400.0, at(110)));
+        assert!(!detector.is_active());
+    }
+}
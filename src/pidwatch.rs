@@ -0,0 +1,44 @@
+use anyhow::{Context, Result};
+use std::os::fd::{FromRawFd, OwnedFd};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+/// Waits for an arbitrary (non-child) process to exit using a pidfd.
+///
+/// The pidfd is opened up front so that a PID which is already gone is an error
+/// rather than a hold that is never released.
+pub struct PidWatch {
+    pid: u32,
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl PidWatch {
+    pub fn open(pid: u32) -> Result<Self> {
+        let raw = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+        if raw < 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to open pidfd for PID {}", pid));
+        }
+        
+        // SAFETY: pidfd_open returned a fresh descriptor that nothing else owns
+        let fd = unsafe { OwnedFd::from_raw_fd(raw as i32) };
+        let fd = AsyncFd::with_interest(fd, Interest::READABLE)
+            .context("Failed to register pidfd with the runtime")?;
+        
+        Ok(Self { pid, fd })
+    }
+    
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+    
+    /// Resolves once the process has exited. A pidfd becomes readable on exit.
+    pub async fn exited(&self) -> Result<()> {
+        let _guard = self
+            .fd
+            .readable()
+            .await
+            .with_context(|| format!("Failed to poll pidfd for PID {}", self.pid))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,332 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::process::{ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use crate::config::PluginConfig;
+use crate::daemon::{Daemon, INITIAL_BACKOFF, MAX_BACKOFF, STABLE_RUN};
+
+/// Fact, hold and reading kind for plugins; facts are `plugin:<plugin name>:<condition>`
+/// and holds `plugin:<plugin name>:<hold>`.
+pub const FACT_KIND: &str = "plugin";
+
+/// One line of plugin output. Unknown fields are ignored.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Update {
+    /// `{"condition": "vpn", "value": true}`
+    Condition { condition: String, value: serde_json::Value },
+    /// `{"hold": "backup", "reason": "Backup running"}`
+    Hold {
+        hold: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// `{"release": "backup"}`
+    Release { release: String },
+}
+
+pub fn parse_update(line: &str) -> Result<Update> {
+    let update: Update = serde_json::from_str(line).context("Invalid plugin update")?;
+    let name = match &update {
+        Update::Condition { condition, .. } => condition,
+        Update::Hold { hold, .. } => hold,
+        Update::Release { release } => release,
+    };
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        bail!("Invalid name '{}'", name);
+    }
+    Ok(update)
+}
+
+/// A plugin's latest values and holds, shared by its facts and its status reading.
+struct PluginState<'a> {
+    daemon: &'a Daemon,
+    name: &'a str,
+    values: BTreeMap<String, serde_json::Value>,
+    holds: BTreeSet<String>,
+}
+
+impl PluginState<'_> {
+    /// Fact or hold source name for one of this plugin's conditions or holds.
+    fn source(&self, name: &str) -> String {
+        format!("{}:{}:{}", FACT_KIND, self.name, name)
+    }
+
+    async fn update(&mut self, update: Update) -> Result<()> {
+        match update {
+            Update::Condition { condition, value } => {
+                // Only booleans can drive rules; other values are reported in status
+                let truthy = value.as_bool() == Some(true);
+                self.daemon.set_fact(&self.source(&condition), truthy).await?;
+                self.values.insert(condition, value);
+            }
+            Update::Hold { hold, reason } => {
+                let reason = reason.unwrap_or_else(|| format!("Plugin {}: {}", self.name, hold));
+                self.daemon.set_hold(&self.source(&hold), &reason).await?;
+                self.holds.insert(hold);
+            }
+            Update::Release { release } => {
+                if self.holds.remove(&release) {
+                    self.daemon.release_hold(&self.source(&release)).await?;
+                }
+            }
+        }
+        self.report(None).await;
+        Ok(())
+    }
+
+    /// Drops every value and hold, e.g. when the plugin died, so stale conditions
+    /// don't linger.
+    async fn clear(&mut self, status: &str) -> Result<()> {
+        for condition in std::mem::take(&mut self.values).into_keys() {
+            self.daemon.set_fact(&self.source(&condition), false).await?;
+        }
+        for hold in std::mem::take(&mut self.holds) {
+            self.daemon.release_hold(&self.source(&hold)).await?;
+        }
+        self.report(Some(status)).await;
+        Ok(())
+    }
+
+    async fn report(&self, status: Option<&str>) {
+        let mut parts: Vec<String> = self
+            .values
+            .iter()
+            .map(|(condition, value)| format!("{}={}", condition, value))
+            .chain(self.holds.iter().map(|hold| format!("holding {}", hold)))
+            .collect();
+        if let Some(status) = status {
+            parts.push(format!("({})", status));
+        }
+        self.daemon
+            .set_reading(&format!("{}:{}", FACT_KIND, self.name), parts.join(", "))
+            .await;
+    }
+}
+
+/// Runs the plugin once, applying its updates until it exits or goes silent.
+async fn run_once(state: &mut PluginState<'_>, config: &PluginConfig) -> Result<ExitStatus> {
+    let Some((program, args)) = config.command.split_first() else {
+        bail!("Plugin '{}' has no command", config.name);
+    };
+
+    let mut child = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to start plugin '{}'", config.name))?;
+
+    let stdout = child.stdout.take().context("Plugin stdout not captured")?;
+    let mut lines = BufReader::new(stdout).lines();
+    let timeout = Duration::from_secs(config.timeout_secs);
+
+    loop {
+        let line = if timeout.is_zero() {
+            lines.next_line().await
+        } else {
+            match tokio::time::timeout(timeout, lines.next_line()).await {
+                Ok(line) => line,
+                Err(_) => {
+                    child.kill().await.ok();
+                    bail!("sent no update for {}s and was killed", config.timeout_secs);
+                }
+            }
+        };
+
+        match line.context("Failed to read plugin output")? {
+            Some(line) if line.trim().is_empty() => {}
+            Some(line) => match parse_update(&line) {
+                Ok(update) => state.update(update).await?,
+                Err(e) => tracing::warn!("Plugin '{}': {:#}: {}", config.name, e, line),
+            },
+            None => break,
+        }
+    }
+
+    child.wait().await.context("Failed to wait for plugin")
+}
+
+/// Keeps one plugin running, restarting it with exponential backoff.
+async fn supervise(daemon: Daemon, config: PluginConfig) -> Result<()> {
+    let mut state = PluginState {
+        daemon: &daemon,
+        name: &config.name,
+        values: BTreeMap::new(),
+        holds: BTreeSet::new(),
+    };
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        let started = Instant::now();
+        tracing::info!("Starting plugin '{}'", config.name);
+
+        let outcome = match run_once(&mut state, &config).await {
+            Ok(status) => format!("exited ({})", status),
+            Err(e) => format!("{:#}", e),
+        };
+        if started.elapsed() >= STABLE_RUN {
+            backoff = INITIAL_BACKOFF;
+        }
+
+        tracing::warn!("Plugin '{}' {}; restarting in {}s", config.name, outcome, backoff.as_secs());
+        state.clear(&format!("restarting: {}", outcome)).await?;
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Runs every configured plugin under supervision.
+pub async fn watch(daemon: Daemon, plugins: Vec<PluginConfig>) -> Result<()> {
+    tracing::info!("Running {} plugin(s)", plugins.len());
+
+    let supervisors = plugins.into_iter().map(|plugin| supervise(daemon.clone(), plugin));
+    for result in futures_util::future::join_all(supervisors).await {
+        result?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Config;
+    use serde_json::json;
+
+    #[test]
+    fn parses_updates() {
+        assert_eq!(
+            parse_update(r#"{"condition": "vpn", "value": true}"#).unwrap(),
+            Update::Condition { condition: "vpn".into(), value: json!(true) }
+        );
+        assert_eq!(
+            parse_update(r#"{"condition": "queued_jobs", "value": 3, "unit": "jobs"}"#).unwrap(),
+            Update::Condition { condition: "queued_jobs".into(), value: json!(3) }
+        );
+        assert_eq!(
+            parse_update(r#"{"hold": "backup", "reason": "Backup running"}"#).unwrap(),
+            Update::Hold { hold: "backup".into(), reason: Some("Backup running".into()) }
+        );
+        assert_eq!(
+            parse_update(r#"{"hold": "backup", "since": 1700000000}"#).unwrap(),
+            Update::Hold { hold: "backup".into(), reason: None }
+        );
+        assert_eq!(
+            parse_update(r#"{"release": "backup"}"#).unwrap(),
+            Update::Release { release: "backup".into() }
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_updates() {
+        for line in [
+            "vpn=true",
+            r#"{"condition": "vpn", "value": true"#,
+            r#"["vpn", true]"#,
+            r#"{"condition": "vpn"}"#,
+            r#"{"value": true}"#,
+            r#"{"hold": 1}"#,
+            r#"{"release": null}"#,
+            r#"{"condition": "", "value": true}"#,
+            r#"{"condition": "v p n", "value": true}"#,
+            r#"{"hold": "", "reason": "x"}"#,
+            r#"{"release": "two words"}"#,
+        ] {
+            assert!(parse_update(line).is_err(), "{}", line);
+        }
+    }
+
+    /// Polls until `check` passes on the daemon, failing after a few seconds.
+    async fn wait_for(daemon: &Daemon, what: &str, check: impl AsyncFn(&Daemon) -> bool) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !check(daemon).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("timed out waiting for {}", what));
+    }
+
+    #[tokio::test]
+    async fn restarts_silent_plugins_and_clears_their_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let starts = dir.path().join("starts");
+        let script = format!(
+            "echo started >> '{}'\n\
+             echo '{{\"condition\": \"vpn\", \"value\": true}}'\n\
+             echo '{{\"hold\": \"job\", \"reason\": \"Render job\"}}'\n\
+             echo 'not json'\n\
+             exec sleep 60\n",
+            starts.display()
+        );
+        let config = PluginConfig {
+            name: "site".into(),
+            command: vec!["sh".into(), "-c".into(), script],
+            timeout_secs: 1,
+        };
+
+        let daemon = Daemon::detached(Config::default());
+        let supervisor = tokio::spawn(supervise(daemon.clone(), config));
+
+        let running = async |daemon: &Daemon| {
+            daemon.readings().await.get("plugin:site").map(String::as_str) == Some("vpn=true, holding job")
+        };
+        wait_for(&daemon, "the plugin's fact and hold", running).await;
+        assert!(daemon.facts().await.contains("plugin:site:vpn"));
+        let holds = daemon.holds().await;
+        let holds: Vec<(&str, &str)> = holds.iter().map(|(source, hold)| (source, hold.reason.as_str())).collect();
+        assert_eq!(holds, [("plugin:site:job", "Render job")]);
+
+        // The plugin goes silent, is killed after the timeout and leaves nothing behind
+        wait_for(&daemon, "the timeout", async |daemon: &Daemon| {
+            daemon.readings().await["plugin:site"].contains("was killed")
+        })
+        .await;
+        assert!(daemon.facts().await.is_empty());
+        assert!(daemon.holds().await.is_empty());
+
+        // and is started again after the backoff
+        wait_for(&daemon, "the restart", running).await;
+        assert_eq!(std::fs::read_to_string(&starts).unwrap().lines().count(), 2);
+
+        supervisor.abort();
+    }
+
+    #[tokio::test]
+    async fn clears_state_when_a_plugin_exits() {
+        let script = "echo '{\"condition\": \"vpn\", \"value\": true}'\n\
+                      echo '{\"hold\": \"a\"}'\n\
+                      echo '{\"hold\": \"b\"}'\n\
+                      echo '{\"release\": \"a\"}'\n\
+                      sleep 0.5\n";
+        let config = PluginConfig {
+            name: "site".into(),
+            command: vec!["sh".into(), "-c".into(), script.into()],
+            timeout_secs: 0,
+        };
+
+        let daemon = Daemon::detached(Config::default());
+        let supervisor = tokio::spawn(supervise(daemon.clone(), config));
+
+        wait_for(&daemon, "the plugin's holds", async |daemon: &Daemon| {
+            let holds = daemon.holds().await;
+            holds.iter().map(|(source, _)| source).eq(["plugin:site:b"])
+        })
+        .await;
+        assert_eq!(daemon.holds().await.iter().next().unwrap().1.reason, "Plugin site: b");
+        assert!(daemon.facts().await.contains("plugin:site:vpn"));
+
+        wait_for(&daemon, "the exit", async |daemon: &Daemon| {
+            daemon.readings().await["plugin:site"].starts_with("(restarting: exited")
+        })
+        .await;
+        assert!(daemon.facts().await.is_empty());
+        assert!(daemon.holds().await.is_empty());
+
+        supervisor.abort();
+    }
+}
@@ -0,0 +1,337 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use zbus::proxy::CacheProperties;
+use zbus::{proxy, Connection};
+use crate::config::PowerConfig;
+use crate::daemon::Daemon;
+
+/// Suppression source for battery policies.
+pub const SUPPRESSION_SOURCE: &str = "power";
+
+/// Hold source for the docked-on-AC policy.
+pub const DOCKED_HOLD_SOURCE: &str = "power:docked";
+
+pub const SYSFS_POWER_SUPPLY: &str = "/sys/class/power_supply";
+
+#[proxy(
+    interface = "org.freedesktop.UPower",
+    default_service = "org.freedesktop.UPower",
+    default_path = "/org/freedesktop/UPower"
+)]
+trait UPower {
+    #[zbus(property)]
+    fn on_battery(&self) -> zbus::Result<bool>;
+}
+
+#[proxy(
+    interface = "org.freedesktop.UPower.Device",
+    default_service = "org.freedesktop.UPower",
+    default_path = "/org/freedesktop/UPower/devices/DisplayDevice"
+)]
+trait UPowerDevice {
+    #[zbus(property)]
+    fn is_present(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn percentage(&self) -> zbus::Result<f64>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PowerStatus {
+    pub on_battery: bool,
+    /// Combined charge of all batteries, if there are any
+    pub battery_percent: Option<f64>,
+    pub docked: bool,
+}
+
+/// What the power policy wants right now.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PowerDecision {
+    pub suppress: Option<String>,
+    pub hold: Option<String>,
+}
+
+pub fn evaluate(config: &PowerConfig, status: &PowerStatus) -> PowerDecision {
+    let mut decision = PowerDecision::default();
+
+    if status.on_battery {
+        if config.ac_only {
+            decision.suppress = Some("On battery power".to_string());
+        }
+
+        if let (Some(min), Some(percent)) = (config.min_battery_percent, status.battery_percent) {
+            if percent < f64::from(min) {
+                decision.suppress = Some(format!("Battery at {:.0}% (below {}%)", percent, min));
+            }
+        }
+    } else if config.enable_on_ac_docked && status.docked {
+        decision.hold = Some("Docked on AC power".to_string());
+    }
+
+    decision
+}
+
+async fn read_upower(connection: &Connection) -> Result<(bool, Option<f64>)> {
+    let upower = UPowerProxy::builder(connection)
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    let on_battery = upower.on_battery().await.context("Failed to read UPower OnBattery")?;
+
+    let device = UPowerDeviceProxy::builder(connection)
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await?;
+    let percent = match device.is_present().await {
+        Ok(true) => device.percentage().await.ok(),
+        _ => None,
+    };
+
+    Ok((on_battery, percent))
+}
+
+/// Reads AC and battery state from a `power_supply` class directory.
+pub fn read_sysfs(root: &Path) -> Result<(bool, Option<f64>)> {
+    let entries = std::fs::read_dir(root)
+        .with_context(|| format!("Failed to read {}", root.display()))?;
+
+    let read = |dir: &PathBuf, name: &str| {
+        std::fs::read_to_string(dir.join(name))
+            .map(|value| value.trim().to_string())
+            .ok()
+    };
+
+    let mut mains_seen = false;
+    let mut mains_online = false;
+    let mut battery_discharging = false;
+    let (mut energy_now, mut energy_full) = (0.0, 0.0);
+    let mut capacities = Vec::new();
+
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        match read(&dir, "type").as_deref() {
+            Some("Mains") | Some("USB") => {
+                mains_seen = true;
+                mains_online |= read(&dir, "online").as_deref() == Some("1");
+            }
+            Some("Battery") => {
+                // Peripheral batteries (mice, headsets) report scope=Device
+                if read(&dir, "scope").as_deref() == Some("Device") {
+                    continue;
+                }
+                battery_discharging |= read(&dir, "status").as_deref() == Some("Discharging");
+
+                let now = read(&dir, "energy_now").or_else(|| read(&dir, "charge_now"));
+                let full = read(&dir, "energy_full").or_else(|| read(&dir, "charge_full"));
+                match (now.and_then(|v| v.parse::<f64>().ok()), full.and_then(|v| v.parse::<f64>().ok())) {
+                    (Some(now), Some(full)) if full > 0.0 => {
+                        energy_now += now;
+                        energy_full += full;
+                    }
+                    _ => {
+                        if let Some(capacity) = read(&dir, "capacity").and_then(|v| v.parse::<f64>().ok()) {
+                            capacities.push(capacity);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let percent = if energy_full > 0.0 {
+        Some(energy_now / energy_full * 100.0)
+    } else if !capacities.is_empty() {
+        Some(capacities.iter().sum::<f64>() / capacities.len() as f64)
+    } else {
+        None
+    };
+
+    // Without a mains supply entry, fall back to the battery's own status
+    let on_battery = if mains_seen { !mains_online } else { battery_discharging };
+
+    Ok((on_battery, percent))
+}
+
+/// Reads the current power status, preferring UPower and falling back to sysfs.
+pub async fn read_status(connection: &Connection, sysfs_root: &Path) -> Result<PowerStatus> {
+    let (on_battery, battery_percent) = match read_upower(connection).await {
+        Ok(status) => status,
+        Err(e) => {
+            tracing::debug!("UPower unavailable ({:#}), reading {}", e, sysfs_root.display());
+            read_sysfs(sysfs_root)?
+        }
+    };
+
+    let docked = crate::dbus::is_docked().await.unwrap_or(false);
+
+    Ok(PowerStatus { on_battery, battery_percent, docked })
+}
+
+/// Applies the configured battery/AC policies whenever the power state changes.
+pub async fn watch(daemon: Daemon, config: PowerConfig) -> Result<()> {
+    use futures_util::StreamExt;
+    use zbus::MatchRule;
+
+    let connection = Connection::system()
+        .await
+        .context("Failed to connect to system D-Bus")?;
+
+    let match_rule = MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .sender("org.freedesktop.UPower")?
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .build();
+
+    let proxy = zbus::fdo::DBusProxy::new(&connection).await?;
+    proxy.add_match_rule(match_rule).await?;
+
+    let mut stream = zbus::MessageStream::from(&connection);
+    let mut interval = tokio::time::interval(Duration::from_secs(config.poll_interval_secs.max(1)));
+    let sysfs_root = PathBuf::from(SYSFS_POWER_SUPPLY);
+
+    tracing::info!("Watching power source");
+
+    let mut last = None;
+    loop {
+        let status = read_status(&connection, &sysfs_root).await?;
+        let decision = evaluate(&config, &status);
+
+        // Facts for rule conditions, independent of the built-in policies
+        daemon.set_fact("power:ac", !status.on_battery).await?;
+        daemon.set_fact("power:battery", status.on_battery).await?;
+        daemon.set_fact("power:docked", status.docked).await?;
+
+        if last.as_ref() != Some(&decision) {
+            tracing::info!(
+                "Power policy: on_battery={} battery={:?} docked={} -> suppress={:?} hold={:?}",
+                status.on_battery, status.battery_percent, status.docked, decision.suppress, decision.hold
+            );
+
+            match &decision.suppress {
+                Some(cause) => daemon.set_suppression(SUPPRESSION_SOURCE, cause).await?,
+                None => daemon.clear_suppression(SUPPRESSION_SOURCE).await?,
+            }
+            match &decision.hold {
+                Some(cause) => daemon.set_hold(DOCKED_HOLD_SOURCE, cause).await?,
+                None => daemon.release_hold(DOCKED_HOLD_SOURCE).await?,
+            }
+            last = Some(decision);
+        }
+
+        // Replies to our own property reads arrive on the same stream; only
+        // PropertiesChanged signals warrant a re-read
+        let changed = async {
+            while let Some(msg) = stream.next().await {
+                if let Ok(msg) = msg {
+                    if msg.header().member().is_some_and(|m| m.as_str() == "PropertiesChanged") {
+                        return;
+                    }
+                }
+            }
+            std::future::pending::<()>().await
+        };
+
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = changed => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(on_battery: bool, battery_percent: Option<f64>, docked: bool) -> PowerStatus {
+        PowerStatus { on_battery, battery_percent, docked }
+    }
+
+    fn add_supply(root: &Path, name: &str, attributes: &[(&str, &str)]) {
+        let dir = root.join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (attribute, value) in attributes {
+            std::fs::write(dir.join(attribute), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    #[test]
+    fn ac_only() {
+        let config = PowerConfig { ac_only: true, ..PowerConfig::default() };
+        assert_eq!(
+            evaluate(&config, &status(true, Some(90.0), false)).suppress.as_deref(),
+            Some("On battery power")
+        );
+        assert_eq!(evaluate(&config, &status(false, Some(90.0), false)), PowerDecision::default());
+    }
+
+    #[test]
+    fn minimum_battery() {
+        let config = PowerConfig { min_battery_percent: Some(20), ..PowerConfig::default() };
+        assert_eq!(evaluate(&config, &status(true, Some(20.0), false)), PowerDecision::default());
+        assert_eq!(
+            evaluate(&config, &status(true, Some(19.4), false)).suppress.as_deref(),
+            Some("Battery at 19% (below 20%)")
+        );
+        // Only on battery, and only with a known charge
+        assert_eq!(evaluate(&config, &status(false, Some(5.0), false)), PowerDecision::default());
+        assert_eq!(evaluate(&config, &status(true, None, false)), PowerDecision::default());
+
+        // A low battery explains the suppression better than ac_only does
+        let both = PowerConfig { ac_only: true, ..config };
+        assert_eq!(
+            evaluate(&both, &status(true, Some(10.0), false)).suppress.as_deref(),
+            Some("Battery at 10% (below 20%)")
+        );
+    }
+
+    #[test]
+    fn docked_on_ac() {
+        let config = PowerConfig { enable_on_ac_docked: true, ..PowerConfig::default() };
+        assert_eq!(
+            evaluate(&config, &status(false, None, true)),
+            PowerDecision { suppress: None, hold: Some("Docked on AC power".to_string()) }
+        );
+        assert_eq!(evaluate(&config, &status(true, Some(80.0), true)), PowerDecision::default());
+        assert_eq!(evaluate(&config, &status(false, None, false)), PowerDecision::default());
+        assert_eq!(evaluate(&PowerConfig::default(), &status(false, None, true)), PowerDecision::default());
+    }
+
+    #[test]
+    fn reads_mains_and_batteries() {
+        let dir = tempfile::tempdir().unwrap();
+        add_supply(dir.path(), "AC", &[("type", "Mains"), ("online", "1")]);
+        add_supply(dir.path(), "BAT0", &[("type", "Battery"), ("status", "Charging"), ("energy_now", "30000000"), ("energy_full", "40000000")]);
+        add_supply(dir.path(), "BAT1", &[("type", "Battery"), ("status", "Charging"), ("energy_now", "10000000"), ("energy_full", "60000000")]);
+        // A wireless mouse is not the laptop's battery
+        add_supply(dir.path(), "hidpp_battery_0", &[("type", "Battery"), ("scope", "Device"), ("status", "Discharging"), ("capacity", "5")]);
+
+        assert_eq!(read_sysfs(dir.path()).unwrap(), (false, Some(40.0)));
+
+        add_supply(dir.path(), "AC", &[("online", "0")]);
+        assert_eq!(read_sysfs(dir.path()).unwrap(), (true, Some(40.0)));
+    }
+
+    #[test]
+    fn falls_back_to_capacity_and_battery_status() {
+        let dir = tempfile::tempdir().unwrap();
+        add_supply(dir.path(), "BAT0", &[("type", "Battery"), ("status", "Discharging"), ("capacity", "70")]);
+        add_supply(dir.path(), "BAT1", &[("type", "Battery"), ("status", "Discharging"), ("charge_now", "x"), ("capacity", "50")]);
+
+        // Without a mains entry the batteries' status decides
+        assert_eq!(read_sysfs(dir.path()).unwrap(), (true, Some(60.0)));
+
+        add_supply(dir.path(), "BAT0", &[("status", "Full")]);
+        add_supply(dir.path(), "BAT1", &[("status", "Not charging")]);
+        assert_eq!(read_sysfs(dir.path()).unwrap(), (false, Some(60.0)));
+    }
+
+    #[test]
+    fn empty_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(read_sysfs(dir.path()).unwrap(), (false, None));
+        assert!(read_sysfs(&dir.path().join("missing")).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::config::{ProcessConfig, ProcessRule};
+use crate::daemon::Daemon;
+
+/// Hold kind for process rules; sources are `process:<rule name>`.
+pub const HOLD_KIND: &str = "process";
+
+/// What we know about a running process from /proc.
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub comm: String,
+    pub exe: Option<String>,
+    pub cmdline: Vec<String>,
+    pub cgroup: String,
+}
+
+impl ProcessInfo {
+    /// Flatpak app id from an `app-flatpak-<id>-<n>.scope` cgroup, if any.
+    pub fn flatpak_app_id(&self) -> Option<&str> {
+        self.cgroup
+            .split(['/', '\n'])
+            .filter_map(|unit| unit.strip_prefix("app-flatpak-"))
+            .filter_map(|rest| rest.strip_suffix(".scope"))
+            .find_map(|rest| rest.rsplit_once('-').map(|(id, _)| id))
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> {
+        let argv0 = self
+            .cmdline
+            .first()
+            .map(|arg| arg.rsplit('/').next().unwrap_or(arg));
+        std::iter::once(self.comm.as_str())
+            .chain(self.exe.as_deref())
+            .chain(argv0)
+    }
+}
+
+/// A process rule with its regex compiled.
+pub struct ProcessMatcher {
+    rule: ProcessRule,
+    cmdline: Option<Regex>,
+}
+
+impl ProcessMatcher {
+    pub fn new(rule: ProcessRule) -> Result<Self> {
+        let cmdline = rule
+            .cmdline
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .with_context(|| format!("Invalid cmdline regex in process rule '{}'", rule.name))?;
+
+        Ok(Self { rule, cmdline })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.rule.name
+    }
+
+    pub fn reason(&self) -> String {
+        self.rule
+            .reason
+            .clone()
+            .unwrap_or_else(|| format!("{} is running", self.rule.name))
+    }
+
+    pub fn matches(&self, process: &ProcessInfo) -> bool {
+        let rule = &self.rule;
+        if rule.exe.is_none() && self.cmdline.is_none() && rule.cgroup.is_none() && rule.flatpak.is_none() {
+            return false;
+        }
+
+        if let Some(exe) = &rule.exe {
+            if !process.names().any(|name| name == exe) {
+                return false;
+            }
+        }
+
+        if let Some(regex) = &self.cmdline {
+            if !regex.is_match(&process.cmdline.join(" ")) {
+                return false;
+            }
+        }
+
+        if let Some(cgroup) = &rule.cgroup {
+            if !process.cgroup.contains(cgroup.as_str()) {
+                return false;
+            }
+        }
+
+        if let Some(app_id) = &rule.flatpak {
+            if process.flatpak_app_id() != Some(app_id.as_str()) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Reads processes owned by `uid` from a procfs mount.
+pub struct ProcessScanner {
+    proc_root: PathBuf,
+    uid: u32,
+}
+
+impl ProcessScanner {
+    pub fn new(proc_root: impl Into<PathBuf>, uid: u32) -> Self {
+        Self { proc_root: proc_root.into(), uid }
+    }
+
+    /// Scanner for the real /proc, limited to our own user's processes.
+    pub fn for_current_user() -> Self {
+        Self::new("/proc", nix::unistd::getuid().as_raw())
+    }
+
+    pub fn scan(&self) -> Result<Vec<ProcessInfo>> {
+        let entries = std::fs::read_dir(&self.proc_root)
+            .with_context(|| format!("Failed to read {}", self.proc_root.display()))?;
+
+        let mut processes = Vec::new();
+        for entry in entries.flatten() {
+            let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else {
+                continue;
+            };
+
+            let dir = entry.path();
+            match entry.metadata() {
+                Ok(meta) if meta.uid() == self.uid => {}
+                _ => continue,
+            }
+
+            // Processes can exit mid-scan; skip anything we can't read
+            if let Some(process) = read_process(&dir, pid) {
+                processes.push(process);
+            }
+        }
+
+        Ok(processes)
+    }
+}
+
+fn read_process(dir: &Path, pid: u32) -> Option<ProcessInfo> {
+    let comm = std::fs::read_to_string(dir.join("comm")).ok()?.trim_end().to_string();
+
+    let cmdline = std::fs::read(dir.join("cmdline"))
+        .map(|raw| {
+            raw.split(|b| *b == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let exe = std::fs::read_link(dir.join("exe"))
+        .ok()
+        .and_then(|path| path.file_name().map(|name| name.to_string_lossy().into_owned()));
+
+    let cgroup = std::fs::read_to_string(dir.join("cgroup")).unwrap_or_default();
+
+    Some(ProcessInfo { pid, comm, exe, cmdline, cgroup })
+}
+
+/// Short command name of a process, as shown by `ps -o comm`.
+pub fn command_name(pid: u32) -> Option<String> {
+    std::fs::read_to_string(format!("/proc/{}/comm", pid))
+        .ok()
+        .map(|comm| comm.trim_end().to_string())
+}
+
+/// Polls /proc and keeps one hold per process rule that currently matches.
+pub async fn watch(daemon: Daemon, config: ProcessConfig) -> Result<()> {
+    let scanner = ProcessScanner::for_current_user();
+
+    let mut matchers = Vec::new();
+    for rule in config.rules {
+        match ProcessMatcher::new(rule) {
+            Ok(matcher) => matchers.push(matcher),
+            Err(e) => tracing::warn!("Skipping process rule: {:#}", e),
+        }
+    }
+
+    tracing::info!("Watching processes for {} rule(s)", matchers.len());
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.scan_interval_secs.max(1)));
+    let mut active = BTreeSet::new();
+
+    loop {
+        interval.tick().await;
+
+        let processes = scanner.scan()?;
+
+        for matcher in &matchers {
+            let source = format!("{}:{}", HOLD_KIND, matcher.name());
+            let running = processes.iter().find(|p| matcher.matches(p));
+
+            match running {
+                Some(process) => {
+                    if active.insert(source.clone()) {
+                        tracing::info!("Process rule '{}' matched pid {} ({})", matcher.name(), process.pid, process.comm);
+                    }
+                    daemon.set_hold(&source, &matcher.reason()).await?;
+                }
+                None => {
+                    if active.remove(&source) {
+                        tracing::info!("Process rule '{}' no longer matches", matcher.name());
+                        daemon.release_hold(&source).await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// Adds a process to a fake procfs.
+    fn add_process(proc_root: &Path, pid: u32, comm: &str, cmdline: &[&str], exe: Option<&str>, cgroup: &str) {
+        let dir = proc_root.join(pid.to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("comm"), format!("{}\n", comm)).unwrap();
+        let mut raw = cmdline.join("\0").into_bytes();
+        raw.push(0);
+        std::fs::write(dir.join("cmdline"), raw).unwrap();
+        if let Some(exe) = exe {
+            symlink(exe, dir.join("exe")).unwrap();
+        }
+        std::fs::write(dir.join("cgroup"), cgroup).unwrap();
+    }
+
+    fn rule(name: &str) -> ProcessRule {
+        ProcessRule { name: name.to_string(), exe: None, cmdline: None, cgroup: None, flatpak: None, reason: None }
+    }
+
+    fn process(comm: &str, cmdline: &[&str], exe: Option<&str>, cgroup: &str) -> ProcessInfo {
+        ProcessInfo {
+            pid: 1,
+            comm: comm.to_string(),
+            exe: exe.map(str::to_string),
+            cmdline: cmdline.iter().map(|arg| arg.to_string()).collect(),
+            cgroup: cgroup.to_string(),
+        }
+    }
+
+    fn matcher(rule: ProcessRule) -> ProcessMatcher {
+        ProcessMatcher::new(rule).unwrap()
+    }
+
+    const FLATPAK_CGROUP: &str =
+        "0::/user.slice/user-1000.slice/user@1000.service/app.slice/app-flatpak-org.blender.Blender-12345.scope\n";
+
+    #[test]
+    fn matches_executable_names() {
+        let rule = ProcessRule { exe: Some("blender".to_string()), ..rule("blender") };
+        let blender = matcher(rule);
+
+        // comm is cut to 15 characters, so exe and argv0 matter too
+        assert!(blender.matches(&process("blender", &[], None, "")));
+        assert!(blender.matches(&process("blender-bin", &[], Some("blender"), "")));
+        assert!(blender.matches(&process("python3", &["/opt/blender/blender", "-b"], None, "")));
+        assert!(!blender.matches(&process("python3", &["/usr/bin/python3", "blender"], Some("python3.12"), "")));
+    }
+
+    #[test]
+    fn matches_cmdline_cgroup_and_flatpak() {
+        let render = matcher(ProcessRule { cmdline: Some(r"render\s+--final".to_string()), ..rule("render") });
+        assert!(render.matches(&process("python3", &["python3", "render", "--final"], None, "")));
+        assert!(!render.matches(&process("python3", &["python3", "render", "--preview"], None, "")));
+
+        let cgroup = matcher(ProcessRule { cgroup: Some("app-flatpak-org.blender".to_string()), ..rule("cgroup") });
+        assert!(cgroup.matches(&process("blender", &[], None, FLATPAK_CGROUP)));
+        assert!(!cgroup.matches(&process("blender", &[], None, "0::/user.slice\n")));
+
+        let flatpak = matcher(ProcessRule { flatpak: Some("org.blender.Blender".to_string()), ..rule("flatpak") });
+        assert!(flatpak.matches(&process("blender", &[], None, FLATPAK_CGROUP)));
+        assert!(!flatpak.matches(&process("blender", &[], None, "0::/app-flatpak-org.blender.Blender.scope\n")));
+
+        // Every given criterion has to hold, and a rule without any never matches
+        let both = matcher(ProcessRule {
+            exe: Some("python3".to_string()),
+            cmdline: Some("render".to_string()),
+            ..rule("both")
+        });
+        assert!(both.matches(&process("python3", &["python3", "render"], None, "")));
+        assert!(!both.matches(&process("bash", &["bash", "render"], None, "")));
+        assert!(!matcher(rule("empty")).matches(&process("python3", &[], None, "")));
+
+        assert!(ProcessMatcher::new(ProcessRule { cmdline: Some("(".to_string()), ..rule("bad") }).is_err());
+    }
+
+    #[test]
+    fn extracts_flatpak_app_ids() {
+        let id = |cgroup: &str| process("x", &[], None, cgroup).flatpak_app_id().map(str::to_string);
+        assert_eq!(id(FLATPAK_CGROUP).as_deref(), Some("org.blender.Blender"));
+        assert_eq!(id("0::/app-flatpak-com.valvesoftware.Steam-99.scope").as_deref(), Some("com.valvesoftware.Steam"));
+        assert_eq!(id("0::/user.slice/app-firefox-123.scope"), None);
+        assert_eq!(id("0::/app-flatpak-nodash.scope"), None);
+        assert_eq!(id(""), None);
+    }
+
+    #[test]
+    fn scans_own_processes() {
+        let dir = tempfile::tempdir().unwrap();
+        add_process(dir.path(), 100, "blender", &["/usr/bin/blender", "-b"], Some("/usr/bin/blender"), FLATPAK_CGROUP);
+        add_process(dir.path(), 200, "kworker/0:1", &[], None, "");
+        std::fs::create_dir_all(dir.path().join("self")).unwrap();
+        std::fs::write(dir.path().join("uptime"), "1.0 1.0\n").unwrap();
+        let uid = std::fs::metadata(dir.path()).unwrap().uid();
+
+        let mut processes = ProcessScanner::new(dir.path(), uid).scan().unwrap();
+        processes.sort_by_key(|process| process.pid);
+        assert_eq!(processes.len(), 2);
+        assert_eq!(processes[0].comm, "blender");
+        assert_eq!(processes[0].exe.as_deref(), Some("blender"));
+        assert_eq!(processes[0].cmdline, ["/usr/bin/blender", "-b"]);
+        assert_eq!(processes[0].flatpak_app_id(), Some("org.blender.Blender"));
+        assert_eq!(processes[1].comm, "kworker/0:1");
+        assert!(processes[1].cmdline.is_empty() && processes[1].exe.is_none());
+
+        // Processes of other users are left out
+        assert!(ProcessScanner::new(dir.path(), uid.wrapping_add(1)).scan().unwrap().is_empty());
+        assert!(ProcessScanner::new(dir.path().join("missing"), uid).scan().is_err());
+    }
+}
@@ -0,0 +1,362 @@
+use anyhow::{Context, Result, bail};
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use zbus::proxy::CacheProperties;
+use zbus::{proxy, Connection};
+use crate::config::RemoteConfig;
+use crate::daemon::Daemon;
+use crate::dbus::Login1ManagerProxy;
+use crate::process::ProcessScanner;
+
+/// Hold kind for remote access; sources are `remote:session:<session id>` or
+/// `remote:server:<process name>`.
+pub const HOLD_KIND: &str = "remote";
+
+#[proxy(
+    interface = "org.freedesktop.login1.Session",
+    default_service = "org.freedesktop.login1"
+)]
+trait Login1Session {
+    #[zbus(property)]
+    fn remote(&self) -> zbus::Result<bool>;
+
+    #[zbus(property)]
+    fn remote_host(&self) -> zbus::Result<String>;
+
+    #[zbus(property)]
+    fn service(&self) -> zbus::Result<String>;
+}
+
+/// Remote logind sessions of `uid` as (session id, reason), skipping `own_session`.
+async fn remote_sessions(
+    connection: &Connection,
+    manager: &Login1ManagerProxy<'_>,
+    uid: u32,
+    own_session: &str,
+) -> Result<BTreeMap<String, String>> {
+    let sessions = manager.list_sessions().await.context("Failed to list logind sessions")?;
+
+    let mut remote = BTreeMap::new();
+    for (id, session_uid, _, _, path) in sessions {
+        if session_uid != uid || id == own_session {
+            continue;
+        }
+
+        let session = Login1SessionProxy::builder(connection)
+            .path(path)?
+            .cache_properties(CacheProperties::No)
+            .build()
+            .await?;
+
+        // Sessions can close between ListSessions and the property read
+        if !session.remote().await.unwrap_or(false) {
+            continue;
+        }
+
+        let service = session.service().await.unwrap_or_default();
+        let host = session.remote_host().await.unwrap_or_default();
+        let reason = match (service.is_empty(), host.is_empty()) {
+            (false, false) => format!("Remote {} session from {}", service, host),
+            (true, false) => format!("Remote session from {}", host),
+            (false, true) => format!("Remote {} session", service),
+            (true, true) => "Remote session".to_string(),
+        };
+        remote.insert(id, reason);
+    }
+
+    Ok(remote)
+}
+
+/// Decodes a `/proc/net/tcp{,6}` address (hex, in host byte order per 32-bit word).
+fn parse_address(hex: &str) -> Option<IpAddr> {
+    let (address, _port) = hex.split_once(':')?;
+    let words: Vec<u32> = (0..address.len() / 8)
+        .map(|i| u32::from_str_radix(&address[i * 8..i * 8 + 8], 16).ok())
+        .collect::<Option<_>>()?;
+
+    match words.as_slice() {
+        [word] => Some(IpAddr::V4(Ipv4Addr::from(word.to_ne_bytes()))),
+        [a, b, c, d] => {
+            let mut bytes = [0u8; 16];
+            for (chunk, word) in bytes.chunks_mut(4).zip([a, b, c, d]) {
+                chunk.copy_from_slice(&word.to_ne_bytes());
+            }
+            let address = Ipv6Addr::from(bytes);
+            Some(address.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(IpAddr::V6(address)))
+        }
+        _ => None,
+    }
+}
+
+/// Peer addresses of established TCP connections, keyed by socket inode.
+pub fn established_peers(proc_root: &Path) -> BTreeMap<u64, IpAddr> {
+    let mut peers = BTreeMap::new();
+    for table in ["net/tcp", "net/tcp6"] {
+        let Ok(content) = std::fs::read_to_string(proc_root.join(table)) else {
+            continue;
+        };
+        for line in content.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // sl local rem st tx:rx tr:when retrnsmt uid timeout inode; 01 = ESTABLISHED
+            if fields.len() < 10 || fields[3] != "01" {
+                continue;
+            }
+            if let (Some(peer), Ok(inode)) = (parse_address(fields[2]), fields[9].parse()) {
+                peers.insert(inode, peer);
+            }
+        }
+    }
+    peers
+}
+
+/// Finds remote-desktop servers that currently have a client connected.
+pub struct RemoteDesktopScanner {
+    processes: ProcessScanner,
+    proc_root: PathBuf,
+    names: Vec<String>,
+}
+
+impl RemoteDesktopScanner {
+    pub fn new(proc_root: impl Into<PathBuf>, uid: u32, names: Vec<String>) -> Self {
+        let proc_root = proc_root.into();
+        Self {
+            processes: ProcessScanner::new(proc_root.clone(), uid),
+            proc_root,
+            names,
+        }
+    }
+
+    /// Connected clients per server process name.
+    pub fn scan(&self) -> Result<BTreeMap<String, BTreeSet<IpAddr>>> {
+        let servers: Vec<_> = self
+            .processes
+            .scan()?
+            .into_iter()
+            .filter(|process| self.names.contains(&process.comm))
+            .collect();
+
+        let mut clients: BTreeMap<String, BTreeSet<IpAddr>> = BTreeMap::new();
+        if servers.is_empty() {
+            return Ok(clients);
+        }
+
+        let peers = established_peers(&self.proc_root);
+        for server in servers {
+            let fd_dir = self.proc_root.join(server.pid.to_string()).join("fd");
+            let Ok(entries) = std::fs::read_dir(&fd_dir) else {
+                continue;
+            };
+
+            for entry in entries.flatten() {
+                let inode = std::fs::read_link(entry.path())
+                    .ok()
+                    .and_then(|target| {
+                        let target = target.to_str()?.to_string();
+                        target.strip_prefix("socket:[")?.strip_suffix(']')?.parse::<u64>().ok()
+                    });
+                if let Some(peer) = inode.and_then(|inode| peers.get(&inode)) {
+                    clients.entry(server.comm.clone()).or_default().insert(*peer);
+                }
+            }
+        }
+
+        Ok(clients)
+    }
+}
+
+/// Holds the inhibitor while this user has a remote logind session (SSH, xrdp)
+/// or a remote-desktop server of theirs has a client connected.
+pub async fn watch(daemon: Daemon, config: RemoteConfig) -> Result<()> {
+    use futures_util::StreamExt;
+
+    let uid = nix::unistd::getuid().as_raw();
+    let own_session = daemon.session().id.clone();
+    let scanner = RemoteDesktopScanner::new("/proc", uid, config.processes.clone());
+
+    let connection = Connection::system()
+        .await
+        .context("Failed to connect to system D-Bus")?;
+
+    let manager = Login1ManagerProxy::new(&connection)
+        .await
+        .context("Failed to create logind proxy")?;
+    let added = manager.receive_session_new().await?.map(|_| ());
+    let removed = manager.receive_session_removed().await?.map(|_| ());
+    let mut session_changes = futures_util::stream::select(added, removed);
+    let mut interval = tokio::time::interval(Duration::from_secs(config.scan_interval_secs.max(1)));
+
+    tracing::info!("Watching remote sessions and {} remote desktop server(s)", config.processes.len());
+
+    let mut active: BTreeMap<String, String> = BTreeMap::new();
+    loop {
+        let mut wanted = BTreeMap::new();
+
+        if config.sessions {
+            for (id, reason) in remote_sessions(&connection, &manager, uid, &own_session).await? {
+                wanted.insert(format!("{}:session:{}", HOLD_KIND, id), reason);
+            }
+        }
+
+        for (server, peers) in scanner.scan()? {
+            let hosts: Vec<String> = peers.iter().map(|peer| peer.to_string()).collect();
+            wanted.insert(
+                format!("{}:server:{}", HOLD_KIND, server),
+                format!("{} connection from {}", server, hosts.join(", ")),
+            );
+        }
+
+        for source in active.keys().filter(|source| !wanted.contains_key(*source)) {
+            tracing::info!("Remote access {} ended", source);
+            daemon.release_hold(source).await?;
+        }
+        for (source, reason) in &wanted {
+            if !active.contains_key(source) {
+                tracing::info!("Remote access {}: {}", source, reason);
+            }
+            daemon.set_hold(source, reason).await?;
+        }
+        active = wanted;
+
+        tokio::select! {
+            _ = interval.tick() => {}
+            changed = session_changes.next() => {
+                if changed.is_none() {
+                    bail!("logind session signal stream ended");
+                }
+            }
+        }
+    }
+}
+
+// /proc/net/tcp{,6} store each 32-bit address word in host byte order, so the
+// fixtures below are what a little-endian kernel writes
+#[cfg(all(test, target_endian = "little"))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, MetadataExt};
+
+    const TCP_HEADER: &str =
+        "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n";
+    const TCP6_HEADER: &str = "  sl  local_address                         remote_address                        \
+                               st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n";
+
+    fn socket_line(local: &str, remote: &str, state: &str, inode: u64) -> String {
+        format!(
+            "   0: {} {} {} 00000000:00000000 00:00000000 00000000  1000        0 {} 1 0000000000000000 20 4 30 10 -1\n",
+            local, remote, state, inode
+        )
+    }
+
+    fn write_tables(root: &Path, tcp: &[String], tcp6: &[String]) {
+        std::fs::create_dir_all(root.join("net")).unwrap();
+        std::fs::write(root.join("net/tcp"), format!("{}{}", TCP_HEADER, tcp.concat())).unwrap();
+        std::fs::write(root.join("net/tcp6"), format!("{}{}", TCP6_HEADER, tcp6.concat())).unwrap();
+    }
+
+    /// Adds a process with the given fd symlink targets to a fake procfs.
+    fn add_process(root: &Path, pid: u32, comm: &str, fds: &[&str]) {
+        let dir = root.join(pid.to_string());
+        std::fs::create_dir_all(dir.join("fd")).unwrap();
+        std::fs::write(dir.join("comm"), format!("{}\n", comm)).unwrap();
+        for (fd, target) in fds.iter().enumerate() {
+            symlink(target, dir.join("fd").join(fd.to_string())).unwrap();
+        }
+    }
+
+    #[test]
+    fn parses_addresses() {
+        let v4 = |address: &str| parse_address(address).map(|address| address.to_string());
+
+        assert_eq!(v4("0100007F:1F90").as_deref(), Some("127.0.0.1"));
+        assert_eq!(v4("0500000A:D431").as_deref(), Some("10.0.0.5"));
+        assert_eq!(v4("1401A8C0:0016").as_deref(), Some("192.168.1.20"));
+        // IPv4-mapped IPv6 peers are reported as IPv4
+        assert_eq!(v4("0000000000000000FFFF00000500000A:170C").as_deref(), Some("10.0.0.5"));
+        assert_eq!(v4("B80D0120000000000000000001000000:170C").as_deref(), Some("2001:db8::1"));
+        assert_eq!(v4("00000000000000000000000001000000:0016").as_deref(), Some("::1"));
+
+        assert_eq!(v4("0100007F"), None);
+        assert_eq!(v4("0100007G:1F90"), None);
+        assert_eq!(v4("0100:1F90"), None);
+        assert_eq!(v4("0100007F0100007F:1F90"), None);
+    }
+
+    #[test]
+    fn finds_established_peers() {
+        let dir = tempfile::tempdir().unwrap();
+        write_tables(
+            dir.path(),
+            &[
+                socket_line("0100007F:170C", "0500000A:D431", "01", 100),
+                // LISTEN and TIME_WAIT sockets have no connected client
+                socket_line("00000000:170C", "00000000:0000", "0A", 101),
+                socket_line("0100007F:170C", "0600000A:D432", "06", 102),
+            ],
+            &[
+                socket_line(
+                    "00000000000000000000000000000000:170C",
+                    "0000000000000000FFFF00000700000A:D433",
+                    "01",
+                    200,
+                ),
+                socket_line(
+                    "00000000000000000000000000000000:170C",
+                    "B80D0120000000000000000001000000:D434",
+                    "01",
+                    201,
+                ),
+                "   1: garbage\n".to_string(),
+            ],
+        );
+
+        let peers = established_peers(dir.path());
+        let peers: Vec<(u64, String)> = peers.into_iter().map(|(inode, peer)| (inode, peer.to_string())).collect();
+        assert_eq!(
+            peers,
+            [(100, "10.0.0.5".to_string()), (200, "10.0.0.7".to_string()), (201, "2001:db8::1".to_string())]
+        );
+
+        // Missing tables (e.g. IPv6 disabled) are skipped
+        std::fs::remove_file(dir.path().join("net/tcp6")).unwrap();
+        assert_eq!(established_peers(dir.path()).len(), 1);
+        assert!(established_peers(&dir.path().join("missing")).is_empty());
+    }
+
+    #[test]
+    fn scans_connected_servers() {
+        let dir = tempfile::tempdir().unwrap();
+        write_tables(
+            dir.path(),
+            &[
+                socket_line("0100007F:170C", "0500000A:D431", "01", 100),
+                socket_line("00000000:170C", "00000000:0000", "0A", 101),
+                socket_line("0100007F:0016", "0800000A:D435", "01", 300),
+            ],
+            &[socket_line(
+                "00000000000000000000000000000000:170C",
+                "0000000000000000FFFF00000700000A:D433",
+                "01",
+                200,
+            )],
+        );
+        add_process(dir.path(), 10, "wayvnc", &["/dev/null", "socket:[101]", "socket:[100]", "socket:[200]", "pipe:[100]"]);
+        // Listening only
+        add_process(dir.path(), 11, "x11vnc", &["socket:[101]"]);
+        // Connected, but not a configured server
+        add_process(dir.path(), 12, "ssh", &["socket:[300]"]);
+        let uid = std::fs::metadata(dir.path()).unwrap().uid();
+
+        let names = vec!["wayvnc".to_string(), "x11vnc".to_string()];
+        let clients = RemoteDesktopScanner::new(dir.path(), uid, names.clone()).scan().unwrap();
+        let clients: Vec<(String, Vec<String>)> = clients
+            .into_iter()
+            .map(|(server, peers)| (server, peers.iter().map(|peer| peer.to_string()).collect()))
+            .collect();
+        assert_eq!(clients, [("wayvnc".to_string(), vec!["10.0.0.5".to_string(), "10.0.0.7".to_string()])]);
+
+        // Servers of other users are not looked at
+        assert!(RemoteDesktopScanner::new(dir.path(), uid.wrapping_add(1), names).scan().unwrap().is_empty());
+    }
+}
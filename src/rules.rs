@@ -0,0 +1,337 @@
+use anyhow::{bail, Context, Result};
+use crate::config::{RuleAction, RuleConfig};
+
+/// Hold and suppression kind for rule actions; sources are `rule:<rule name>`.
+pub const HOLD_KIND: &str = "rule";
+
+/// A boolean expression over named conditions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Const(bool),
+    Condition(String),
+    Not(Box<Expr>),
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Open,
+    Close,
+    Not,
+    And,
+    Or,
+    Ident(String),
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | ':')
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(i, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '!' => {
+                chars.next();
+                tokens.push(Token::Not);
+            }
+            '&' | '|' => {
+                chars.next();
+                if chars.next().map(|(_, next)| next) != Some(c) {
+                    bail!("Expected '{}{}' at position {}", c, c, i);
+                }
+                tokens.push(if c == '&' { Token::And } else { Token::Or });
+            }
+            c if is_ident_char(c) => {
+                let mut ident = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if !is_ident_char(c) {
+                        break;
+                    }
+                    ident.push(c);
+                    chars.next();
+                }
+                tokens.push(match ident.to_ascii_lowercase().as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    _ => Token::Ident(ident),
+                });
+            }
+            other => bail!("Unexpected '{}' at position {}", other, i),
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut terms = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            terms.push(self.and()?);
+        }
+        Ok(if terms.len() == 1 { terms.remove(0) } else { Expr::Or(terms) })
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut terms = vec![self.unary()?];
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            terms.push(self.unary()?);
+        }
+        Ok(if terms.len() == 1 { terms.remove(0) } else { Expr::And(terms) })
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.next() {
+            Some(Token::Not) => Ok(Expr::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                if self.next() != Some(Token::Close) {
+                    bail!("Missing ')'");
+                }
+                Ok(expr)
+            }
+            Some(Token::Ident(ident)) => Ok(match ident.to_ascii_lowercase().as_str() {
+                "true" => Expr::Const(true),
+                "false" => Expr::Const(false),
+                _ => Expr::Condition(ident),
+            }),
+            Some(token) => bail!("Unexpected {:?}", token),
+            None => bail!("Unexpected end of expression"),
+        }
+    }
+}
+
+impl Expr {
+    /// Parses `media AND power:ac AND NOT schedule:night`. `&&`, `||` and `!`
+    /// work too; NOT binds tighter than AND, which binds tighter than OR.
+    pub fn parse(input: &str) -> Result<Self> {
+        let mut parser = Parser { tokens: tokenize(input)?, pos: 0 };
+        let expr = parser.or()?;
+        if let Some(token) = parser.peek() {
+            bail!("Unexpected {:?} after expression", token);
+        }
+        Ok(expr)
+    }
+
+    pub fn eval(&self, condition: &dyn Fn(&str) -> bool) -> bool {
+        match self {
+            Expr::Const(value) => *value,
+            Expr::Condition(name) => condition(name),
+            Expr::Not(expr) => !expr.eval(condition),
+            Expr::And(terms) => terms.iter().all(|term| term.eval(condition)),
+            Expr::Or(terms) => terms.iter().any(|term| term.eval(condition)),
+        }
+    }
+
+    /// The expression with each condition's current value, e.g.
+    /// `media=true AND NOT schedule:night=false`.
+    pub fn explain(&self, condition: &dyn Fn(&str) -> bool) -> String {
+        let nested = |expr: &Expr| match expr {
+            Expr::And(_) | Expr::Or(_) => format!("({})", expr.explain(condition)),
+            _ => expr.explain(condition),
+        };
+
+        match self {
+            Expr::Const(value) => value.to_string(),
+            Expr::Condition(name) => format!("{}={}", name, condition(name)),
+            Expr::Not(expr) => format!("NOT {}", nested(expr)),
+            Expr::And(terms) => terms.iter().map(nested).collect::<Vec<_>>().join(" AND "),
+            Expr::Or(terms) => terms.iter().map(nested).collect::<Vec<_>>().join(" OR "),
+        }
+    }
+}
+
+/// A configured rule with its expression parsed.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub config: RuleConfig,
+    pub expr: Expr,
+}
+
+impl Rule {
+    pub fn new(config: RuleConfig) -> Result<Self> {
+        let expr = Expr::parse(&config.when)
+            .with_context(|| format!("Invalid expression in rule '{}'", config.name))?;
+        Ok(Self { config, expr })
+    }
+
+    pub fn source(&self) -> String {
+        format!("{}:{}", HOLD_KIND, self.config.name)
+    }
+
+    pub fn reason(&self) -> String {
+        self.config.reason.clone().unwrap_or_else(|| match self.config.action {
+            RuleAction::Hold => format!("Rule {}", self.config.name),
+            RuleAction::Release => format!("Rule {} forces release", self.config.name),
+        })
+    }
+}
+
+/// The rule that decides the outcome: the true rule with the highest priority,
+/// with release winning ties so a conflict never keeps the machine awake.
+pub fn winner<'a>(rules: &'a [Rule], condition: &dyn Fn(&str) -> bool) -> Option<&'a Rule> {
+    rules
+        .iter()
+        .filter(|rule| rule.expr.eval(condition))
+        .max_by_key(|rule| (rule.config.priority, rule.config.action == RuleAction::Release))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cond(name: &str) -> Expr {
+        Expr::Condition(name.to_string())
+    }
+
+    /// Only the listed conditions are true; anything else, including names no
+    /// watcher reports, is false.
+    fn truth<'a>(true_names: &'a [&'a str]) -> impl Fn(&str) -> bool + 'a {
+        move |name| true_names.contains(&name)
+    }
+
+    fn rule(name: &str, when: &str, action: RuleAction, priority: i32) -> Rule {
+        Rule::new(RuleConfig {
+            name: name.to_string(),
+            when: when.to_string(),
+            action,
+            priority,
+            reason: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn parses_operators() {
+        assert_eq!(Expr::parse("media").unwrap(), cond("media"));
+        assert_eq!(
+            Expr::parse("media AND power:ac").unwrap(),
+            Expr::And(vec![cond("media"), cond("power:ac")])
+        );
+        assert_eq!(Expr::parse("a && b").unwrap(), Expr::parse("a and b").unwrap());
+        assert_eq!(Expr::parse("a || b").unwrap(), Expr::Or(vec![cond("a"), cond("b")]));
+        assert_eq!(Expr::parse("!a").unwrap(), Expr::Not(Box::new(cond("a"))));
+        assert_eq!(Expr::parse("NOT not a").unwrap(), Expr::Not(Box::new(Expr::Not(Box::new(cond("a"))))));
+        assert_eq!(Expr::parse("TRUE or false").unwrap(), Expr::Or(vec![Expr::Const(true), Expr::Const(false)]));
+        assert_eq!(
+            Expr::parse("a and b and c").unwrap(),
+            Expr::And(vec![cond("a"), cond("b"), cond("c")])
+        );
+    }
+
+    #[test]
+    fn precedence_and_parentheses() {
+        // NOT binds tighter than AND, which binds tighter than OR
+        assert_eq!(
+            Expr::parse("a OR b AND NOT c").unwrap(),
+            Expr::Or(vec![cond("a"), Expr::And(vec![cond("b"), Expr::Not(Box::new(cond("c")))])])
+        );
+        assert_eq!(
+            Expr::parse("(a OR b) AND c").unwrap(),
+            Expr::And(vec![Expr::Or(vec![cond("a"), cond("b")]), cond("c")])
+        );
+        assert_eq!(
+            Expr::parse("NOT (a AND b)").unwrap(),
+            Expr::Not(Box::new(Expr::And(vec![cond("a"), cond("b")])))
+        );
+        assert_eq!(Expr::parse("((a))").unwrap(), cond("a"));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for input in ["", "a AND", "OR a", "(a", "a)", "a b", "a & b", "a | b", "a ? b", "()", "NOT"] {
+            assert!(Expr::parse(input).is_err(), "'{}' should be rejected", input);
+        }
+
+        let error = Rule::new(RuleConfig {
+            name: "broken".to_string(),
+            when: "media AND".to_string(),
+            action: RuleAction::Hold,
+            priority: 0,
+            reason: None,
+        })
+        .unwrap_err();
+        assert!(format!("{:#}", error).contains("Invalid expression in rule 'broken'"));
+    }
+
+    #[test]
+    fn evaluates() {
+        let expr = Expr::parse("media AND power:ac AND NOT schedule:night").unwrap();
+        assert!(expr.eval(&truth(&["media", "power:ac"])));
+        assert!(!expr.eval(&truth(&["media", "power:ac", "schedule:night"])));
+        assert!(!expr.eval(&truth(&["media"])));
+
+        let expr = Expr::parse("(a OR b) AND NOT c").unwrap();
+        assert!(expr.eval(&truth(&["b"])));
+        assert!(!expr.eval(&truth(&["b", "c"])));
+        assert!(!expr.eval(&truth(&[])));
+
+        // Unknown conditions are simply false
+        assert!(!Expr::parse("no-such-watcher").unwrap().eval(&truth(&["media"])));
+        assert!(Expr::parse("NOT no-such-watcher").unwrap().eval(&truth(&["media"])));
+    }
+
+    #[test]
+    fn explains() {
+        let expr = Expr::parse("media AND NOT schedule:night").unwrap();
+        assert_eq!(expr.explain(&truth(&["media"])), "media=true AND NOT schedule:night=false");
+
+        let expr = Expr::parse("(a OR b) AND NOT (c AND d) OR true").unwrap();
+        assert_eq!(
+            expr.explain(&truth(&["b", "d"])),
+            "((a=false OR b=true) AND NOT (c=false AND d=true)) OR true"
+        );
+    }
+
+    #[test]
+    fn highest_priority_wins_and_release_breaks_ties() {
+        let rules = vec![
+            rule("media", "media", RuleAction::Hold, 0),
+            rule("battery", "power:battery", RuleAction::Release, 0),
+            rule("meeting", "calendar", RuleAction::Hold, 10),
+        ];
+        let winner = |names: &[&str]| winner(&rules, &truth(names)).map(|rule| rule.config.name.clone());
+
+        assert_eq!(winner(&[]), None);
+        assert_eq!(winner(&["media"]).as_deref(), Some("media"));
+        assert_eq!(winner(&["media", "power:battery"]).as_deref(), Some("battery"));
+        assert_eq!(winner(&["media", "power:battery", "calendar"]).as_deref(), Some("meeting"));
+
+        assert_eq!(rules[0].source(), "rule:media");
+        assert_eq!(rules[0].reason(), "Rule media");
+        assert_eq!(rules[1].reason(), "Rule battery forces release");
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{bail, Context, Result};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::os::unix::process::ExitStatusExt;
+use tokio::signal::unix::{signal, SignalKind};
+use crate::daemon::render_template;
+use crate::dbus::{DaemonHold, InhibitorLock};
+use crate::holds::{self, Holds};
+use crate::{get_current_session, Config};
+
+/// Signals passed through to the child instead of terminating us.
+const FORWARDED: &[(SignalKind, Signal)] = &[
+    (SignalKind::interrupt(), Signal::SIGINT),
+    (SignalKind::terminate(), Signal::SIGTERM),
+    (SignalKind::hangup(), Signal::SIGHUP),
+    (SignalKind::quit(), Signal::SIGQUIT),
+    (SignalKind::user_defined1(), Signal::SIGUSR1),
+    (SignalKind::user_defined2(), Signal::SIGUSR2),
+];
+
+/// Signals the terminal sends to its whole foreground process group.
+const KEYBOARD: &[Signal] = &[Signal::SIGINT, Signal::SIGQUIT];
+
+/// Whether we, and so the child, are the terminal's foreground process group.
+/// The child then already received any keyboard signal from the kernel, and many
+/// tools treat a second SIGINT as "abort without cleanup".
+fn in_foreground() -> bool {
+    // SAFETY: both calls only query process state
+    let foreground = unsafe { libc::tcgetpgrp(libc::STDIN_FILENO) };
+    foreground != -1 && foreground == unsafe { libc::getpgrp() }
+}
+
+/// Takes the logind inhibitor ourselves, named like the daemon would name it.
+async fn acquire_direct(what: &str, reason: &str) -> Result<InhibitorLock> {
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("Using default config: {:#}", e);
+        Config::default()
+    });
+    let session = get_current_session().await.map(|session| session.id).unwrap_or_default();
+
+    let mut holds = Holds::default();
+    holds.insert_what(holds::CLIENT, what, reason);
+    let who = render_template(&config.who_template, &session, &holds);
+    let why = render_template(&config.why_template, &session, &holds);
+
+    InhibitorLock::acquire(what, &who, &why).await
+}
+
+/// How the inhibitor for a `run` invocation is held.
+enum RunHold {
+    Daemon(DaemonHold),
+    /// No daemon was reachable, so we hold the logind inhibitor ourselves
+    Direct(InhibitorLock),
+}
+
+/// Runs `command` while holding an inhibitor, returning the exit code to pass on.
+pub async fn run(what: &str, reason: Option<String>, command: &[String]) -> Result<i32> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("No command given");
+    };
+    holds::validate_what(what)?;
+
+    let reason = reason.unwrap_or_else(|| format!("Running {}", command.join(" ")));
+
+    // Installed before the hold is taken and the child started, so a signal in
+    // between is forwarded once the child runs instead of killing us with the
+    // hold still registered
+    let mut streams = Vec::new();
+    for (kind, sig) in FORWARDED {
+        streams.push((signal(*kind).context("Failed to install signal handler")?, *sig));
+    }
+
+    let hold = match DaemonHold::register(what, &reason).await {
+        Ok(hold) => RunHold::Daemon(hold),
+        Err(e) => {
+            eprintln!("Daemon unavailable ({:#}), taking the inhibitor directly", e);
+            RunHold::Direct(acquire_direct(what, &reason).await?)
+        }
+    };
+
+    let mut child = tokio::process::Command::new(program)
+        .args(args)
+        .spawn()
+        .with_context(|| format!("Failed to start {}", program))?;
+
+    let child_pid = child.id().map(|pid| Pid::from_raw(pid as i32));
+
+    let (signal_tx, mut signal_rx) = tokio::sync::mpsc::unbounded_channel();
+    for (mut stream, sig) in streams {
+        let tx = signal_tx.clone();
+        tokio::spawn(async move {
+            while stream.recv().await.is_some() {
+                if tx.send(sig).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    let status = loop {
+        tokio::select! {
+            status = child.wait() => break status.context("Failed to wait for command")?,
+            Some(sig) = signal_rx.recv() => {
+                if KEYBOARD.contains(&sig) && in_foreground() {
+                    continue;
+                }
+                if let Some(pid) = child_pid {
+                    kill(pid, sig).ok();
+                }
+            }
+        }
+    };
+
+    match hold {
+        RunHold::Daemon(hold) => {
+            if let Err(e) = hold.release().await {
+                eprintln!("{:#}", e);
+            }
+        }
+        RunHold::Direct(lock) => drop(lock),
+    }
+
+    Ok(status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)))
+}
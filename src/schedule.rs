@@ -0,0 +1,527 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zbus::Connection;
+use crate::config::{ScheduleAction, ScheduleConfig, ScheduleRule};
+use crate::daemon::Daemon;
+use crate::zoneinfo::TimeZone;
+
+/// Hold and suppression kind for schedule windows; sources are `schedule:<rule name>`.
+pub const HOLD_KIND: &str = "schedule";
+
+pub const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Upper bound on how long the scheduler sleeps, so wall-clock jumps are noticed
+/// within a minute even without a resume signal.
+const MAX_SLEEP_SECS: i64 = 60;
+
+/// A wall-clock position in the week, in the local time zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    /// 0 = Monday
+    pub weekday: usize,
+    /// Minutes since local midnight
+    pub minute: u32,
+}
+
+impl LocalTime {
+    /// The position of `timestamp` in the week, in `zone` or, without one, in the
+    /// system's local time zone.
+    pub fn at(timestamp: i64, zone: Option<&TimeZone>) -> Result<Self> {
+        if let Some(zone) = zone {
+            let local = timestamp + zone.offset_at(timestamp);
+            // 1970-01-01 was a Thursday
+            return Ok(Self {
+                weekday: (local.div_euclid(86_400) + 3).rem_euclid(7) as usize,
+                minute: (local.rem_euclid(86_400) / 60) as u32,
+            });
+        }
+
+        let time = timestamp as libc::time_t;
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+            bail!("Failed to convert {} to local time", timestamp);
+        }
+
+        Ok(Self {
+            weekday: ((tm.tm_wday + 6) % 7) as usize,
+            minute: (tm.tm_hour * 60 + tm.tm_min) as u32,
+        })
+    }
+}
+
+/// The timestamp of `minute` past local midnight, `days` days after the local date
+/// of `now`, in `zone` or the system's local time zone. Minutes past 24:00 roll
+/// over into the next day.
+fn local_timestamp(now: i64, days: i64, minute: u32, zone: Option<&TimeZone>) -> Result<i64> {
+    if let Some(zone) = zone {
+        let date = (now + zone.offset_at(now)).div_euclid(86_400) + days;
+        return Ok(zone.to_utc(date * 86_400 + i64::from(minute) * 60));
+    }
+
+    let time = now as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        bail!("Failed to convert {} to local time", now);
+    }
+
+    tm.tm_mday += days as libc::c_int;
+    tm.tm_hour = (minute / 60) as libc::c_int;
+    tm.tm_min = (minute % 60) as libc::c_int;
+    tm.tm_sec = 0;
+    // Let mktime work out whether DST applies on that day
+    tm.tm_isdst = -1;
+
+    let timestamp = unsafe { libc::mktime(&mut tm) };
+    if timestamp == -1 {
+        bail!("Failed to convert local time to a timestamp");
+    }
+    Ok(timestamp as i64)
+}
+
+impl std::fmt::Display for LocalTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {:02}:{:02}", WEEKDAYS[self.weekday], self.minute / 60, self.minute % 60)
+    }
+}
+
+/// A schedule rule with its days and times parsed.
+#[derive(Debug, Clone)]
+pub struct Window {
+    rule: ScheduleRule,
+    days: [bool; 7],
+    start: u32,
+    end: u32,
+}
+
+impl Window {
+    pub fn new(rule: ScheduleRule) -> Result<Self> {
+        let context = || format!("Invalid schedule rule '{}'", rule.name);
+
+        let days = parse_days(&rule.days).with_context(context)?;
+        let start = parse_time(&rule.start).with_context(context)?;
+        let end = parse_time(&rule.end).with_context(context)?;
+
+        Ok(Self { rule, days, start, end })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.rule.name
+    }
+
+    pub fn action(&self) -> ScheduleAction {
+        self.rule.action
+    }
+
+    pub fn source(&self) -> String {
+        format!("{}:{}", HOLD_KIND, self.rule.name)
+    }
+
+    pub fn reason(&self) -> String {
+        self.rule.reason.clone().unwrap_or_else(|| match self.rule.action {
+            ScheduleAction::Inhibit => format!("Scheduled: {}", self.rule.name),
+            ScheduleAction::Quiet => format!("Quiet hours: {}", self.rule.name),
+        })
+    }
+
+    pub fn is_active(&self, time: LocalTime) -> bool {
+        if self.start < self.end {
+            return self.days[time.weekday] && (self.start..self.end).contains(&time.minute);
+        }
+
+        // Runs past midnight: the tail belongs to the previous day's window
+        let yesterday = (time.weekday + 6) % 7;
+        (self.days[time.weekday] && time.minute >= self.start)
+            || (self.days[yesterday] && time.minute < self.end)
+    }
+}
+
+/// Accepts "mon".."sun" (or longer names) and ranges like "mon-fri" or "fri-mon".
+fn parse_days(entries: &[String]) -> Result<[bool; 7]> {
+    if entries.is_empty() {
+        return Ok([true; 7]);
+    }
+
+    let day = |name: &str| {
+        let name = name.trim().to_ascii_lowercase();
+        WEEKDAYS
+            .iter()
+            .position(|day| name.len() >= 3 && day.starts_with(&name[..3]))
+            .with_context(|| format!("Unknown weekday '{}'", name))
+    };
+
+    let mut days = [false; 7];
+    for entry in entries {
+        match entry.split_once('-') {
+            Some((from, to)) => {
+                let (from, to) = (day(from)?, day(to)?);
+                let mut current = from;
+                loop {
+                    days[current] = true;
+                    if current == to {
+                        break;
+                    }
+                    current = (current + 1) % 7;
+                }
+            }
+            None => days[day(entry)?] = true,
+        }
+    }
+
+    Ok(days)
+}
+
+/// Parses "HH:MM" into minutes since midnight; "24:00" is allowed as an end time.
+fn parse_time(value: &str) -> Result<u32> {
+    let parsed = value
+        .trim()
+        .split_once(':')
+        .and_then(|(h, m)| Some((h.parse::<u32>().ok()?, m.parse::<u32>().ok()?)));
+
+    match parsed {
+        Some((hour, minute)) if hour < 24 && minute < 60 => Ok(hour * 60 + minute),
+        Some((24, 0)) => Ok(24 * 60),
+        _ => bail!("Invalid time '{}', expected HH:MM", value),
+    }
+}
+
+/// Windows starting or ending at a point in time.
+#[derive(Debug, Clone)]
+pub struct Transition {
+    pub at: i64,
+    pub local: LocalTime,
+    /// Rule name and whether the window starts (true) or ends (false)
+    pub changes: Vec<(String, bool)>,
+}
+
+impl Transition {
+    /// e.g. `Mon 07:00 (in 1h 05m): night ends`
+    pub fn describe(&self, now: i64) -> String {
+        let changes: Vec<String> = self
+            .changes
+            .iter()
+            .map(|(name, starts)| format!("{} {}", name, if *starts { "starts" } else { "ends" }))
+            .collect();
+        let minutes = (self.at - now + 59) / 60;
+
+        format!("{} (in {}h {:02}m): {}", self.local, minutes / 60, minutes % 60, changes.join(", "))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    windows: Vec<Window>,
+    /// None follows the system's local time zone
+    zone: Option<TimeZone>,
+}
+
+impl Schedule {
+    pub fn new(rules: Vec<ScheduleRule>) -> Result<Self> {
+        let windows = rules.into_iter().map(Window::new).collect::<Result<_>>()?;
+        Ok(Self { windows, zone: None })
+    }
+
+    /// A schedule whose windows are read in `zone` rather than the system's local time.
+    pub fn in_zone(rules: Vec<ScheduleRule>, zone: TimeZone) -> Result<Self> {
+        Ok(Self { zone: Some(zone), ..Self::new(rules)? })
+    }
+
+    pub fn windows(&self) -> &[Window] {
+        &self.windows
+    }
+
+    /// Which windows are active at `timestamp`, in rule order.
+    pub fn active_at(&self, timestamp: i64) -> Result<Vec<bool>> {
+        let time = LocalTime::at(timestamp, self.zone.as_ref())?;
+        Ok(self.windows.iter().map(|window| window.is_active(time)).collect())
+    }
+
+    /// Every start and end of a window from yesterday (whose window may run past
+    /// midnight) until a week from now, as timestamps. Resolving each boundary on
+    /// its own date keeps DST shifts correct.
+    fn boundaries(&self, now: i64) -> Result<BTreeSet<i64>> {
+        let today = LocalTime::at(now, self.zone.as_ref())?.weekday as i64;
+
+        let mut boundaries = BTreeSet::new();
+        for offset in -1..=7 {
+            let weekday = (today + offset).rem_euclid(7) as usize;
+            for window in self.windows.iter().filter(|window| window.days[weekday]) {
+                let end_offset = if window.start < window.end { offset } else { offset + 1 };
+                boundaries.insert(local_timestamp(now, offset, window.start, self.zone.as_ref())?);
+                boundaries.insert(local_timestamp(now, end_offset, window.end, self.zone.as_ref())?);
+            }
+        }
+        Ok(boundaries)
+    }
+
+    /// The first time after `now` at which some window starts or ends. A boundary
+    /// where one window hands over to the next (e.g. an every-day 00:00-24:00
+    /// window) changes nothing and is skipped.
+    pub fn next_transition(&self, now: i64) -> Result<Option<Transition>> {
+        if self.windows.is_empty() {
+            return Ok(None);
+        }
+
+        let current = self.active_at(now)?;
+
+        for at in self.boundaries(now)?.into_iter().filter(|at| *at > now) {
+            let active = self.active_at(at)?;
+            if active == current {
+                continue;
+            }
+
+            let changes = self
+                .windows
+                .iter()
+                .zip(current.iter().zip(&active))
+                .filter(|(_, (before, after))| before != after)
+                .map(|(window, (_, after))| (window.name().to_string(), *after))
+                .collect();
+
+            return Ok(Some(Transition { at, local: LocalTime::at(at, self.zone.as_ref())?, changes }));
+        }
+
+        Ok(None)
+    }
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Re-reads the time zone so a changed /etc/localtime or TZ is picked up.
+fn refresh_time_zone() {
+    // The libc crate doesn't bind tzset for Unix targets
+    extern "C" {
+        fn tzset();
+    }
+    unsafe { tzset() };
+}
+
+/// Applies schedule windows as holds (inhibit) or suppressions (quiet hours),
+/// re-evaluating at each transition and whenever the system resumes.
+pub async fn watch(daemon: Daemon, config: ScheduleConfig) -> Result<()> {
+    use futures_util::StreamExt;
+    use zbus::MatchRule;
+
+    let schedule = Schedule::new(config.rules)?;
+
+    let connection = Connection::system()
+        .await
+        .context("Failed to connect to system D-Bus")?;
+
+    // tokio's timers pause during suspend, so resume triggers a re-evaluation
+    let match_rule = MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .sender("org.freedesktop.login1")?
+        .interface("org.freedesktop.login1.Manager")?
+        .member("PrepareForSleep")?
+        .build();
+
+    let proxy = zbus::fdo::DBusProxy::new(&connection).await?;
+    proxy.add_match_rule(match_rule).await?;
+
+    let mut stream = zbus::MessageStream::from(&connection);
+
+    tracing::info!("Watching schedule with {} rule(s)", schedule.windows().len());
+
+    let mut active = BTreeSet::new();
+    loop {
+        refresh_time_zone();
+        let now = now();
+
+        for (window, is_active) in schedule.windows().iter().zip(schedule.active_at(now)?) {
+            let source = window.source();
+
+            if is_active {
+                if active.insert(source.clone()) {
+                    tracing::info!("Schedule window '{}' started", window.name());
+                }
+                match window.action() {
+                    ScheduleAction::Inhibit => daemon.set_hold(&source, &window.reason()).await?,
+                    ScheduleAction::Quiet => daemon.set_suppression(&source, &window.reason()).await?,
+                }
+            } else if active.remove(&source) {
+                tracing::info!("Schedule window '{}' ended", window.name());
+                match window.action() {
+                    ScheduleAction::Inhibit => daemon.release_hold(&source).await?,
+                    ScheduleAction::Quiet => daemon.clear_suppression(&source).await?,
+                }
+            }
+        }
+
+        let wait = match schedule.next_transition(now)? {
+            Some(transition) => {
+                tracing::debug!("Next schedule transition at {}", transition.local);
+                (transition.at - now).clamp(1, MAX_SLEEP_SECS)
+            }
+            None => MAX_SLEEP_SECS,
+        };
+
+        let resumed = async {
+            while let Some(msg) = stream.next().await {
+                if let Ok(msg) = msg {
+                    if msg.header().member().is_some_and(|m| m.as_str() == "PrepareForSleep") {
+                        return;
+                    }
+                }
+            }
+            std::future::pending::<()>().await
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(wait as u64)) => {}
+            _ = resumed => tracing::info!("Sleep state changed, re-evaluating schedule"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zoneinfo::PosixRule;
+
+    /// 2024-01-01 00:00 UTC, a Monday.
+    const MONDAY: i64 = 1_704_067_200;
+    const HOUR: i64 = 3600;
+    const DAY: i64 = 24 * HOUR;
+
+    fn utc(rules: Vec<ScheduleRule>) -> Schedule {
+        Schedule::in_zone(rules, TimeZone::from_rule(PosixRule::parse("UTC0").unwrap())).unwrap()
+    }
+
+    fn rule(name: &str, days: &[&str], start: &str, end: &str) -> ScheduleRule {
+        ScheduleRule {
+            name: name.to_string(),
+            days: days.iter().map(|day| day.to_string()).collect(),
+            start: start.to_string(),
+            end: end.to_string(),
+            action: ScheduleAction::Inhibit,
+            reason: None,
+        }
+    }
+
+    fn days(entries: &[&str]) -> Result<[bool; 7]> {
+        parse_days(&entries.iter().map(|entry| entry.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn parses_days() {
+        assert_eq!(days(&[]).unwrap(), [true; 7]);
+        assert_eq!(days(&["mon-fri"]).unwrap(), [true, true, true, true, true, false, false]);
+        assert_eq!(days(&["fri-mon"]).unwrap(), [true, false, false, false, true, true, true]);
+        assert_eq!(days(&["Saturday", "wed"]).unwrap(), [false, false, true, false, false, true, false]);
+        assert_eq!(days(&["sun-sun"]).unwrap(), [false, false, false, false, false, false, true]);
+        assert!(days(&["mo"]).is_err());
+        assert!(days(&["funday"]).is_err());
+        assert!(days(&["mon-xyz"]).is_err());
+    }
+
+    #[test]
+    fn parses_times() {
+        assert_eq!(parse_time("00:00").unwrap(), 0);
+        assert_eq!(parse_time(" 07:30 ").unwrap(), 450);
+        assert_eq!(parse_time("23:59").unwrap(), 1439);
+        assert_eq!(parse_time("24:00").unwrap(), 1440);
+        for invalid in ["24:01", "12:60", "7", "7:", ":30", "ab:cd", "-1:00", ""] {
+            assert!(parse_time(invalid).is_err(), "{} should be rejected", invalid);
+        }
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let window = Window::new(rule("work", &["mon-fri"], "09:00", "17:00")).unwrap();
+        let at = |weekday, hour: u32, minute: u32| LocalTime { weekday, minute: hour * 60 + minute };
+
+        assert!(!window.is_active(at(0, 8, 59)));
+        assert!(window.is_active(at(0, 9, 0)));
+        assert!(window.is_active(at(4, 16, 59)));
+        assert!(!window.is_active(at(4, 17, 0)));
+        assert!(!window.is_active(at(5, 12, 0)));
+    }
+
+    #[test]
+    fn window_past_midnight() {
+        let window = Window::new(rule("night", &["fri"], "22:00", "06:00")).unwrap();
+        let at = |weekday, hour: u32| LocalTime { weekday, minute: hour * 60 };
+
+        assert!(!window.is_active(at(4, 21)));
+        assert!(window.is_active(at(4, 22)));
+        assert!(window.is_active(at(4, 23)));
+        // The tail belongs to Friday's window, not to Saturday's
+        assert!(window.is_active(at(5, 5)));
+        assert!(!window.is_active(at(5, 6)));
+        assert!(!window.is_active(at(5, 22)));
+        assert!(!window.is_active(at(4, 3)));
+    }
+
+    #[test]
+    fn next_transition_within_the_day() {
+        let schedule = utc(vec![rule("work", &["mon-fri"], "09:00", "17:00")]);
+
+        let transition = schedule.next_transition(MONDAY + 8 * HOUR).unwrap().unwrap();
+        assert_eq!(transition.at, MONDAY + 9 * HOUR);
+        assert_eq!(transition.changes, [("work".to_string(), true)]);
+        assert_eq!(transition.describe(MONDAY + 8 * HOUR), "mon 09:00 (in 1h 00m): work starts");
+
+        let transition = schedule.next_transition(MONDAY + 9 * HOUR).unwrap().unwrap();
+        assert_eq!(transition.at, MONDAY + 17 * HOUR);
+        assert_eq!(transition.changes, [("work".to_string(), false)]);
+    }
+
+    #[test]
+    fn next_transition_skips_the_weekend() {
+        let schedule = utc(vec![rule("work", &["mon-fri"], "09:00", "17:00")]);
+
+        let friday_evening = MONDAY + 4 * DAY + 17 * HOUR + 30 * 60;
+        let transition = schedule.next_transition(friday_evening).unwrap().unwrap();
+        assert_eq!(transition.at, MONDAY + 7 * DAY + 9 * HOUR);
+    }
+
+    #[test]
+    fn next_transition_past_midnight() {
+        let schedule = utc(vec![
+            rule("night", &["fri"], "22:00", "06:00"),
+            rule("late", &["fri"], "22:00", "23:00"),
+        ]);
+
+        // Saturday 03:00 is inside Friday's window
+        let transition = schedule.next_transition(MONDAY + 5 * DAY + 3 * HOUR).unwrap().unwrap();
+        assert_eq!(transition.at, MONDAY + 5 * DAY + 6 * HOUR);
+        assert_eq!(transition.changes, [("night".to_string(), false)]);
+
+        let transition = schedule.next_transition(MONDAY + 4 * DAY + 12 * HOUR).unwrap().unwrap();
+        assert_eq!(transition.at, MONDAY + 4 * DAY + 22 * HOUR);
+        assert_eq!(transition.changes, [("night".to_string(), true), ("late".to_string(), true)]);
+    }
+
+    #[test]
+    fn next_transition_skips_handovers() {
+        let always = utc(vec![rule("always", &[], "00:00", "24:00")]);
+        assert!(always.next_transition(MONDAY + 12 * HOUR).unwrap().is_none());
+
+        let split = utc(vec![
+            rule("morning", &[], "06:00", "12:00"),
+            rule("afternoon", &[], "12:00", "18:00"),
+        ]);
+        let transition = split.next_transition(MONDAY + 7 * HOUR).unwrap().unwrap();
+        assert_eq!(transition.at, MONDAY + 12 * HOUR);
+        assert_eq!(transition.changes, [("morning".to_string(), false), ("afternoon".to_string(), true)]);
+
+        assert!(utc(Vec::new()).next_transition(MONDAY).unwrap().is_none());
+    }
+
+    #[test]
+    fn next_transition_across_dst() {
+        let zone = TimeZone::from_rule(PosixRule::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap());
+        let schedule = Schedule::in_zone(vec![rule("work", &[], "09:00", "17:00")], zone).unwrap();
+
+        // Saturday 2024-03-30 21:00 CET; clocks go forward overnight
+        let transition = schedule.next_transition(MONDAY + 89 * DAY + 20 * HOUR).unwrap().unwrap();
+        assert_eq!(transition.at, MONDAY + 90 * DAY + 7 * HOUR);
+        assert_eq!(transition.local, LocalTime { weekday: 6, minute: 9 * 60 });
+    }
+}
@@ -0,0 +1,440 @@
+use anyhow::{anyhow, bail, Result};
+use rhai::{Dynamic, Engine, Scope, AST};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::config::{RuleAction, ScriptsConfig};
+use crate::daemon::Daemon;
+use crate::holds;
+use crate::session::LOCKED_FACT;
+
+/// Hold and suppression kind for script decisions; sources are `script:<file stem>`.
+pub const HOLD_KIND: &str = "script";
+
+/// Caps on the size of any string, array or map a script builds.
+const MAX_STRING_SIZE: usize = 64 * 1024;
+const MAX_COLLECTION_SIZE: usize = 10_000;
+
+/// Minimum time between runs triggered by change notifications, so a script whose
+/// decision feeds back into its own inputs (e.g. one that reads `state`) can't spin.
+const MIN_RERUN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What a script asked for on its last run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub action: RuleAction,
+    pub reason: Option<String>,
+}
+
+/// Interprets a script's result: `()` or `"none"` for no opinion, `"hold"` or
+/// `"release"`, or a map such as `#{ action: "hold", reason: "Rendering" }`.
+pub fn parse_decision(value: Dynamic) -> Result<Option<Decision>> {
+    let (action, reason) = if value.is_map() {
+        let map = value.cast::<rhai::Map>();
+        let reason = match map.get("reason") {
+            Some(reason) if !reason.is_unit() => Some(
+                reason
+                    .clone()
+                    .into_string()
+                    .map_err(|ty| anyhow!("reason must be a string, not {}", ty))?,
+            ),
+            _ => None,
+        };
+        (map.get("action").cloned().unwrap_or(Dynamic::UNIT), reason)
+    } else {
+        (value, None)
+    };
+
+    if action.is_unit() {
+        return Ok(None);
+    }
+    let action = action
+        .into_string()
+        .map_err(|ty| anyhow!("Expected \"hold\", \"release\" or \"none\", got {}", ty))?;
+
+    let action = match action.to_ascii_lowercase().as_str() {
+        "none" => return Ok(None),
+        "hold" => RuleAction::Hold,
+        "release" => RuleAction::Release,
+        other => bail!("Unknown decision '{}'", other),
+    };
+    Ok(Some(Decision { action, reason }))
+}
+
+/// Read-only view of the daemon handed to scripts. Script decisions themselves
+/// are left out so scripts can't feed back into one another.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub state: String,
+    pub locked: bool,
+    pub holds: BTreeMap<String, String>,
+    pub suppressions: BTreeMap<String, String>,
+    pub facts: BTreeSet<String>,
+    pub readings: BTreeMap<String, String>,
+}
+
+impl Snapshot {
+    pub async fn take(daemon: &Daemon) -> Self {
+        let own = |source: &str| holds::source_kind(source) == HOLD_KIND;
+
+        let state = daemon.state().await;
+        let facts = daemon.facts().await;
+        Self {
+            state: if state.is_enabled() {
+                "enabled"
+            } else if state.is_failed() {
+                "failed"
+            } else {
+                "disabled"
+            }
+            .to_string(),
+            locked: facts.contains(LOCKED_FACT),
+            holds: daemon
+                .holds()
+                .await
+                .iter()
+                .filter(|(source, _)| !own(source))
+                .map(|(source, hold)| (source.to_string(), hold.reason.clone()))
+                .collect(),
+            suppressions: daemon
+                .suppressions()
+                .await
+                .into_iter()
+                .filter(|(source, _)| !own(source))
+                .collect(),
+            facts,
+            readings: daemon.readings().await,
+        }
+    }
+
+    /// Same matching as rule conditions: `process` is active while any
+    /// `process:*` hold is, `power:ac` while that fact is true.
+    pub fn active(&self, name: &str) -> bool {
+        self.holds
+            .keys()
+            .chain(self.suppressions.keys())
+            .chain(self.facts.iter())
+            .any(|source| holds::source_matches(source, name))
+    }
+
+    fn scope(&self) -> Scope<'static> {
+        fn map(entries: &BTreeMap<String, String>) -> rhai::Map {
+            entries
+                .iter()
+                .map(|(key, value)| (key.as_str().into(), value.clone().into()))
+                .collect()
+        }
+
+        let mut scope = Scope::new();
+        scope.push_constant("state", self.state.clone());
+        scope.push_constant("locked", self.locked);
+        scope.push_constant("holds", map(&self.holds));
+        scope.push_constant("suppressions", map(&self.suppressions));
+        scope.push_constant(
+            "facts",
+            self.facts.iter().map(|fact| Dynamic::from(fact.clone())).collect::<rhai::Array>(),
+        );
+        scope.push_constant("readings", map(&self.readings));
+        scope
+    }
+}
+
+/// A sandboxed Rhai engine and the scripts it runs.
+pub struct ScriptHost {
+    engine: Engine,
+    /// The snapshot `active()` reads during a run.
+    snapshot: Arc<RwLock<Snapshot>>,
+    /// Compiled scripts by name, or the compile error.
+    scripts: BTreeMap<String, Result<AST, String>>,
+}
+
+impl ScriptHost {
+    pub fn new(config: &ScriptsConfig) -> Result<Self> {
+        if config.max_operations == 0 {
+            bail!("scripts.max_operations must be at least 1");
+        }
+
+        let snapshot = Arc::new(RwLock::new(Snapshot::default()));
+        let mut engine = Engine::new();
+
+        // Scripts can't import other files or eval strings, and their work and
+        // memory are bounded so a runaway script can't stall the daemon
+        engine.set_module_resolver(rhai::module_resolvers::DummyModuleResolver::new());
+        engine.disable_symbol("eval");
+        engine.set_max_operations(config.max_operations);
+        engine.set_max_call_levels(config.max_call_depth);
+        engine.set_max_expr_depths(64, 32);
+        engine.set_max_string_size(MAX_STRING_SIZE);
+        engine.set_max_array_size(MAX_COLLECTION_SIZE);
+        engine.set_max_map_size(MAX_COLLECTION_SIZE);
+
+        engine.on_print(|text| tracing::info!("Script: {}", text));
+        engine.on_debug(|text, source, _| tracing::debug!("Script {}: {}", source.unwrap_or("?"), text));
+
+        let view = snapshot.clone();
+        engine.register_fn("active", move |name: &str| {
+            view.read().map(|snapshot| snapshot.active(name)).unwrap_or(false)
+        });
+
+        Ok(Self { engine, snapshot, scripts: BTreeMap::new() })
+    }
+
+    /// Compiles `files`, replacing every previously loaded script.
+    pub fn load<'a>(&mut self, files: impl IntoIterator<Item = &'a Path>) {
+        self.scripts.clear();
+        for path in files {
+            let Some(name) = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()) else {
+                continue;
+            };
+            let compiled = std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))
+                .and_then(|source| self.engine.compile(source).map_err(|e| e.to_string()))
+                .map(|mut ast| {
+                    ast.set_source(name.as_str());
+                    ast
+                });
+
+            match &compiled {
+                Ok(_) => tracing::info!("Loaded script '{}' from {}", name, path.display()),
+                Err(e) => tracing::warn!("Script '{}' failed to load: {}", name, e),
+            }
+            if self.scripts.insert(name.clone(), compiled).is_some() {
+                tracing::warn!("Script '{}' is defined more than once; using {}", name, path.display());
+            }
+        }
+    }
+
+    /// Runs every script against `snapshot`, returning each one's decision.
+    pub fn run(&self, snapshot: Snapshot) -> BTreeMap<String, Result<Option<Decision>>> {
+        let scope = snapshot.scope();
+        if let Ok(mut view) = self.snapshot.write() {
+            *view = snapshot;
+        }
+
+        self.scripts
+            .iter()
+            .map(|(name, compiled)| {
+                let result = match compiled {
+                    Ok(ast) => self
+                        .engine
+                        .eval_ast_with_scope::<Dynamic>(&mut scope.clone(), ast)
+                        .map_err(|e| anyhow!("{}", e))
+                        .and_then(parse_decision),
+                    Err(e) => Err(anyhow!("{}", e)),
+                };
+                (name.clone(), result)
+            })
+            .collect()
+    }
+}
+
+fn describe(decision: &Option<Decision>) -> String {
+    match decision {
+        None => "no decision".to_string(),
+        Some(Decision { action, reason }) => {
+            let action = match action {
+                RuleAction::Hold => "hold",
+                RuleAction::Release => "release",
+            };
+            match reason {
+                Some(reason) => format!("{} ({})", action, reason),
+                None => action.to_string(),
+            }
+        }
+    }
+}
+
+/// Runs the configured scripts whenever the daemon's state changes and on every
+/// interval, applying their decisions as `script:<name>` holds or suppressions.
+/// Script files are reloaded when they change on disk.
+pub async fn watch(daemon: Daemon, config: ScriptsConfig) -> Result<()> {
+    let mut host = ScriptHost::new(&config)?;
+    let mut changes = daemon.subscribe();
+    let period = Duration::from_secs(config.interval_secs.max(1));
+    // The first run happens right away below, so the first tick is one period out
+    let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+
+    let mut files = BTreeMap::new();
+    let mut applied: BTreeMap<String, Decision> = BTreeMap::new();
+    let mut errors: BTreeMap<String, String> = BTreeMap::new();
+
+    tracing::info!("Watching {} script path(s)", config.paths.len());
+
+    loop {
+        let started = Instant::now();
+        let current = crate::config::watched_files(&config.paths, "rhai");
+        if current != files {
+            host.load(current.keys().map(PathBuf::as_path));
+            for name in applied.keys().chain(errors.keys()) {
+                if !host.scripts.contains_key(name) {
+                    daemon.set_reading(&format!("{}:{}", HOLD_KIND, name), "unloaded".to_string()).await;
+                }
+            }
+            files = current;
+        }
+
+        let mut wanted = BTreeMap::new();
+        // Even bounded, a run can take a while; keep it off the async workers.
+        // The host moves into the blocking task and comes back with the results.
+        let snapshot = Snapshot::take(&daemon).await;
+        let (returned, results) = tokio::task::spawn_blocking(move || {
+            let results = host.run(snapshot);
+            (host, results)
+        })
+        .await?;
+        host = returned;
+
+        for (name, result) in results {
+            let source = format!("{}:{}", HOLD_KIND, name);
+            let decision = match result {
+                Ok(decision) => {
+                    errors.remove(&name);
+                    decision
+                }
+                Err(e) => {
+                    // A failing script runs on every change; only report new errors
+                    let message = format!("{:#}", e);
+                    if errors.get(&name) != Some(&message) {
+                        tracing::warn!("Script '{}' failed: {}", name, message);
+                        errors.insert(name.clone(), message.clone());
+                    }
+                    daemon.set_reading(&source, format!("error: {}", message)).await;
+                    continue;
+                }
+            };
+
+            daemon.set_reading(&source, describe(&decision)).await;
+            if let Some(decision) = decision {
+                wanted.insert(name, decision);
+            }
+        }
+
+        for (name, decision) in &applied {
+            // Reason changes are applied in place below
+            if wanted.get(name).is_some_and(|wanted| wanted.action == decision.action) {
+                continue;
+            }
+            let source = format!("{}:{}", HOLD_KIND, name);
+            match decision.action {
+                RuleAction::Hold => daemon.release_hold(&source).await?,
+                RuleAction::Release => daemon.clear_suppression(&source).await?,
+            }
+        }
+        for (name, decision) in &wanted {
+            let source = format!("{}:{}", HOLD_KIND, name);
+            match decision.action {
+                RuleAction::Hold => {
+                    let reason = decision.reason.clone().unwrap_or_else(|| format!("Script {}", name));
+                    daemon.set_hold(&source, &reason).await?;
+                }
+                RuleAction::Release => {
+                    let reason = decision
+                        .reason
+                        .clone()
+                        .unwrap_or_else(|| format!("Script {} forces release", name));
+                    daemon.set_suppression(&source, &reason).await?;
+                }
+            }
+        }
+        applied = wanted;
+
+        // Applying the decisions notified every subscriber, this watcher included;
+        // those changes are our own and must not trigger another run
+        changes.borrow_and_update();
+
+        tokio::select! {
+            _ = interval.tick() => {}
+            result = changes.changed() => {
+                result?;
+                // Changes arriving in the meantime are picked up by the same run
+                tokio::time::sleep_until(started + MIN_RERUN_INTERVAL).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn host_with(source: &str, config: &ScriptsConfig) -> ScriptHost {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.rhai");
+        std::fs::write(&path, source).unwrap();
+        let mut host = ScriptHost::new(config).unwrap();
+        host.load([path.as_path()]);
+        host
+    }
+
+    #[test]
+    fn requires_an_operation_limit() {
+        let config = ScriptsConfig { max_operations: 0, ..Default::default() };
+        assert!(ScriptHost::new(&config).is_err());
+    }
+
+    #[test]
+    fn runaway_scripts_are_aborted() {
+        let config = ScriptsConfig { max_operations: 1000, ..Default::default() };
+        let host = host_with("loop {}", &config);
+        assert!(host.run(Snapshot::default())["test"].is_err());
+    }
+
+    #[test]
+    fn decisions() {
+        let host = host_with(
+            r#"if active("media") { #{ action: "hold", reason: "Playing" } } else { "release" }"#,
+            &ScriptsConfig::default(),
+        );
+
+        let mut snapshot = Snapshot::default();
+        snapshot.holds.insert("media:mpv".to_string(), "Playing".to_string());
+        let decision = host.run(snapshot).remove("test").unwrap().unwrap();
+        assert_eq!(decision, Some(Decision { action: RuleAction::Hold, reason: Some("Playing".to_string()) }));
+
+        let decision = host.run(Snapshot::default()).remove("test").unwrap().unwrap();
+        assert_eq!(decision, Some(Decision { action: RuleAction::Release, reason: None }));
+    }
+
+    #[tokio::test]
+    async fn scripts_reading_their_own_effect_do_not_spin() {
+        let dir = tempfile::tempdir().unwrap();
+        // Holding enables the inhibitor, which makes the script release again
+        std::fs::write(
+            dir.path().join("flip.rhai"),
+            r#"if state == "enabled" { "release" } else { "hold" }"#,
+        )
+        .unwrap();
+        let config = ScriptsConfig {
+            enabled: true,
+            paths: vec![dir.path().display().to_string()],
+            interval_secs: 3600,
+            ..Default::default()
+        };
+
+        let daemon = crate::daemon::Daemon::detached(crate::Config::default());
+        let mut changes = daemon.subscribe();
+        let watcher = tokio::spawn(watch(daemon.clone(), config));
+
+        let mut notifications = 0;
+        let window = tokio::time::sleep(Duration::from_millis(2500));
+        tokio::pin!(window);
+        loop {
+            tokio::select! {
+                _ = &mut window => break,
+                result = changes.changed() => {
+                    result.unwrap();
+                    notifications += 1;
+                }
+            }
+        }
+        watcher.abort();
+
+        // The first run holds and nothing else runs it again before the interval
+        assert!(daemon.holds().await.iter().any(|(source, _)| source == "script:flip"));
+        assert!(daemon.state().await.is_enabled());
+        assert!(notifications <= 1, "{} notifications", notifications);
+        assert_eq!(daemon.readings().await["script:flip"], "hold");
+    }
+}
@@ -0,0 +1,159 @@
+use crate::daemon::Daemon;
+use crate::dbus::{self, HoldInfo, RuleInfo, SystemInhibitor};
+use crate::holds;
+use crate::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use zbus::message::Header;
+use zbus::names::BusName;
+use zbus::{fdo, interface, Connection};
+
+/// The daemon's exported `com.logind.IdleControl` object.
+pub struct IdleControlService {
+    daemon: Daemon,
+    /// Makes client hold ids unique, so one process can hold several
+    next_hold: AtomicU64,
+}
+
+impl IdleControlService {
+    pub fn new(daemon: Daemon) -> Self {
+        Self { daemon, next_hold: AtomicU64::new(1) }
+    }
+}
+
+fn failed(e: anyhow::Error) -> fdo::Error {
+    fdo::Error::Failed(format!("{:#}", e))
+}
+
+/// The PID of the process that sent a method call.
+async fn sender_pid(header: &Header<'_>, connection: &Connection) -> fdo::Result<u32> {
+    let sender = header
+        .sender()
+        .ok_or_else(|| fdo::Error::Failed("Message has no sender".to_string()))?;
+    fdo::DBusProxy::new(connection)
+        .await?
+        .get_connection_unix_process_id(BusName::from(sender.to_owned()))
+        .await
+}
+
+/// The PID owning a `client:<pid>:<n>` hold id.
+fn client_hold_owner(id: &str) -> Option<u32> {
+    let rest = id.strip_prefix(holds::CLIENT)?.strip_prefix(':')?;
+    let (pid, counter) = rest.split_once(':')?;
+    counter.parse::<u64>().ok()?;
+    pid.parse().ok()
+}
+
+#[interface(name = "com.logind.IdleControl")]
+impl IdleControlService {
+    /// Every inhibitor logind knows about, with ours flagged.
+    async fn list_system_inhibitors(&self) -> fdo::Result<Vec<SystemInhibitor>> {
+        dbus::list_system_inhibitors().await.map_err(failed)
+    }
+    
+    /// Registers a hold for the calling process; it is released when the caller
+    /// exits or calls `ReleaseHold`. Returns the hold id.
+    async fn add_hold(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        what: &str,
+        reason: &str,
+    ) -> fdo::Result<String> {
+        holds::validate_what(what).map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+        
+        let pid = sender_pid(&header, connection).await?;
+        let counter = self.next_hold.fetch_add(1, Ordering::Relaxed);
+        let id = format!("{}:{}:{}", holds::CLIENT, pid, counter);
+        self.daemon
+            .hold_for_pid(pid, id.clone(), what, reason)
+            .await
+            .map_err(failed)?;
+        
+        Ok(id)
+    }
+    
+    /// Releases a hold previously returned by `AddHold` to the same process.
+    async fn release_hold(
+        &self,
+        #[zbus(header)] header: Header<'_>,
+        #[zbus(connection)] connection: &Connection,
+        id: &str,
+    ) -> fdo::Result<()> {
+        let Some(owner) = client_hold_owner(id) else {
+            return Err(fdo::Error::InvalidArgs(format!("Not a client hold: {}", id)));
+        };
+        if sender_pid(&header, connection).await? != owner {
+            return Err(fdo::Error::AccessDenied(format!("Hold {} belongs to another process", id)));
+        }
+        self.daemon.release_hold(id).await.map_err(failed)
+    }
+    
+    /// Holds a plain idle inhibitor until `pid` exits. An empty reason names the
+    /// process instead. Returns the hold id.
+    async fn attach_pid(&self, pid: u32, reason: &str) -> fdo::Result<String> {
+        let reason = if reason.is_empty() {
+            let comm = process::command_name(pid).unwrap_or_else(|| "process".to_string());
+            format!("Waiting for {} (PID {})", comm, pid)
+        } else {
+            reason.to_string()
+        };
+        
+        let id = format!("{}:{}", holds::ATTACH, pid);
+        self.daemon
+            .hold_for_pid(pid, id.clone(), holds::DEFAULT_WHAT, &reason)
+            .await
+            .map_err(failed)?;
+        
+        Ok(id)
+    }
+    
+    /// Every active hold: source, what, reason.
+    async fn list_holds(&self) -> Vec<HoldInfo> {
+        self.daemon
+            .holds()
+            .await
+            .iter()
+            .map(|(source, hold)| HoldInfo {
+                source: source.to_string(),
+                what: hold.what.clone(),
+                reason: hold.reason.clone(),
+            })
+            .collect()
+    }
+    
+    /// Policies currently forcing the inhibitor off: source, reason.
+    async fn list_suppressions(&self) -> Vec<(String, String)> {
+        self.daemon.suppressions().await.into_iter().collect()
+    }
+    
+    /// Latest measurements from watchers that sample a value: source, value.
+    async fn list_readings(&self) -> Vec<(String, String)> {
+        self.daemon.readings().await.into_iter().collect()
+    }
+    
+    /// Every rule with whether it is true, whether it decides the outcome, and why.
+    async fn explain_rules(&self) -> Vec<RuleInfo> {
+        self.daemon.explain_rules().await
+    }
+    
+    /// Number of times the daemon found its inhibitor missing from logind and re-acquired it.
+    #[zbus(property)]
+    fn repair_count(&self) -> u32 {
+        self.daemon.repair_count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_hold_owners() {
+        assert_eq!(client_hold_owner("client:1234:7"), Some(1234));
+        assert_eq!(client_hold_owner("client:1234"), None);
+        assert_eq!(client_hold_owner("client:abc:1"), None);
+        assert_eq!(client_hold_owner("client:1234:x"), None);
+        assert_eq!(client_hold_owner("attach:1234:1"), None);
+        assert_eq!(client_hold_owner("clients:1234:1"), None);
+    }
+}
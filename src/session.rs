@@ -1,6 +1,10 @@
 use anyhow::{Context, Result, bail};
 use zbus::{proxy, Connection};
 use zbus::zvariant::OwnedObjectPath;
+use crate::dbus::Login1ManagerProxy;
+
+/// Fact that is true while this session is locked.
+pub const LOCKED_FACT: &str = "session:locked";
 
 #[derive(Debug, Clone)]
 pub struct SessionInfo {
@@ -8,16 +12,6 @@ pub struct SessionInfo {
     pub path: OwnedObjectPath,
 }
 
-#[proxy(
-    interface = "org.freedesktop.login1.Manager",
-    default_service = "org.freedesktop.login1",
-    default_path = "/org/freedesktop/login1"
-)]
-trait Login1Manager {
-    fn get_session_by_pid(&self, pid: u32) -> zbus::Result<(String, OwnedObjectPath)>;
-    fn get_session(&self, session_id: &str) -> zbus::Result<OwnedObjectPath>;
-}
-
 #[proxy(
     interface = "org.freedesktop.login1.Session",
     default_service = "org.freedesktop.login1"
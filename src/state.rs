@@ -1,10 +1,13 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum State {
     Enabled,
     Disabled,
+    /// Inhibition was requested but logind refused it; carries the reason.
+    Failed(String),
 }
 
 impl State {
@@ -13,8 +16,18 @@ impl State {
         
         if state_path.exists() {
             let content = std::fs::read_to_string(&state_path)?;
-            match content.trim() {
-                "1" => Ok(Self::Enabled),
+            let mut lines = content.lines();
+            match lines.next().map(str::trim) {
+                Some("1") => Ok(Self::Enabled),
+                Some("2") => {
+                    let reason = lines.collect::<Vec<_>>().join("\n");
+                    let reason = reason.trim();
+                    if reason.is_empty() {
+                        Ok(Self::Failed("unknown error".to_string()))
+                    } else {
+                        Ok(Self::Failed(reason.to_string()))
+                    }
+                }
                 _ => Ok(Self::Disabled),
             }
         } else {
@@ -30,25 +43,30 @@ impl State {
         }
         
         let content = match self {
-            Self::Enabled => "1",
-            Self::Disabled => "0",
+            Self::Enabled => "1".to_string(),
+            Self::Disabled => "0".to_string(),
+            Self::Failed(reason) => format!("2\n{}\n", reason),
         };
         
         std::fs::write(state_path, content)?;
         Ok(())
     }
     
-    pub fn toggle(&self) -> Self {
-        match self {
-            Self::Enabled => Self::Disabled,
-            Self::Disabled => Self::Enabled,
-        }
-    }
-    
     pub fn is_enabled(&self) -> bool {
         matches!(self, Self::Enabled)
     }
     
+    pub fn is_failed(&self) -> bool {
+        matches!(self, Self::Failed(_))
+    }
+    
+    pub fn failure_reason(&self) -> Option<&str> {
+        match self {
+            Self::Failed(reason) => Some(reason),
+            _ => None,
+        }
+    }
+    
     pub fn state_path() -> PathBuf {
         let runtime_dir = std::env::var("XDG_RUNTIME_DIR")
             .ok()
@@ -63,11 +81,45 @@ impl State {
     }
 }
 
+/// Manual holds (source to reason), persisted apart from the state: an enabled
+/// state may only reflect automatic holds, which their watchers re-register
+/// after a restart anyway.
+pub fn load_user_holds() -> Result<BTreeMap<String, String>> {
+    let path = user_holds_path();
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+
+    let content = std::fs::read_to_string(&path)?;
+    serde_json::from_str(&content).with_context(|| format!("Invalid hold file {}", path.display()))
+}
+
+pub fn save_user_holds(holds: &BTreeMap<String, String>) -> Result<()> {
+    let path = user_holds_path();
+    if holds.is_empty() {
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, serde_json::to_string(holds)?)?;
+    Ok(())
+}
+
+pub fn user_holds_path() -> PathBuf {
+    State::state_path().with_extension("holds")
+}
+
 impl std::fmt::Display for State {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Enabled => write!(f, "1"),
             Self::Disabled => write!(f, "0"),
+            Self::Failed(_) => write!(f, "2"),
         }
     }
 }
@@ -0,0 +1,158 @@
+use anyhow::{bail, Context, Result};
+use futures_util::{FutureExt, StreamExt};
+use glob::Pattern;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use zbus::zvariant::OwnedObjectPath;
+use zbus::{proxy, Connection, MatchRule};
+use crate::config::SystemdConfig;
+use crate::daemon::Daemon;
+use crate::holds;
+
+/// Hold kind for units; sources are `systemd:<user|system>:<unit>`.
+pub const HOLD_KIND: &str = "systemd";
+
+/// Lets a burst of unit signals settle before re-listing units.
+const SETTLE: Duration = Duration::from_millis(250);
+
+/// name, description, load state, active state, sub state, followed unit,
+/// object path, job id, job type, job path
+type UnitEntry = (String, String, String, String, String, String, OwnedObjectPath, u32, String, OwnedObjectPath);
+
+#[proxy(
+    interface = "org.freedesktop.systemd1.Manager",
+    default_service = "org.freedesktop.systemd1",
+    default_path = "/org/freedesktop/systemd1"
+)]
+trait SystemdManager {
+    fn subscribe(&self) -> zbus::Result<()>;
+
+    fn list_units(&self) -> zbus::Result<Vec<UnitEntry>>;
+}
+
+/// Which systemd instance a unit belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    User,
+    System,
+}
+
+impl Scope {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::User => "user",
+            Scope::System => "system",
+        }
+    }
+}
+
+fn compile(patterns: &[String]) -> Vec<Pattern> {
+    patterns
+        .iter()
+        .filter_map(|pattern| match Pattern::new(pattern) {
+            Ok(pattern) => Some(pattern),
+            Err(e) => {
+                tracing::warn!("Skipping invalid unit pattern '{}': {}", pattern, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Matching units that are `active` or `activating`, with their state.
+async fn running_units(manager: &SystemdManagerProxy<'_>, patterns: &[Pattern]) -> Result<BTreeMap<String, String>> {
+    let units = manager.list_units().await.context("Failed to list systemd units")?;
+
+    Ok(units
+        .into_iter()
+        .filter(|unit| matches!(unit.3.as_str(), "active" | "activating"))
+        .filter(|unit| patterns.iter().any(|pattern| pattern.matches(&unit.0)))
+        .map(|unit| (unit.0, unit.3))
+        .collect())
+}
+
+/// Holds the configured inhibitor while matching units on one bus are running.
+async fn watch_bus(daemon: Daemon, connection: Connection, scope: Scope, patterns: Vec<Pattern>, what: String) -> Result<()> {
+    let manager = SystemdManagerProxy::new(&connection).await?;
+    // Without a subscriber systemd doesn't emit unit signals at all
+    manager.subscribe().await.context("Failed to subscribe to systemd signals")?;
+
+    let manager_rule = MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .sender("org.freedesktop.systemd1")?
+        .interface("org.freedesktop.systemd1.Manager")?
+        .build();
+
+    let properties_rule = MatchRule::builder()
+        .msg_type(zbus::message::Type::Signal)
+        .sender("org.freedesktop.systemd1")?
+        .interface("org.freedesktop.DBus.Properties")?
+        .member("PropertiesChanged")?
+        .arg(0, "org.freedesktop.systemd1.Unit")?
+        .build();
+
+    let proxy = zbus::fdo::DBusProxy::new(&connection).await?;
+    proxy.add_match_rule(manager_rule).await?;
+    proxy.add_match_rule(properties_rule).await?;
+
+    let mut stream = zbus::MessageStream::from(&connection);
+
+    tracing::info!("Watching {} systemd units ({} pattern(s))", scope.name(), patterns.len());
+
+    let mut active: BTreeMap<String, String> = BTreeMap::new();
+    loop {
+        let running = running_units(&manager, &patterns).await?;
+
+        for unit in active.keys().filter(|unit| !running.contains_key(*unit)) {
+            tracing::info!("{} unit {} stopped", scope.name(), unit);
+            daemon.release_hold(&format!("{}:{}:{}", HOLD_KIND, scope.name(), unit)).await?;
+        }
+        for (unit, state) in &running {
+            if !active.contains_key(unit) {
+                tracing::info!("{} unit {} is {}", scope.name(), unit, state);
+            }
+            let source = format!("{}:{}:{}", HOLD_KIND, scope.name(), unit);
+            daemon.set_hold_what(&source, &what, &format!("{} is running", unit)).await?;
+        }
+        active = running;
+
+        // Method replies share the stream; only signals warrant a re-list
+        loop {
+            match stream.next().await {
+                Some(Ok(msg)) if msg.message_type() == zbus::message::Type::Signal => break,
+                Some(_) => continue,
+                None => bail!("D-Bus connection closed"),
+            }
+        }
+
+        tokio::time::sleep(SETTLE).await;
+        while let Some(Some(_)) = stream.next().now_or_never() {}
+    }
+}
+
+/// Watches configured units on the user and system buses.
+pub async fn watch(daemon: Daemon, config: SystemdConfig) -> Result<()> {
+    holds::validate_what(&config.what)?;
+
+    let user = compile(&config.user_units);
+    let system = compile(&config.system_units);
+
+    let user_watch = async {
+        if user.is_empty() {
+            return Ok(());
+        }
+        let connection = Connection::session().await.context("Failed to connect to session D-Bus")?;
+        watch_bus(daemon.clone(), connection, Scope::User, user, config.what.clone()).await
+    };
+
+    let system_watch = async {
+        if system.is_empty() {
+            return Ok(());
+        }
+        let connection = Connection::system().await.context("Failed to connect to system D-Bus")?;
+        watch_bus(daemon.clone(), connection, Scope::System, system, config.what.clone()).await
+    };
+
+    tokio::try_join!(user_watch, system_watch)?;
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+/// Multicast group of raw kernel uevents; udev rebroadcasts on group 2.
+const KERNEL_UEVENT_GROUP: u32 = 1;
+
+/// One kernel uevent, e.g. `add@/devices/pci0000:00/.../1-2` with its properties.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uevent {
+    pub action: String,
+    pub devpath: String,
+    pub properties: BTreeMap<String, String>,
+}
+
+impl Uevent {
+    pub fn subsystem(&self) -> Option<&str> {
+        self.properties.get("SUBSYSTEM").map(String::as_str)
+    }
+}
+
+/// Parses a NUL-separated kernel uevent message. udev's own messages (which
+/// start with "libudev") are not kernel events and yield `None`.
+pub fn parse_uevent(message: &[u8]) -> Option<Uevent> {
+    let mut fields = message
+        .split(|byte| *byte == 0)
+        .filter(|field| !field.is_empty())
+        .map(String::from_utf8_lossy);
+
+    let header = fields.next()?;
+    let (action, devpath) = header.split_once('@')?;
+
+    Some(Uevent {
+        action: action.to_string(),
+        devpath: devpath.to_string(),
+        properties: fields
+            .filter_map(|field| {
+                let (key, value) = field.split_once('=').filter(|(key, _)| !key.is_empty())?;
+                Some((key.to_string(), value.to_string()))
+            })
+            .collect(),
+    })
+}
+
+/// A netlink socket receiving kernel uevents.
+pub struct UeventSocket {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl UeventSocket {
+    pub fn open() -> Result<Self> {
+        let raw = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if raw < 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to open uevent socket");
+        }
+
+        // SAFETY: socket returned a fresh descriptor that nothing else owns
+        let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = KERNEL_UEVENT_GROUP;
+        let bound = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if bound < 0 {
+            return Err(std::io::Error::last_os_error()).context("Failed to bind uevent socket");
+        }
+
+        let fd = AsyncFd::with_interest(fd, Interest::READABLE)
+            .context("Failed to register uevent socket with the runtime")?;
+        Ok(Self { fd })
+    }
+
+    /// The next raw message. Fails with ENOBUFS when events were dropped.
+    pub async fn recv(&self) -> std::io::Result<Vec<u8>> {
+        let mut buf = vec![0u8; 8192];
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let result = guard.try_io(|fd| {
+                let read = unsafe { libc::recv(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+                if read < 0 {
+                    Err(std::io::Error::last_os_error())
+                } else {
+                    Ok(read as usize)
+                }
+            });
+            if let Ok(result) = result {
+                buf.truncate(result?);
+                return Ok(buf);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_kernel_messages() {
+        let message = b"add@/devices/pci0000:00/0000:00:14.0/usb1/1-2\0ACTION=add\0DEVPATH=/devices/pci0000:00/0000:00:14.0/usb1/1-2\0SUBSYSTEM=usb\0DEVTYPE=usb_device\0PRODUCT=17ef/a396/5110\0SEQNUM=4711\0";
+        let event = parse_uevent(message).unwrap();
+
+        assert_eq!(event.action, "add");
+        assert_eq!(event.devpath, "/devices/pci0000:00/0000:00:14.0/usb1/1-2");
+        assert_eq!(event.subsystem(), Some("usb"));
+        assert_eq!(event.properties.get("PRODUCT").map(String::as_str), Some("17ef/a396/5110"));
+        assert_eq!(event.properties.len(), 6);
+    }
+
+    #[test]
+    fn tolerates_malformed_messages() {
+        assert_eq!(parse_uevent(b""), None);
+        assert_eq!(parse_uevent(b"\0\0"), None);
+        // udev's rebroadcasts have a binary header instead of action@devpath
+        assert_eq!(parse_uevent(b"libudev\0\xfe\xed\xca\xfe"), None);
+
+        // Fields without a key are skipped, values may contain '=', bad UTF-8 is replaced
+        let event = parse_uevent(b"remove@/devices/x\0garbage\0KEY=a=b\0\0NAME=\xff\0=empty\0").unwrap();
+        assert_eq!(event.action, "remove");
+        assert_eq!(event.subsystem(), None);
+        assert_eq!(event.properties.get("KEY").map(String::as_str), Some("a=b"));
+        assert_eq!(event.properties.get("NAME").map(String::as_str), Some("\u{fffd}"));
+        assert_eq!(event.properties.len(), 2);
+
+        // A message cut off mid-field keeps what arrived
+        let event = parse_uevent(b"change@/devices/y\0SUBSYSTEM=power_supply\0POWER_SUPPLY_ON").unwrap();
+        assert_eq!(event.subsystem(), Some("power_supply"));
+        assert_eq!(event.properties.len(), 1);
+    }
+}
@@ -0,0 +1,237 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::config::{UsbConfig, UsbRule};
+use crate::daemon::Daemon;
+use crate::holds;
+use crate::uevent::{parse_uevent, Uevent, UeventSocket};
+
+/// Hold kind for attached devices; sources are `usb:<rule name>`.
+pub const HOLD_KIND: &str = "usb";
+
+/// Lets a burst of uevents (a dock brings several devices) settle before rescanning.
+const SETTLE: Duration = Duration::from_millis(250);
+
+/// Whether a uevent adds or removes a whole USB device rather than an interface.
+pub fn is_usb_device(event: &Uevent) -> bool {
+    event.subsystem() == Some("usb") && event.properties.get("DEVTYPE").is_some_and(|t| t == "usb_device")
+}
+
+/// A USB device in sysfs, e.g. /sys/bus/usb/devices/1-2.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UsbDevice {
+    pub dir: PathBuf,
+    /// `vendor:product` in lowercase hex
+    pub id: String,
+}
+
+impl UsbDevice {
+    /// A sysfs attribute of the device, e.g. `serial` or `manufacturer`.
+    pub fn attribute(&self, name: &str) -> Option<String> {
+        if name.is_empty() || name.contains('/') || name == ".." {
+            return None;
+        }
+        std::fs::read_to_string(self.dir.join(name)).ok().map(|value| value.trim().to_string())
+    }
+
+    /// The product string, or the id for devices that have none.
+    pub fn description(&self) -> String {
+        self.attribute("product").unwrap_or_else(|| self.id.clone())
+    }
+}
+
+/// Every set criterion has to match; a rule with no criteria never matches.
+pub fn rule_matches(rule: &UsbRule, device: &UsbDevice) -> bool {
+    if rule.id.is_none() && rule.attributes.is_empty() {
+        return false;
+    }
+
+    rule.id.as_ref().is_none_or(|id| id.eq_ignore_ascii_case(&device.id))
+        && rule
+            .attributes
+            .iter()
+            .all(|(name, value)| device.attribute(name).as_deref() == Some(value.as_str()))
+}
+
+/// Lists USB devices below a sysfs root, so tests can point it at a fake tree.
+pub struct UsbScanner {
+    sysfs_root: PathBuf,
+}
+
+impl UsbScanner {
+    pub fn new(sysfs_root: impl Into<PathBuf>) -> Self {
+        Self { sysfs_root: sysfs_root.into() }
+    }
+
+    /// Attached devices; interfaces (`1-2:1.0`) have no idVendor and are skipped.
+    pub fn devices(&self) -> Result<Vec<UsbDevice>> {
+        let root = self.sysfs_root.join("bus/usb/devices");
+        let entries = std::fs::read_dir(&root).with_context(|| format!("Failed to read {}", root.display()))?;
+
+        let read = |dir: &Path, name: &str| std::fs::read_to_string(dir.join(name)).ok().map(|s| s.trim().to_lowercase());
+
+        let mut devices: Vec<UsbDevice> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let dir = entry.path();
+                let id = format!("{}:{}", read(&dir, "idVendor")?, read(&dir, "idProduct")?);
+                Some(UsbDevice { dir, id })
+            })
+            .collect();
+
+        devices.sort_by(|a, b| a.dir.cmp(&b.dir));
+        Ok(devices)
+    }
+
+    /// Hold sources and reasons for every rule with a matching device attached.
+    pub fn matches(&self, rules: &[UsbRule]) -> Result<BTreeMap<String, String>> {
+        let devices = self.devices()?;
+
+        Ok(rules
+            .iter()
+            .filter_map(|rule| {
+                let device = devices.iter().find(|device| rule_matches(rule, device))?;
+                let reason = rule
+                    .reason
+                    .clone()
+                    .unwrap_or_else(|| format!("{} attached", device.description()));
+                Some((format!("{}:{}", HOLD_KIND, rule.name), reason))
+            })
+            .collect())
+    }
+}
+
+/// Holds the configured inhibitor while matching USB devices are attached,
+/// rescanning sysfs whenever the kernel reports a USB device coming or going.
+pub async fn watch(daemon: Daemon, config: UsbConfig) -> Result<()> {
+    use futures_util::FutureExt;
+
+    holds::validate_what(&config.what)?;
+
+    let scanner = UsbScanner::new("/sys");
+    // Subscribe before the first scan so nothing plugged in meanwhile is missed
+    let socket = UeventSocket::open()?;
+
+    tracing::info!("Watching USB devices for {} rule(s)", config.rules.len());
+
+    let mut active: BTreeMap<String, String> = BTreeMap::new();
+    loop {
+        let wanted = scanner.matches(&config.rules)?;
+
+        for source in active.keys().filter(|source| !wanted.contains_key(*source)) {
+            tracing::info!("USB device for {} removed", source);
+            daemon.release_hold(source).await?;
+        }
+        for (source, reason) in &wanted {
+            if !active.contains_key(source) {
+                tracing::info!("USB device for {}: {}", source, reason);
+            }
+            daemon.set_hold_what(source, &config.what, reason).await?;
+        }
+        active = wanted;
+
+        loop {
+            match socket.recv().await {
+                Ok(message) if parse_uevent(&message).is_some_and(|event| is_usb_device(&event)) => break,
+                Ok(_) => continue,
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    tracing::warn!("Missed uevents, rescanning USB devices");
+                    break;
+                }
+                Err(e) => return Err(e).context("Failed to receive uevents"),
+            }
+        }
+
+        tokio::time::sleep(SETTLE).await;
+        while let Some(Ok(_)) = socket.recv().now_or_never() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn add_device(sysfs_root: &Path, name: &str, attributes: &[(&str, &str)]) {
+        let dir = sysfs_root.join("bus/usb/devices").join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        for (attribute, value) in attributes {
+            std::fs::write(dir.join(attribute), format!("{}\n", value)).unwrap();
+        }
+    }
+
+    fn rule(name: &str, id: Option<&str>, attributes: &[(&str, &str)]) -> UsbRule {
+        UsbRule {
+            name: name.to_string(),
+            id: id.map(str::to_string),
+            attributes: attributes.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            reason: None,
+        }
+    }
+
+    fn fake_sysfs() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        add_device(
+            dir.path(),
+            "1-2",
+            &[("idVendor", "17EF"), ("idProduct", "a396"), ("serial", "DOCK123"), ("product", "ThinkPad Dock")],
+        );
+        add_device(dir.path(), "1-2:1.0", &[("bInterfaceClass", "03")]);
+        add_device(dir.path(), "1-3", &[("idVendor", "046d"), ("idProduct", "c52b")]);
+        dir
+    }
+
+    #[test]
+    fn lists_devices() {
+        let sysfs = fake_sysfs();
+        let ids: Vec<String> = UsbScanner::new(sysfs.path()).devices().unwrap().into_iter().map(|d| d.id).collect();
+        assert_eq!(ids, ["17ef:a396", "046d:c52b"]);
+        assert!(UsbScanner::new(sysfs.path().join("missing")).devices().is_err());
+    }
+
+    #[test]
+    fn matches_rules() {
+        let sysfs = fake_sysfs();
+        let devices = UsbScanner::new(sysfs.path()).devices().unwrap();
+        let dock = &devices[0];
+
+        assert!(rule_matches(&rule("dock", Some("17EF:A396"), &[]), dock));
+        assert!(rule_matches(&rule("dock", Some("17ef:a396"), &[("serial", "DOCK123")]), dock));
+        assert!(rule_matches(&rule("dock", None, &[("serial", "DOCK123")]), dock));
+        assert!(!rule_matches(&rule("dock", Some("17ef:a396"), &[("serial", "OTHER")]), dock));
+        assert!(!rule_matches(&rule("dock", Some("17ef:0000"), &[]), dock));
+        assert!(!rule_matches(&rule("dock", None, &[("manufacturer", "Lenovo")]), dock));
+        assert!(!rule_matches(&rule("empty", None, &[]), dock));
+        // Attribute names can't leave the device directory
+        assert!(!rule_matches(&rule("escape", None, &[("../1-3/idVendor", "046d")]), dock));
+        assert_eq!(dock.attribute(".."), None);
+    }
+
+    #[test]
+    fn hold_sources_and_reasons() {
+        let sysfs = fake_sysfs();
+        let mut named = rule("receiver", Some("046d:c52b"), &[]);
+        named.reason = Some("Mouse receiver".to_string());
+        let rules = [rule("dock", None, &[("serial", "DOCK123")]), named, rule("absent", Some("dead:beef"), &[])];
+
+        let matches = UsbScanner::new(sysfs.path()).matches(&rules).unwrap();
+        assert_eq!(
+            matches,
+            BTreeMap::from([
+                ("usb:dock".to_string(), "ThinkPad Dock attached".to_string()),
+                ("usb:receiver".to_string(), "Mouse receiver".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn recognizes_device_uevents() {
+        let event = |devtype: &str| {
+            parse_uevent(format!("add@/devices/pci0000:00/usb1/1-2\0SUBSYSTEM=usb\0DEVTYPE={}\0", devtype).as_bytes())
+                .unwrap()
+        };
+        assert!(is_usb_device(&event("usb_device")));
+        assert!(!is_usb_device(&event("usb_interface")));
+        assert!(!is_usb_device(&parse_uevent(b"add@/devices/virtual/net/tun0\0SUBSYSTEM=net\0").unwrap()));
+    }
+}
@@ -0,0 +1,194 @@
+use serde_json::json;
+use crate::config::WaybarConfig;
+use crate::dbus::{self, HoldInfo};
+use crate::schedule::{self, Schedule};
+use crate::{Config, State};
+
+/// Output of the `monitor` command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MonitorFormat {
+    /// Bare state lines: 1, 0 or 2
+    #[default]
+    Plain,
+    /// Waybar custom-module JSON
+    Waybar,
+}
+
+/// Waybar renders Pango markup, so text from holds has to be escaped.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn state_name(state: &State) -> &'static str {
+    match state {
+        State::Enabled => "enabled",
+        State::Disabled => "disabled",
+        State::Failed(_) => "failed",
+    }
+}
+
+/// One Waybar module update. `timer` describes the next scheduled change, if any.
+pub fn module(config: &WaybarConfig, state: &State, holds: &[HoldInfo], timer: Option<&str>) -> serde_json::Value {
+    let name = state_name(state);
+    let icon = match state {
+        State::Enabled => &config.icon_enabled,
+        State::Disabled => &config.icon_disabled,
+        State::Failed(_) => &config.icon_failed,
+    };
+
+    let mut reasons: Vec<&str> = Vec::new();
+    for hold in holds {
+        if !reasons.contains(&hold.reason.as_str()) {
+            reasons.push(&hold.reason);
+        }
+    }
+
+    let render = |template: &str| {
+        template
+            .replace("{icon}", icon)
+            .replace("{state}", name)
+            .replace("{count}", &holds.len().to_string())
+            .replace("{reasons}", &escape(&reasons.join("; ")))
+    };
+
+    let mut tooltip = vec![render(&config.tooltip)];
+    if let Some(reason) = state.failure_reason() {
+        tooltip.push(format!("Failed: {}", escape(reason)));
+    }
+    for hold in holds {
+        tooltip.push(format!("{} ({}): {}", escape(&hold.source), hold.what, escape(&hold.reason)));
+    }
+    if let Some(timer) = timer {
+        tooltip.push(format!("Next: {}", escape(timer)));
+    }
+
+    json!({
+        "text": render(&config.text),
+        "alt": name,
+        "tooltip": tooltip.join("\n"),
+        "class": name,
+        "percentage": if state.is_enabled() { 100 } else { 0 },
+    })
+}
+
+/// Renders `monitor` output with the configuration read once at startup.
+pub struct Renderer {
+    config: WaybarConfig,
+    schedule: Option<Schedule>,
+}
+
+impl Renderer {
+    /// Reads the config, falling back to the defaults when it can't be loaded.
+    pub fn load() -> Self {
+        let config = Config::load().unwrap_or_else(|e| {
+            tracing::warn!("Failed to load config, using defaults: {:#}", e);
+            Config::default()
+        });
+        Self::new(&config)
+    }
+
+    pub fn new(config: &Config) -> Self {
+        let schedule = if config.schedule.enabled {
+            Schedule::new(config.schedule.rules.clone())
+                .inspect_err(|e| tracing::warn!("Not showing schedule changes: {:#}", e))
+                .ok()
+        } else {
+            None
+        };
+
+        Self { config: config.waybar.clone(), schedule }
+    }
+
+    /// Renders `state` with the daemon's current holds as one line of Waybar JSON.
+    /// Whatever can't be read is left out, so the state is always shown.
+    pub async fn render(&self, state: &State) -> String {
+        // Without a running daemon there are no holds to list
+        let holds = dbus::query_holds().await.unwrap_or_default();
+
+        let now = schedule::now();
+        let timer = match self.schedule.as_ref().map(|schedule| schedule.next_transition(now)) {
+            Some(Ok(transition)) => transition.map(|transition| transition.describe(now)),
+            Some(Err(e)) => {
+                tracing::warn!("Failed to find the next schedule change: {:#}", e);
+                None
+            }
+            None => None,
+        };
+
+        module(&self.config, state, &holds, timer.as_deref()).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hold(source: &str, what: &str, reason: &str) -> HoldInfo {
+        HoldInfo { source: source.to_string(), what: what.to_string(), reason: reason.to_string() }
+    }
+
+    fn waybar_config() -> WaybarConfig {
+        WaybarConfig {
+            text: "{icon} {count}".to_string(),
+            tooltip: "{state}: {reasons}".to_string(),
+            ..WaybarConfig::default()
+        }
+    }
+
+    #[test]
+    fn enabled_module() {
+        let holds = [
+            hold("process:a", "idle", "Compiling <release> & testing"),
+            hold("process:b", "idle", "Compiling <release> & testing"),
+            hold("media:mpv", "idle:sleep", "Playing"),
+        ];
+        let module = module(&waybar_config(), &State::Enabled, &holds, Some("Mon 07:00 (in 1h 05m): night <ends>"));
+
+        assert_eq!(module["text"], "☕ 3");
+        assert_eq!(module["alt"], "enabled");
+        assert_eq!(module["class"], "enabled");
+        assert_eq!(module["percentage"], 100);
+        assert_eq!(
+            module["tooltip"],
+            "enabled: Compiling &lt;release&gt; &amp; testing; Playing\n\
+             process:a (idle): Compiling &lt;release&gt; &amp; testing\n\
+             process:b (idle): Compiling &lt;release&gt; &amp; testing\n\
+             media:mpv (idle:sleep): Playing\n\
+             Next: Mon 07:00 (in 1h 05m): night &lt;ends&gt;"
+        );
+    }
+
+    #[test]
+    fn disabled_and_failed_modules() {
+        let disabled = module(&waybar_config(), &State::Disabled, &[], None);
+        assert_eq!(disabled["text"], "💤 0");
+        assert_eq!(disabled["class"], "disabled");
+        assert_eq!(disabled["percentage"], 0);
+        assert_eq!(disabled["tooltip"], "disabled: ");
+
+        let state = State::Failed("Access denied <logind>".to_string());
+        let failed = module(&waybar_config(), &state, &[hold("user", "idle", "Manual")], None);
+        assert_eq!(failed["text"], "⚠ 1");
+        assert_eq!(failed["alt"], "failed");
+        assert_eq!(failed["class"], "failed");
+        assert_eq!(failed["percentage"], 0);
+        assert_eq!(failed["tooltip"], "failed: Manual\nFailed: Access denied &lt;logind&gt;\nuser (idle): Manual");
+    }
+
+    #[test]
+    fn broken_schedule_is_left_out() {
+        let mut config = Config::default();
+        config.schedule.enabled = true;
+        config.schedule.rules = vec![crate::config::ScheduleRule {
+            name: "broken".to_string(),
+            days: vec!["someday".to_string()],
+            start: "25:00".to_string(),
+            end: "07:00".to_string(),
+            action: Default::default(),
+            reason: None,
+        }];
+
+        let renderer = Renderer::new(&config);
+        assert!(renderer.schedule.is_none());
+    }
+}
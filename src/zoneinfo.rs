@@ -0,0 +1,469 @@
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use crate::calendar::{civil_from_days, days_from_civil, days_in_month};
+
+/// Where time zone files are looked up unless `$TZDIR` says otherwise.
+pub const ZONEINFO_DIR: &str = "/usr/share/zoneinfo";
+
+/// Start or end of daylight saving time in a POSIX TZ rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RuleDate {
+    /// `Jn`: day 1..=365, February 29 never counts
+    Julian(u32),
+    /// `n`: day 0..=365, February 29 counts
+    ZeroBased(u32),
+    /// `Mm.w.d`: weekday `d` (0 = Sunday) of week `w` (5 = last) of month `m`
+    MonthWeek(u32, u32, u32),
+}
+
+impl RuleDate {
+    /// Days since 1970-01-01 of this date in `year`.
+    fn day(&self, year: i64) -> i64 {
+        let january_first = days_from_civil(year, 1, 1);
+        match *self {
+            Self::Julian(n) => {
+                let leap = days_in_month(year, 2) == 29;
+                january_first + i64::from(n) - 1 + i64::from(leap && n >= 60)
+            }
+            Self::ZeroBased(n) => january_first + i64::from(n),
+            Self::MonthWeek(month, week, weekday) => {
+                let first = days_from_civil(year, month, 1);
+                // 1970-01-01 was a Thursday
+                let first_weekday = (first + 4).rem_euclid(7);
+                let mut day = first + (i64::from(weekday) - first_weekday).rem_euclid(7) + (i64::from(week) - 1) * 7;
+                while day >= first + i64::from(days_in_month(year, month)) {
+                    day -= 7;
+                }
+                day
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Dst {
+    offset: i64,
+    /// Dates and seconds past local midnight at which DST starts and ends
+    start: (RuleDate, i64),
+    end: (RuleDate, i64),
+}
+
+/// A POSIX TZ rule such as `CET-1CEST,M3.5.0,M10.5.0/3`, which TZif files carry
+/// for times past their last transition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PosixRule {
+    /// Standard UTC offset in seconds, east positive
+    std_offset: i64,
+    dst: Option<Dst>,
+}
+
+/// Consumes a zone abbreviation: letters, or anything quoted in `<>`.
+fn take_name(rest: &mut &str) -> Result<()> {
+    let length = match rest.strip_prefix('<') {
+        Some(quoted) => quoted.find('>').context("Unterminated zone name")? + 2,
+        None => rest.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(rest.len()),
+    };
+    if length < 3 {
+        bail!("Invalid zone name");
+    }
+    *rest = &rest[length..];
+    Ok(())
+}
+
+fn take_number(rest: &mut &str) -> Result<i64> {
+    let length = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    let number = rest[..length].parse().context("Expected a number")?;
+    *rest = &rest[length..];
+    Ok(number)
+}
+
+/// Consumes `[+-]hh[:mm[:ss]]` as seconds.
+fn take_hms(rest: &mut &str) -> Result<i64> {
+    let sign = match rest.as_bytes().first() {
+        Some(b'-') => -1,
+        _ => 1,
+    };
+    *rest = rest.trim_start_matches(['+', '-']);
+
+    let mut seconds = take_number(rest)? * 3600;
+    for unit in [60, 1] {
+        match rest.strip_prefix(':') {
+            Some(tail) => {
+                *rest = tail;
+                seconds += take_number(rest)? * unit;
+            }
+            None => break,
+        }
+    }
+    Ok(sign * seconds)
+}
+
+/// Consumes a `date[/time]` transition; the time defaults to 02:00.
+fn take_transition(rest: &mut &str) -> Result<(RuleDate, i64)> {
+    let date = if let Some(tail) = rest.strip_prefix('J') {
+        *rest = tail;
+        RuleDate::Julian(take_number(rest)?.clamp(1, 365) as u32)
+    } else if let Some(tail) = rest.strip_prefix('M') {
+        *rest = tail;
+        let month = take_number(rest)?;
+        *rest = rest.strip_prefix('.').context("Expected '.'")?;
+        let week = take_number(rest)?;
+        *rest = rest.strip_prefix('.').context("Expected '.'")?;
+        let weekday = take_number(rest)?;
+        if !(1..=12).contains(&month) || !(1..=5).contains(&week) || !(0..=6).contains(&weekday) {
+            bail!("Invalid M{}.{}.{}", month, week, weekday);
+        }
+        RuleDate::MonthWeek(month as u32, week as u32, weekday as u32)
+    } else {
+        RuleDate::ZeroBased(take_number(rest)?.clamp(0, 365) as u32)
+    };
+
+    let time = match rest.strip_prefix('/') {
+        Some(tail) => {
+            *rest = tail;
+            take_hms(rest)?
+        }
+        None => 2 * 3600,
+    };
+    Ok((date, time))
+}
+
+impl PosixRule {
+    pub fn parse(spec: &str) -> Result<Self> {
+        Self::parse_inner(spec).with_context(|| format!("Invalid TZ rule '{}'", spec))
+    }
+
+    fn parse_inner(spec: &str) -> Result<Self> {
+        let mut rest = spec.trim();
+        take_name(&mut rest)?;
+        // POSIX offsets count west of UTC
+        let std_offset = -take_hms(&mut rest)?;
+        if rest.is_empty() {
+            return Ok(Self { std_offset, dst: None });
+        }
+
+        take_name(&mut rest)?;
+        let offset = if rest.is_empty() || rest.starts_with(',') {
+            std_offset + 3600
+        } else {
+            -take_hms(&mut rest)?
+        };
+
+        // Without explicit dates POSIX leaves the rule to the implementation; use the US one
+        let (start, end) = match rest.strip_prefix(',') {
+            Some(mut dates) => {
+                let start = take_transition(&mut dates)?;
+                dates = dates.strip_prefix(',').context("Expected ','")?;
+                let end = take_transition(&mut dates)?;
+                if !dates.is_empty() {
+                    bail!("Trailing '{}'", dates);
+                }
+                (start, end)
+            }
+            None if rest.is_empty() => ((RuleDate::MonthWeek(3, 2, 0), 7200), (RuleDate::MonthWeek(11, 1, 0), 7200)),
+            None => bail!("Trailing '{}'", rest),
+        };
+
+        Ok(Self { std_offset, dst: Some(Dst { offset, start, end }) })
+    }
+
+    /// UTC offset in seconds at a UTC timestamp.
+    pub fn offset_at(&self, utc: i64) -> i64 {
+        let Some(dst) = &self.dst else {
+            return self.std_offset;
+        };
+
+        let (year, _, _) = civil_from_days((utc + self.std_offset).div_euclid(86_400));
+        // The start is given in standard time, the end in daylight time
+        let start = dst.start.0.day(year) * 86_400 + dst.start.1 - self.std_offset;
+        let end = dst.end.0.day(year) * 86_400 + dst.end.1 - dst.offset;
+
+        // Southern hemisphere rules (and Dublin's negative DST) wrap the year
+        let in_dst = if start < end { (start..end).contains(&utc) } else { utc >= start || utc < end };
+        if in_dst {
+            dst.offset
+        } else {
+            self.std_offset
+        }
+    }
+}
+
+/// A time zone read from a TZif file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeZone {
+    transitions: Vec<i64>,
+    /// UTC offset from each transition on
+    offsets: Vec<i64>,
+    /// UTC offset before the first transition
+    initial: i64,
+    /// Rule for times after the last transition
+    footer: Option<PosixRule>,
+}
+
+/// Transition times, their local time type indices and each type's UTC offset,
+/// from one TZif data block, followed by the block's length.
+type Block = (Vec<i64>, Vec<usize>, Vec<i64>, usize);
+
+fn read_block(data: &[u8], time_size: usize) -> Result<Block> {
+    if data.len() < 44 || &data[..4] != b"TZif" {
+        bail!("Not a TZif file");
+    }
+    let count = |index: usize| u32::from_be_bytes(data[20 + 4 * index..24 + 4 * index].try_into().unwrap()) as usize;
+    let (isutcnt, isstdcnt, leapcnt, timecnt, typecnt, charcnt) = (count(0), count(1), count(2), count(3), count(4), count(5));
+    if typecnt == 0 {
+        bail!("TZif file without local time types");
+    }
+
+    let length = 44
+        + timecnt * time_size
+        + timecnt
+        + typecnt * 6
+        + charcnt
+        + leapcnt * (time_size + 4)
+        + isstdcnt
+        + isutcnt;
+    let body = data.get(44..length).context("Truncated TZif file")?;
+
+    let (times, body) = body.split_at(timecnt * time_size);
+    let transitions = times
+        .chunks_exact(time_size)
+        .map(|time| match time_size {
+            4 => i64::from(i32::from_be_bytes(time.try_into().unwrap())),
+            _ => i64::from_be_bytes(time.try_into().unwrap()),
+        })
+        .collect();
+
+    let (indices, body) = body.split_at(timecnt);
+    let indices: Vec<usize> = indices.iter().map(|index| usize::from(*index)).collect();
+    if indices.iter().any(|index| *index >= typecnt) {
+        bail!("Invalid local time type in TZif file");
+    }
+
+    let offsets = body[..typecnt * 6]
+        .chunks_exact(6)
+        .map(|info| i64::from(i32::from_be_bytes(info[..4].try_into().unwrap())))
+        .collect();
+
+    Ok((transitions, indices, offsets, length))
+}
+
+impl TimeZone {
+    /// Loads a zone such as `Europe/Berlin` from `$TZDIR` or the system zoneinfo.
+    pub fn load(name: &str) -> Result<Self> {
+        let root = std::env::var_os("TZDIR").map_or_else(|| PathBuf::from(ZONEINFO_DIR), PathBuf::from);
+        Self::load_from(&root, name)
+    }
+
+    /// Some clients prefix zone names with their own path, e.g.
+    /// `/mozilla.org/20050126_1/Europe/Berlin`, so leading segments are dropped
+    /// until a zone file matches.
+    pub fn load_from(root: &Path, name: &str) -> Result<Self> {
+        let segments: Vec<&str> = name.split('/').filter(|segment| !segment.is_empty()).collect();
+        if segments.iter().any(|segment| segment.starts_with('.')) {
+            bail!("Invalid time zone '{}'", name);
+        }
+
+        for skip in 0..segments.len() {
+            let path = root.join(segments[skip..].join("/"));
+            if path.is_file() {
+                let data = std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+                return Self::parse(&data).with_context(|| format!("Invalid time zone file {}", path.display()));
+            }
+        }
+        bail!("Unknown time zone '{}'", name)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let (transitions, indices, offsets, length) = read_block(data, 4)?;
+        if data[4] < b'2' {
+            return Ok(Self::new(transitions, &indices, &offsets, None));
+        }
+
+        // Version 2+ repeats the data with 64-bit times, followed by a TZ rule footer
+        let data = &data[length..];
+        let (transitions, indices, offsets, length) = read_block(data, 8)?;
+        let footer = std::str::from_utf8(&data[length..])
+            .ok()
+            .map(|footer| footer.trim_matches('\n'))
+            .filter(|footer| !footer.is_empty())
+            .map(PosixRule::parse)
+            .transpose()?;
+
+        Ok(Self::new(transitions, &indices, &offsets, footer))
+    }
+
+    fn new(transitions: Vec<i64>, indices: &[usize], offsets: &[i64], footer: Option<PosixRule>) -> Self {
+        Self {
+            offsets: indices.iter().map(|index| offsets[*index]).collect(),
+            transitions,
+            initial: offsets[0],
+            footer,
+        }
+    }
+
+    /// A zone that follows a POSIX TZ rule such as `UTC0` at all times.
+    pub fn from_rule(rule: PosixRule) -> Self {
+        Self { transitions: Vec::new(), offsets: Vec::new(), initial: rule.std_offset, footer: Some(rule) }
+    }
+
+    /// UTC offset in seconds at a UTC timestamp.
+    pub fn offset_at(&self, utc: i64) -> i64 {
+        match self.transitions.partition_point(|transition| *transition <= utc) {
+            count if count == self.transitions.len() && self.footer.is_some() => {
+                self.footer.as_ref().unwrap().offset_at(utc)
+            }
+            0 => self.initial,
+            count => self.offsets[count - 1],
+        }
+    }
+
+    /// The UTC timestamp of a local wall-clock time, given as seconds since the
+    /// epoch as if the wall clock were UTC. Repeated times resolve to the first
+    /// instant, and times skipped by a DST gap are read with the offset before the
+    /// gap, as RFC 5545 prescribes.
+    pub fn to_utc(&self, local: i64) -> i64 {
+        let before = self.offset_at(local - 86_400);
+        let after = self.offset_at(local + 86_400);
+
+        [before, after]
+            .into_iter()
+            .find(|offset| self.offset_at(local - offset) == *offset)
+            .map_or(local - before, |offset| local - offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: i64 = 3600;
+
+    fn local(year: i64, month: u32, day: u32, hour: i64, minute: i64) -> i64 {
+        days_from_civil(year, month, day) * 86_400 + hour * HOUR + minute * 60
+    }
+
+    #[test]
+    fn parses_posix_rules() {
+        let rule = PosixRule::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        assert_eq!(rule.std_offset, HOUR);
+        let dst = rule.dst.as_ref().unwrap();
+        assert_eq!(dst.offset, 2 * HOUR);
+        assert_eq!(dst.start, (RuleDate::MonthWeek(3, 5, 0), 2 * HOUR));
+        assert_eq!(dst.end, (RuleDate::MonthWeek(10, 5, 0), 3 * HOUR));
+
+        let rule = PosixRule::parse("<+0530>-5:30").unwrap();
+        assert_eq!(rule, PosixRule { std_offset: 5 * HOUR + 30 * 60, dst: None });
+        assert_eq!(PosixRule::parse("UTC0").unwrap().offset_at(0), 0);
+
+        for invalid in ["", "C-1", "CET", "CET-1CEST,M3.5.0", "CET-1CEST,M13.5.0,M10.5.0", "<CET-1"] {
+            assert!(PosixRule::parse(invalid).is_err(), "{} should be rejected", invalid);
+        }
+    }
+
+    #[test]
+    fn posix_rule_offsets() {
+        let berlin = PosixRule::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap();
+        // DST 2030 runs from 2030-03-31 01:00 UTC to 2030-10-27 01:00 UTC
+        assert_eq!(berlin.offset_at(local(2030, 3, 31, 0, 59)), HOUR);
+        assert_eq!(berlin.offset_at(local(2030, 3, 31, 1, 0)), 2 * HOUR);
+        assert_eq!(berlin.offset_at(local(2030, 10, 27, 0, 59)), 2 * HOUR);
+        assert_eq!(berlin.offset_at(local(2030, 10, 27, 1, 0)), HOUR);
+
+        let sydney = PosixRule::parse("AEST-10AEDT,M10.1.0,M4.1.0/3").unwrap();
+        assert_eq!(sydney.offset_at(local(2030, 1, 15, 0, 0)), 11 * HOUR);
+        assert_eq!(sydney.offset_at(local(2030, 7, 15, 0, 0)), 10 * HOUR);
+
+        let new_york = PosixRule::parse("EST5EDT,M3.2.0,M11.1.0").unwrap();
+        assert_eq!(new_york.offset_at(local(2030, 7, 4, 12, 0)), -4 * HOUR);
+        assert_eq!(new_york.offset_at(local(2030, 12, 25, 12, 0)), -5 * HOUR);
+    }
+
+    #[test]
+    fn rule_dates() {
+        // The last Sunday of March 2024 was the 31st, the second Sunday the 10th
+        assert_eq!(RuleDate::MonthWeek(3, 5, 0).day(2024), days_from_civil(2024, 3, 31));
+        assert_eq!(RuleDate::MonthWeek(3, 2, 0).day(2024), days_from_civil(2024, 3, 10));
+        assert_eq!(RuleDate::Julian(60).day(2024), days_from_civil(2024, 3, 1));
+        assert_eq!(RuleDate::ZeroBased(59).day(2024), days_from_civil(2024, 2, 29));
+    }
+
+    /// A version 2 file with one transition, from UTC+1 to UTC+2 at 1000, and a footer.
+    fn tzif(footer: &str) -> Vec<u8> {
+        let block = |time: &[u8]| {
+            let mut data = b"TZif2".to_vec();
+            data.extend([0; 15]);
+            for count in [0u32, 0, 0, 1, 2, 4] {
+                data.extend(count.to_be_bytes());
+            }
+            data.extend(time);
+            data.push(1);
+            data.extend(3600i32.to_be_bytes());
+            data.extend([0, 0]);
+            data.extend(7200i32.to_be_bytes());
+            data.extend([1, 0]);
+            data.extend(b"AB\0\0");
+            data
+        };
+
+        let mut data = block(&1000i32.to_be_bytes());
+        data.extend(block(&1000i64.to_be_bytes()));
+        data.extend(format!("\n{}\n", footer).into_bytes());
+        data
+    }
+
+    #[test]
+    fn parses_tzif() {
+        let zone = TimeZone::parse(&tzif("")).unwrap();
+        assert_eq!(zone.offset_at(999), HOUR);
+        assert_eq!(zone.offset_at(1000), 2 * HOUR);
+        assert_eq!(zone.offset_at(1_000_000), 2 * HOUR);
+
+        let zone = TimeZone::parse(&tzif("<+03>-3")).unwrap();
+        assert_eq!(zone.offset_at(1000), 3 * HOUR);
+
+        assert!(TimeZone::parse(b"TZif2").is_err());
+        assert!(TimeZone::parse(&tzif("")[..60]).is_err());
+        assert!(TimeZone::parse(&tzif("bogus")).is_err());
+    }
+
+    #[test]
+    fn resolves_local_times() {
+        let berlin = TimeZone {
+            transitions: Vec::new(),
+            offsets: Vec::new(),
+            initial: HOUR,
+            footer: Some(PosixRule::parse("CET-1CEST,M3.5.0,M10.5.0/3").unwrap()),
+        };
+
+        assert_eq!(berlin.to_utc(local(2030, 1, 15, 10, 0)), local(2030, 1, 15, 9, 0));
+        assert_eq!(berlin.to_utc(local(2030, 7, 15, 10, 0)), local(2030, 7, 15, 8, 0));
+        // 02:30 doesn't exist on 2030-03-31 and is read with the winter offset
+        assert_eq!(berlin.to_utc(local(2030, 3, 31, 2, 30)), local(2030, 3, 31, 1, 30));
+        // 02:30 happens twice on 2030-10-27; the first one is in summer time
+        assert_eq!(berlin.to_utc(local(2030, 10, 27, 2, 30)), local(2030, 10, 27, 0, 30));
+    }
+
+    #[test]
+    fn loads_zone_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("Test")).unwrap();
+        std::fs::write(dir.path().join("Test/Zone"), tzif("")).unwrap();
+
+        let zone = TimeZone::load_from(dir.path(), "Test/Zone").unwrap();
+        assert_eq!(zone.offset_at(0), HOUR);
+        assert_eq!(TimeZone::load_from(dir.path(), "/vendor.example/2005_1/Test/Zone").unwrap(), zone);
+        assert!(TimeZone::load_from(dir.path(), "Test/Missing").is_err());
+        assert!(TimeZone::load_from(dir.path(), "../Test/Zone").is_err());
+    }
+
+    #[test]
+    fn system_zones() {
+        if !Path::new(ZONEINFO_DIR).join("Europe/Berlin").is_file() {
+            return;
+        }
+        let berlin = TimeZone::load_from(Path::new(ZONEINFO_DIR), "Europe/Berlin").unwrap();
+        assert_eq!(berlin.to_utc(local(2024, 7, 1, 10, 0)), local(2024, 7, 1, 8, 0));
+        assert_eq!(berlin.to_utc(local(2024, 1, 15, 10, 0)), local(2024, 1, 15, 9, 0));
+        assert_eq!(berlin.to_utc(local(2060, 7, 1, 10, 0)), local(2060, 7, 1, 8, 0));
+    }
+}